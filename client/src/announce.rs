@@ -1,98 +1,245 @@
-use shared::{
-    self, WIREPLUG_ORG_WP,
-    protocol::{self, WireplugResponse},
-};
+use shared::protocol::{self, WireplugResponse};
 use std::{
     io::{Read, Write},
-    net::TcpStream,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs},
     process,
     time::Duration,
 };
 use wireguard_control::{Backend, Device, Key};
 
-use crate::{netstat::NetInfo, utils};
+use crate::{config::Servers, nat, netstat::NetInfo, utils};
 
-fn send_announcement<S: Read + Write>(
-    stream: &mut S,
-    announcement: protocol::WireplugAnnouncement,
-) -> Result<protocol::WireplugResponse, std::io::Error> {
-    stream.write_all(&protocol::WIREPLUG_PROTOCOL_MAGIC)?;
-    stream.write_all(&protocol::WIREPLUG_PROTOCOL_VERSION)?;
-
-    let encoded_message = postcard::to_allocvec(&announcement)
-        .map_err(|e| std::io::Error::other(format!("encoding error: {e}")))?;
+const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(2);
 
-    let encoded_message_size: [u8; 4] = u32::try_from(encoded_message.len())
-        .expect("Message length exceeds u32::MAX")
-        .to_le_bytes();
-    stream.write_all(&encoded_message_size)?;
-    stream.write_all(&encoded_message)?;
+fn write_message<S: Write, T: serde::Serialize>(
+    stream: &mut S,
+    message: &T,
+) -> Result<(), std::io::Error> {
+    stream.write_all(&protocol::encode_message(message)?)
+}
 
-    let mut header = [0u8; 4];
+fn read_message<S: Read, T: serde::de::DeserializeOwned>(
+    stream: &mut S,
+) -> Result<T, std::io::Error> {
+    let mut header = [0u8; protocol::WIREPLUG_HEADER_SIZE];
     stream.read_exact(&mut header)?;
-    if header[..3] != protocol::WIREPLUG_PROTOCOL_MAGIC {
-        return Err(std::io::Error::other("bad message"));
-    }
-    if header[3..] != protocol::WIREPLUG_PROTOCOL_VERSION {
-        log::warn!("You're running an outdated version of wireplugd.");
-        log::warn!("Please update to the latest version to continue using the service.");
-        // XXX needs to return custom error so we can kill wireguard-go before we go down
-        process::exit(1);
+    match protocol::decode_header(&header) {
+        Ok(()) => {}
+        Err(protocol::HeaderError::OtherVersion) => {
+            log::warn!("You're running an outdated version of wireplugd.");
+            log::warn!("Please update to the latest version to continue using the service.");
+            // XXX needs to return custom error so we can kill wireguard-go before we go down
+            process::exit(1);
+        }
+        Err(e) => return Err(std::io::Error::other(e)),
     }
 
     let mut length_bytes = [0u8; 4];
     stream.read_exact(&mut length_bytes)?;
-    let encoded_length = u32::from_le_bytes(length_bytes) as usize;
-    if encoded_length > shared::MAX_MESSAGE_SIZE {
-        return Err(std::io::Error::other(format!(
-            "Message size {} exceeds maximum allowed size of {}",
-            encoded_length,
-            shared::MAX_MESSAGE_SIZE
-        )));
-    }
+    let encoded_length =
+        protocol::decode_length(u32::from_le_bytes(length_bytes)).map_err(std::io::Error::other)?;
     let mut encoded_message = vec![0u8; encoded_length];
     stream.read_exact(&mut encoded_message)?;
 
-    let response: protocol::WireplugResponse = postcard::from_bytes(&encoded_message)
-        .map_err(|e| std::io::Error::other(format!("encoding error: {e}")))?;
+    protocol::decode_message(&encoded_message)
+}
 
-    Ok(response)
+fn send_announcement<S: Read + Write>(
+    stream: &mut S,
+    announcement: protocol::WireplugAnnouncement,
+    private_key: &Key,
+) -> Result<protocol::WireplugResponse, std::io::Error> {
+    write_message(stream, &announcement)?;
+
+    let challenge: protocol::WireplugChallenge = read_message(stream)?;
+    let private_key: [u8; 32] = private_key
+        .as_bytes()
+        .try_into()
+        .map_err(|_| std::io::Error::other("bad private key length"))?;
+    write_message(stream, &challenge.respond(private_key))?;
+
+    read_message(stream)
 }
 
-pub(crate) fn announce(
-    if_name: &String,
-    peers: &[Key],
-    announcement_port: u16,
-    netinfo: &NetInfo,
-    needs_relay: bool,
-) -> Result<WireplugResponse, std::io::Error> {
+fn device_keys(if_name: &str) -> Result<(Key, Key), std::io::Error> {
     let iface = if_name.parse()?;
     let device = Device::get(&iface, Backend::default())?;
-    let Some(initiator_pubkey) = &device.public_key.clone() else {
-        return Err(std::io::Error::other(format!(
+    match (device.public_key, device.private_key) {
+        (Some(public_key), Some(private_key)) => Ok((public_key, private_key)),
+        _ => Err(std::io::Error::other(format!(
             "{if_name} is not configured"
-        )));
-    };
+        ))),
+    }
+}
 
-    let mut socket = TcpStream::connect((WIREPLUG_ORG_WP, shared::WIREPLUG_WPCOD_PORT))?;
-    socket.set_write_timeout(Some(Duration::from_secs(1)))?;
-    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
-    let mut client_connection = utils::get_tls_client_connection(WIREPLUG_ORG_WP)
-        .map_err(|e| std::io::Error::other(format!("failed to create TLS client: {e}")))?;
-    let mut stream = rustls::Stream::new(&mut client_connection, &mut socket);
+// Connects to the first coordination server address `filter` accepts
+fn connect(
+    servers: &Servers,
+    filter: impl Fn(&SocketAddr) -> bool,
+    timeout: Duration,
+) -> Result<TcpStream, std::io::Error> {
+    let (host, port) = &servers.coordination;
+    let mut last_error = std::io::Error::other(format!("could not resolve {host}"));
+    let mut socket = None;
+    for addr in (host.as_str(), *port).to_socket_addrs()?.filter(&filter) {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(s) => {
+                socket = Some(s);
+                break;
+            }
+            Err(e) => last_error = e,
+        }
+    }
+    let socket = socket.ok_or(last_error)?;
+    socket.set_write_timeout(Some(timeout))?;
+    socket.set_read_timeout(Some(timeout))?;
+    Ok(socket)
+}
 
-    let announcement = protocol::WireplugAnnouncement::new(
-        &initiator_pubkey.to_base64(),
-        peers.iter().map(|p| p.to_base64()).collect(),
-        netinfo.wan_ipv6,
-        announcement_port,
-        netinfo.lan_addrs.clone(),
-        needs_relay,
-    );
+// Runs the announcement exchange with the first coordination server address
+// `filter` accepts
+fn exchange(
+    if_name: &str,
+    servers: &Servers,
+    filter: impl Fn(&SocketAddr) -> bool,
+    timeout: Duration,
+    announcement: impl FnOnce(&Key) -> protocol::WireplugAnnouncement,
+) -> Result<WireplugResponse, std::io::Error> {
+    let (initiator_pubkey, private_key) = device_keys(if_name)?;
+    let mut socket = connect(servers, filter, timeout)?;
+    let mut client_connection =
+        utils::get_tls_client_connection(&servers.coordination.0, &servers.tls_trust, None)
+            .map_err(|e| std::io::Error::other(format!("failed to create TLS client: {e}")))?;
+    let mut stream = rustls::Stream::new(&mut client_connection, &mut socket);
 
-    let response = send_announcement(&mut stream, announcement)?;
+    let response = send_announcement(&mut stream, announcement(&initiator_pubkey), &private_key)?;
     if !response.valid() {
         return Err(std::io::Error::other("invalid response"));
     }
     Ok(response)
 }
+
+// A connection to the coordination server's TCP relay, paired with `peer`'s
+// once it connects too
+pub(crate) fn connect_tcp_relay(
+    if_name: &str,
+    servers: &Servers,
+    peer: &Key,
+) -> Result<rustls::StreamOwned<rustls::ClientConnection, TcpStream>, std::io::Error> {
+    let (initiator_pubkey, private_key) = device_keys(if_name)?;
+    let socket = connect(servers, |_| true, ANNOUNCE_TIMEOUT)?;
+    let client_connection = utils::get_tls_client_connection(
+        &servers.coordination.0,
+        &servers.tls_trust,
+        Some(protocol::WIREPLUG_TCP_RELAY_ALPN),
+    )
+    .map_err(|e| std::io::Error::other(format!("failed to create TLS client: {e}")))?;
+    let mut stream = rustls::StreamOwned::new(client_connection, socket);
+    while stream.conn.is_handshaking() {
+        stream.conn.complete_io(&mut stream.sock)?;
+    }
+    if stream.conn.alpn_protocol() != Some(protocol::WIREPLUG_TCP_RELAY_ALPN) {
+        return Err(std::io::Error::other(
+            "the coordination server does not relay over TCP",
+        ));
+    }
+
+    let request =
+        protocol::WireplugTcpRelayRequest::new(&initiator_pubkey.to_base64(), &peer.to_base64());
+    write_message(&mut stream, &request)?;
+    let challenge: protocol::WireplugChallenge = read_message(&mut stream)?;
+    let private_key: [u8; 32] = private_key
+        .as_bytes()
+        .try_into()
+        .map_err(|_| std::io::Error::other("bad private key length"))?;
+    write_message(&mut stream, &challenge.respond(private_key))?;
+    Ok(stream)
+}
+
+pub(crate) fn announce(
+    if_name: &str,
+    peers: &[Key],
+    announcement_port: u16,
+    netinfo: &NetInfo,
+    needs_relay: bool,
+    servers: &Servers,
+) -> Result<WireplugResponse, std::io::Error> {
+    // peers probe a few ports past our next mapping
+    let predicted_ports = match (
+        netinfo.wan_ipv4,
+        netinfo.nat.and_then(|n| n.allocation_step),
+    ) {
+        (Some(_), Some(step)) => nat::predict_ports(step, &servers.stun)
+            .inspect_err(|e| log::debug!("could not predict ports: {e}"))
+            .ok(),
+        _ => None,
+    };
+    exchange(
+        if_name,
+        servers,
+        |_| true,
+        ANNOUNCE_TIMEOUT,
+        |initiator_pubkey| {
+            protocol::WireplugAnnouncement::new(
+                &initiator_pubkey.to_base64(),
+                peers.iter().map(|p| p.to_base64()).collect(),
+                netinfo.wan_ipv4,
+                netinfo.wan_ipv6,
+                announcement_port,
+                netinfo.lan_addrs.clone(),
+                needs_relay,
+            )
+            .with_nat(netinfo.nat)
+            .with_birthday(servers.birthday_traversal)
+            .with_predicted_ports(predicted_ports)
+            .with_tcp_relay(netinfo.udp_blocked || servers.tcp_relay)
+        },
+    )
+}
+
+// Our public addresses as seen by the coordination server, asked once over
+// each address family
+pub(crate) fn get_public_ips(
+    if_name: &str,
+    servers: &Servers,
+    timeout: Duration,
+) -> (Option<Ipv4Addr>, Option<Ipv6Addr>) {
+    let observe = |ipv6: bool| {
+        let res = exchange(
+            if_name,
+            servers,
+            |addr| addr.is_ipv6() == ipv6,
+            timeout,
+            |initiator_pubkey| {
+                protocol::WireplugAnnouncement::new(
+                    &initiator_pubkey.to_base64(),
+                    vec![],
+                    None,
+                    None,
+                    0,
+                    vec![],
+                    false,
+                )
+            },
+        );
+        match res {
+            Ok(response) => Some(response.observed_addr.ip().to_canonical()),
+            Err(e) => {
+                log::debug!(
+                    "could not get observed IPv{} address: {e}",
+                    if ipv6 { 6 } else { 4 }
+                );
+                None
+            }
+        }
+    };
+    let ipv4 = match observe(false) {
+        Some(IpAddr::V4(ip)) => Some(ip),
+        _ => None,
+    };
+    let ipv6 = match observe(true) {
+        Some(IpAddr::V6(ip)) => Some(ip),
+        _ => None,
+    };
+    (ipv4, ipv6)
+}
@@ -0,0 +1,162 @@
+use std::{fmt::Write as OtherWrite, os::unix::net::UnixStream, process::ExitCode, time::Duration};
+
+use clap::{Parser, Subcommand};
+use shared::control::{self, ControlRequest, ControlResponse, DaemonStatus, PeerStatus};
+
+#[derive(Parser)]
+#[command(version, name="wireplugctl", about="Control a running wireplugd", long_about = None)]
+struct Cli {
+    interface_name: String,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Show the network, NAT and peers")]
+    Status,
+    #[command(about = "Show a single peer")]
+    Peer { public_key: String },
+    #[command(about = "Announce all peers to the coordination server now")]
+    Announce,
+    #[command(about = "Pick a new port and detect the NAT again")]
+    DetectNat,
+    #[command(about = "Stop changing peer endpoints")]
+    Pause,
+    #[command(about = "Resume changing peer endpoints")]
+    Resume,
+}
+
+fn get_size_str(size: u64) -> String {
+    match size {
+        0..=1023 => format!("{size}Bytes"),
+        1024..=1048575 => format!("{}KiB", size / 1024),
+        1048576.. => format!("{:.2}MiB", size as f64 / (1024.0 * 1024.0)),
+    }
+}
+
+fn write_peer(w: &mut String, peer: &PeerStatus) -> std::fmt::Result {
+    writeln!(w, "\tpublic key: {}", peer.public_key)?;
+    match peer.endpoint {
+        Some(e) => writeln!(w, "\tendpoint: {e}")?,
+        None => writeln!(w, "\tendpoint: N/A")?,
+    }
+    let allowed_ips = peer
+        .allowed_ips
+        .iter()
+        .map(|ip| ip.to_string())
+        .collect::<Vec<_>>();
+    writeln!(w, "\tallowed IPs: {}", allowed_ips.join(", "))?;
+    match peer.last_handshake_secs {
+        Some(secs) => writeln!(w, "\tlast handshake: {secs} seconds ago")?,
+        None => writeln!(w, "\tlast handshake: N/A")?,
+    }
+    if let Some(pka) = peer.persistent_keepalive {
+        writeln!(w, "\tpersistent keepalive: every {pka} seconds")?;
+    }
+    if let Some(liveness) = peer.liveness {
+        writeln!(w, "\tliveness: {liveness}")?;
+    }
+    writeln!(w, "\ttx: {}", get_size_str(peer.tx_bytes))?;
+    writeln!(w, "\trx: {}", get_size_str(peer.rx_bytes))?;
+    if peer.inactive {
+        writeln!(w, "\tINACTIVE, looking for a new endpoint")?;
+    }
+    Ok(())
+}
+
+fn write_status(w: &mut String, status: &DaemonStatus) -> std::fmt::Result {
+    writeln!(w, "Interface: {}", status.interface)?;
+    if status.paused {
+        writeln!(w, "\tendpoint management is PAUSED")?;
+    }
+    match status.listen_port {
+        Some(port) => writeln!(w, "\tlisten port: {port}")?,
+        None => writeln!(w, "\tlisten port: N/A")?,
+    }
+    writeln!(w, "\tannounced port: {}", status.announced_port)?;
+    writeln!(w, "\nNetwork:\n-------")?;
+    match &status.network {
+        Some(network) => {
+            match network.wan_ipv4 {
+                Some(ip) => writeln!(w, "\tPublic IPv4: {ip}")?,
+                None => writeln!(w, "\tPublic IPv4: N/A")?,
+            }
+            match network.wan_ipv6 {
+                Some(ip) => writeln!(w, "\tPublic IPv6: {ip}")?,
+                None => writeln!(w, "\tPublic IPv6: N/A")?,
+            }
+            for addr in &network.lan_addrs {
+                writeln!(w, "\tLAN IP: {addr}")?;
+            }
+            match (network.default_gateway, &network.gateway_mac) {
+                (Some(ip), Some(mac)) => writeln!(w, "\tGateway: {ip} ({mac})")?,
+                (Some(ip), None) => writeln!(w, "\tGateway: {ip}")?,
+                _ => writeln!(w, "\tGateway: N/A")?,
+            }
+            if let Some(nat) = &network.nat {
+                writeln!(w, "\tNAT behaviour: {nat}")?;
+            }
+            if network.needs_relay {
+                writeln!(w, "\tneeds relay")?;
+            }
+        }
+        None => writeln!(w, "\tN/A")?,
+    }
+    match &status.nat {
+        Some(nat) => writeln!(w, "\tNAT: {nat}")?,
+        None => writeln!(w, "\tNAT: N/A")?,
+    }
+    writeln!(w, "\nPeers:\n------")?;
+    for peer in &status.peers {
+        write_peer(w, peer)?;
+        writeln!(
+            w,
+            "\t--------------------------------------------------------"
+        )?;
+    }
+    Ok(())
+}
+
+fn send_request(ifname: &str, request: &ControlRequest) -> std::io::Result<ControlResponse> {
+    let path = control::socket_path(ifname);
+    let mut stream = UnixStream::connect(&path)
+        .map_err(|e| std::io::Error::other(format!("could not connect to {path}: {e}")))?;
+    stream.set_read_timeout(Some(Duration::from_secs(60)))?;
+    control::write_message(&mut stream, request)?;
+    control::read_message(&mut stream)
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let request = match cli.command {
+        Command::Status => ControlRequest::Status,
+        Command::Peer { public_key } => ControlRequest::Peer(public_key),
+        Command::Announce => ControlRequest::Announce,
+        Command::DetectNat => ControlRequest::DetectNat,
+        Command::Pause => ControlRequest::Pause,
+        Command::Resume => ControlRequest::Resume,
+    };
+    let response = match send_request(&cli.interface_name, &request) {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("wireplugctl: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut out = String::new();
+    let written = match &response {
+        ControlResponse::Status(status) => write_status(&mut out, status),
+        ControlResponse::Peer(peer) => write_peer(&mut out, peer),
+        ControlResponse::Done => Ok(()),
+        ControlResponse::Error(e) => {
+            eprintln!("wireplugd: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if written.is_err() {
+        return ExitCode::FAILURE;
+    }
+    print!("{out}");
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,210 @@
+use std::{
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    thread,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use shared::protocol::BirthdayRole;
+use wireguard_control::Key;
+
+use crate::{utils, wg_interface};
+
+// 256 open ports against 1024 probes line up about 98% of the time
+const HARD_PORTS: usize = 256;
+const EASY_PROBES: usize = 1024;
+// probes are paced so neither NAT sees them all at once
+const PROBES_PER_BURST: usize = 64;
+const BURST_INTERVAL: Duration = Duration::from_millis(100);
+const TRAVERSAL_TIMEOUT: Duration = Duration::from_secs(10);
+// how long the easy side listens for an answer after its last probe
+const ANSWER_WAIT: Duration = Duration::from_secs(1);
+const PROBE: &[u8] = b"wireplug birthday probe";
+// answers get lost too
+const ANSWERS: usize = 3;
+
+// Whoever receives a probe from the peer answers it, and the path is found
+fn receive_probe(socket: &UdpSocket, peer: IpAddr) -> std::io::Result<Option<SocketAddr>> {
+    let mut buf = [0u8; 64];
+    loop {
+        let (n, from) = match socket.recv_from(&mut buf) {
+            Ok(res) => res,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if from.ip() == peer && &buf[..n] == PROBE {
+            for _ in 0..ANSWERS {
+                socket.send_to(PROBE, from)?;
+            }
+            return Ok(Some(from));
+        }
+    }
+}
+
+// Hard side: every port opens a mapping towards the peer. Returns the local
+// port whose mapping one of the peer's probes came through, or None once
+// `wait` says to stop.
+fn open_ports(
+    peer: SocketAddr,
+    wait: &mut dyn FnMut(Duration) -> bool,
+) -> std::io::Result<Option<u16>> {
+    let mut sockets = Vec::with_capacity(HARD_PORTS);
+    for _ in 0..HARD_PORTS {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_nonblocking(true)?;
+        socket.send_to(PROBE, peer)?;
+        sockets.push(socket);
+    }
+    let deadline = Instant::now() + TRAVERSAL_TIMEOUT;
+    while Instant::now() < deadline {
+        for socket in &sockets {
+            if receive_probe(socket, peer.ip())?.is_some() {
+                return Ok(Some(socket.local_addr()?.port()));
+            }
+        }
+        if wait(Duration::from_millis(10)) {
+            log::debug!("birthday traversal interrupted");
+            break;
+        }
+    }
+    Ok(None)
+}
+
+// WireGuard may take a moment to let go of the port
+fn bind_released(port: u16) -> std::io::Result<UdpSocket> {
+    for _ in 0..10 {
+        match UdpSocket::bind(("0.0.0.0", port)) {
+            Ok(socket) => return Ok(socket),
+            Err(e) if e.kind() == ErrorKind::AddrInUse => thread::sleep(Duration::from_millis(100)),
+            Err(e) => return Err(e),
+        }
+    }
+    UdpSocket::bind(("0.0.0.0", port))
+}
+
+// Listens on `socket` until `deadline` for an answer from `peer`
+fn receive_until(
+    socket: &UdpSocket,
+    peer: IpAddr,
+    deadline: Instant,
+) -> std::io::Result<Option<SocketAddr>> {
+    while Instant::now() < deadline {
+        if let Some(from) = receive_probe(socket, peer)? {
+            return Ok(Some(from));
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    Ok(None)
+}
+
+// Easy side: probes random ports of the peer's address from `local_port`.
+// Returns the peer's address once a probe got through. Takes about
+// EASY_PROBES / PROBES_PER_BURST * BURST_INTERVAL, plus ANSWER_WAIT.
+fn spray(local_port: u16, peer: IpAddr) -> std::io::Result<Option<SocketAddr>> {
+    let socket = bind_released(local_port)?;
+    socket.set_nonblocking(true)?;
+    let mut rng = rand::rng();
+    let mut sent = 0;
+    while sent < EASY_PROBES {
+        let burst = PROBES_PER_BURST.min(EASY_PROBES - sent);
+        for _ in 0..burst {
+            let port = rng.random_range(1024..=u16::MAX);
+            socket.send_to(PROBE, SocketAddr::new(peer, port))?;
+        }
+        sent += burst;
+        if let Some(from) = receive_until(&socket, peer, Instant::now() + BURST_INTERVAL)? {
+            return Ok(Some(from));
+        }
+    }
+    receive_until(&socket, peer, Instant::now() + ANSWER_WAIT)
+}
+
+// Sends a probe to each of `addrs` from the interface's listen port, so the
+// NAT lets answers from any of them in. WireGuard sends at most one handshake
+// per REKEY_TIMEOUT wherever it is pointed, so the port is borrowed instead,
+// cutting the other peers off for as long as the sends take.
+pub(crate) fn open_towards(if_name: &str, addrs: &[SocketAddr]) -> std::io::Result<()> {
+    let listen_port =
+        wg_interface::get_listen_port(if_name)?.ok_or(std::io::Error::other("no listen port"))?;
+    wg_interface::update_port(if_name, utils::get_random_port())?;
+    let sent = bind_released(listen_port).and_then(|socket| {
+        for addr in addrs {
+            socket.send_to(PROBE, *addr)?;
+        }
+        Ok(())
+    });
+    wg_interface::update_port(if_name, listen_port)?;
+    sent
+}
+
+// Runs our side of the exchange and points the peer at the path that was
+// found. The hard side moves its listen port onto the port that lined up,
+// the easy side borrows its listen port while probing, as its mapping is the
+// same for every destination. WireGuard listens elsewhere meanwhile, so every
+// other peer on the interface is cut off for the 2-3 seconds of the spray.
+// Returns true if a path was found. The hard side listens through `wait`.
+pub(crate) fn traverse(
+    if_name: &str,
+    peer_tracker: &mut wg_interface::PeerTracker,
+    peer: &Key,
+    role: BirthdayRole,
+    port_to_announce: &mut u16,
+    wait: &mut dyn FnMut(Duration) -> bool,
+) -> std::io::Result<bool> {
+    let iface = if_name.parse()?;
+    match role {
+        BirthdayRole::Hard { peer: endpoint } => {
+            let Some(port) = open_ports(endpoint, wait)? else {
+                log::info!("birthday traversal with {} failed", peer.to_base64());
+                return Ok(false);
+            };
+            log::info!("birthday traversal: moving the listen port to {port}");
+            wg_interface::update_port(if_name, port)?;
+            *port_to_announce = port;
+            wg_interface::punch(&iface, peer_tracker, peer, endpoint)?;
+        }
+        BirthdayRole::Easy { peer: peer_ip } => {
+            let listen_port = wg_interface::get_listen_port(if_name)?
+                .ok_or(std::io::Error::other("no listen port"))?;
+            // given back as soon as the spray is over, found or not
+            wg_interface::update_port(if_name, utils::get_random_port())?;
+            let found = spray(listen_port, peer_ip);
+            wg_interface::update_port(if_name, listen_port)?;
+            let Some(endpoint) = found? else {
+                log::info!("birthday traversal with {} failed", peer.to_base64());
+                return Ok(false);
+            };
+            log::info!("birthday traversal: {} @{endpoint}", peer.to_base64());
+            wg_interface::punch(&iface, peer_tracker, peer, endpoint)?;
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probe_is_answered() {
+        let hard = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let easy = UdpSocket::bind("127.0.0.1:0").unwrap();
+        easy.set_nonblocking(true).unwrap();
+        let localhost = IpAddr::from([127, 0, 0, 1]);
+        assert_eq!(receive_probe(&easy, localhost).unwrap(), None);
+
+        // strays are ignored
+        hard.send_to(b"hello", easy.local_addr().unwrap()).unwrap();
+        hard.send_to(PROBE, easy.local_addr().unwrap()).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(
+            receive_probe(&easy, localhost).unwrap(),
+            Some(hard.local_addr().unwrap())
+        );
+        let mut buf = [0u8; 64];
+        let (n, from) = hard.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..n], PROBE);
+        assert_eq!(from, easy.local_addr().unwrap());
+    }
+}
@@ -1,7 +1,13 @@
-use serde::{Deserialize, Serialize};
-use std::io::Error;
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{collections::HashMap, io::Error, net::IpAddr};
 use wireguard_control::Key;
 
+use crate::{
+    publicip::{ProviderKind, PublicIpConfig},
+    utils::TlsTrust,
+};
+
 static CONFIG_PATH: &str = "/etc/wireplugd";
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -19,14 +25,44 @@ impl Config {
             peers: vec![Peer::new_example()],
         }
     }
+
+    pub(crate) fn fallback_endpoints(&self) -> std::io::Result<HashMap<Key, String>> {
+        let mut endpoints = HashMap::new();
+        for peer in &self.peers {
+            if let Some(endpoint) = &peer.endpoint {
+                let key = Key::from_base64(&peer.public_key)
+                    .map_err(|e| Error::other(format!("Could not parse key: {e}")))?;
+                endpoints.insert(key, endpoint.to_owned());
+            }
+        }
+        Ok(endpoints)
+    }
+
+    // options set on the command line take precedence. The file's own are
+    // kept, so a reload compares against what the file said.
+    pub(crate) fn server_options(&self, cli: ServerOptions) -> ServerOptions {
+        self.interface.servers.clone().merge(cli)
+    }
+
+    // changes to the [Interface] section need a restart
+    pub(crate) fn needs_restart(&self, reloaded: &Config) -> bool {
+        self.interface != reloaded.interface
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 #[serde(rename_all = "PascalCase")]
 pub(crate) struct Interface {
     pub address: String,
     pub private_key: String,
     pub public_key: Option<String>,
+    // seconds since the last handshake before a peer is considered stale / unreachable
+    pub handshake_stale_after: Option<u64>,
+    pub handshake_timeout: Option<u64>,
+    // keepalives that may go missing before a peer is considered stale
+    pub missed_keepalives: Option<u32>,
+    #[serde(flatten)]
+    pub servers: ServerOptions,
 }
 
 impl Interface {
@@ -36,35 +72,234 @@ impl Interface {
             address: String::from("10.0.0.1/24"),
             private_key: key.to_base64(),
             public_key: Some(key.get_public().to_base64()),
+            handshake_stale_after: None,
+            handshake_timeout: None,
+            missed_keepalives: None,
+            servers: ServerOptions::default(),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "PascalCase")]
 pub(crate) struct Peer {
     pub public_key: String,
-    pub allowed_ips: String,
+    #[serde(
+        rename = "AllowedIPs",
+        alias = "AllowedIps",
+        serialize_with = "serialize_allowed_ips",
+        deserialize_with = "deserialize_allowed_ips"
+    )]
+    pub allowed_ips: Vec<IpNet>,
+    pub preshared_key: Option<String>,
+    pub persistent_keepalive: Option<u16>,
+    // host:port, used when the coordination server can't locate the peer
+    pub endpoint: Option<String>,
 }
 
 impl Peer {
     pub(crate) fn new_example() -> Self {
         Self {
             public_key: String::from("AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA"),
-            allowed_ips: String::from("10.0.0.2"),
+            allowed_ips: vec![IpNet::from(IpAddr::from([10, 0, 0, 2]))],
+            preshared_key: None,
+            persistent_keepalive: None,
+            endpoint: None,
+        }
+    }
+}
+
+// AllowedIPs follows wg-quick: a comma separated list of addresses with an
+// optional prefix length. A list of strings is accepted as well.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AllowedIpsRepr {
+    List(String),
+    Array(Vec<String>),
+}
+
+fn parse_allowed_ip(s: &str) -> Result<IpNet, String> {
+    let s = s.trim();
+    s.parse::<IpNet>()
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map(|net| net.trunc())
+        .map_err(|_| format!("bad AllowedIPs entry: {s:?}"))
+}
+
+fn deserialize_allowed_ips<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<IpNet>, D::Error> {
+    let entries = match AllowedIpsRepr::deserialize(d)? {
+        AllowedIpsRepr::List(s) => s
+            .split(',')
+            .filter(|e| !e.trim().is_empty())
+            .map(str::to_owned)
+            .collect(),
+        AllowedIpsRepr::Array(v) => v,
+    };
+    entries
+        .iter()
+        .map(|e| parse_allowed_ip(e))
+        .collect::<Result<_, _>>()
+        .map_err(serde::de::Error::custom)
+}
+
+fn serialize_allowed_ips<S: Serializer>(ips: &[IpNet], s: S) -> Result<S::Ok, S::Error> {
+    let list = ips
+        .iter()
+        .map(|ip| ip.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    s.serialize_str(&list)
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct ServerOptions {
+    pub coordination_server: Option<String>,
+    pub stun_servers: Option<Vec<String>>,
+    pub relay_server: Option<String>,
+    pub ca_cert: Option<String>,
+    pub pinned_cert: Option<String>,
+    // third-party public IP lookups (DNS and HTTPS)
+    pub public_ip_lookups: Option<bool>,
+    // in the order they are asked, e.g. ["coordination", "stun", "dns", "https"]
+    pub public_ip_providers: Option<Vec<ProviderKind>>,
+    // how many providers must agree before the network counts as changed
+    pub public_ip_quorum: Option<usize>,
+    // seconds, per provider
+    pub public_ip_timeout: Option<u64>,
+    // many ports against many probes, for peers behind destination-dependent NATs
+    pub birthday_traversal: Option<bool>,
+    // always relay over TCP, otherwise only when no STUN server answers
+    pub tcp_relay: Option<bool>,
+}
+
+impl ServerOptions {
+    // options set in `other` take precedence
+    pub(crate) fn merge(self, other: ServerOptions) -> ServerOptions {
+        ServerOptions {
+            coordination_server: other.coordination_server.or(self.coordination_server),
+            stun_servers: other.stun_servers.or(self.stun_servers),
+            relay_server: other.relay_server.or(self.relay_server),
+            ca_cert: other.ca_cert.or(self.ca_cert),
+            pinned_cert: other.pinned_cert.or(self.pinned_cert),
+            public_ip_lookups: other.public_ip_lookups.or(self.public_ip_lookups),
+            public_ip_providers: other.public_ip_providers.or(self.public_ip_providers),
+            public_ip_quorum: other.public_ip_quorum.or(self.public_ip_quorum),
+            public_ip_timeout: other.public_ip_timeout.or(self.public_ip_timeout),
+            birthday_traversal: other.birthday_traversal.or(self.birthday_traversal),
+            tcp_relay: other.tcp_relay.or(self.tcp_relay),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Servers {
+    pub coordination: (String, u16),
+    pub stun: Vec<(String, u16)>,
+    pub relay: String,
+    pub tls_trust: TlsTrust,
+    pub public_ip: PublicIpConfig,
+    pub birthday_traversal: bool,
+    pub tcp_relay: bool,
+}
+
+impl Servers {
+    pub(crate) fn from_options(options: &ServerOptions) -> anyhow::Result<Self> {
+        let coordination = match &options.coordination_server {
+            Some(server) => shared::parse_host_port(server, shared::WIREPLUG_WPCOD_PORT)?,
+            None => (
+                shared::WIREPLUG_ORG_WP.to_owned(),
+                shared::WIREPLUG_WPCOD_PORT,
+            ),
+        };
+        let stun = match &options.stun_servers {
+            Some(servers) => servers
+                .iter()
+                .map(|s| shared::parse_host_port(s, shared::WIREPLUG_STUN_PORT))
+                .collect::<Result<Vec<_>, _>>()?,
+            None => vec![
+                (
+                    shared::WIREPLUG_ORG_STUN1.to_owned(),
+                    shared::WIREPLUG_STUN_PORT,
+                ),
+                (
+                    shared::WIREPLUG_ORG_STUN2.to_owned(),
+                    shared::WIREPLUG_STUN_PORT,
+                ),
+            ],
+        };
+        if stun.len() < 2 {
+            return Err(anyhow::Error::msg(
+                "at least two STUN servers are required for NAT detection",
+            ));
+        }
+        let relay = options
+            .relay_server
+            .clone()
+            .unwrap_or(shared::WIREPLUG_ORG_RELAY.to_owned());
+        let tls_trust = TlsTrust::load(options.ca_cert.as_deref(), options.pinned_cert.as_deref())?;
+        Ok(Self {
+            coordination,
+            stun,
+            relay,
+            tls_trust,
+            public_ip: PublicIpConfig::new(
+                options.public_ip_providers.clone(),
+                options.public_ip_lookups.unwrap_or(true),
+                options.public_ip_quorum,
+                options.public_ip_timeout,
+            )?,
+            birthday_traversal: options.birthday_traversal.unwrap_or(false),
+            tcp_relay: options.tcp_relay.unwrap_or(false),
+        })
+    }
+}
+
+// Peers are matched by public key; anything else that differs makes a peer `changed`.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct PeersDiff {
+    pub added: Vec<Peer>,
+    pub removed: Vec<Peer>,
+    pub changed: Vec<(Peer, Peer)>,
+}
+
+impl PeersDiff {
+    pub(crate) fn new(old: &[Peer], new: &[Peer]) -> Self {
+        let mut diff = PeersDiff::default();
+        for peer in new {
+            match old.iter().find(|p| p.public_key == peer.public_key) {
+                None => diff.added.push(peer.clone()),
+                Some(old_peer) if old_peer != peer => {
+                    diff.changed.push((old_peer.clone(), peer.clone()))
+                }
+                Some(_) => (),
+            }
+        }
+        for peer in old {
+            if !new.iter().any(|p| p.public_key == peer.public_key) {
+                diff.removed.push(peer.clone());
+            }
         }
+        diff
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
     }
 }
 
-#[cfg(not(target_os = "openbsd"))]
-pub(crate) fn read_from_file(ifname: &String) -> std::io::Result<Config> {
-    let path = format!("{CONFIG_PATH}.{ifname}");
+pub(crate) fn path(ifname: &str) -> String {
+    format!("{CONFIG_PATH}.{ifname}")
+}
+
+pub(crate) fn read_from_file(ifname: &str) -> std::io::Result<Config> {
+    let path = path(ifname);
     let config = std::fs::read_to_string(path)?;
     toml::from_str(&config).map_err(|e| Error::other(format!("Config file parsing error: {e}")))
 }
 
 pub(crate) fn generate_example_to_file(ifname: &str) -> std::io::Result<()> {
-    let path = format!("{CONFIG_PATH}.{ifname}");
+    let path = path(ifname);
     if std::fs::exists(&path)? {
         return Err(Error::other(format!("{path} already exists")));
     }
@@ -73,3 +308,107 @@ pub(crate) fn generate_example_to_file(ifname: &str) -> std::io::Result<()> {
         .map_err(|e| std::io::Error::other(format!("serialization error: {e}")))?;
     std::fs::write(&path, &config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, Peer, PeersDiff, ServerOptions};
+
+    #[test]
+    fn reloading_unchanged_servers_needs_no_restart() {
+        let file = r#"
+            [Interface]
+            Address = "10.0.0.1/24"
+            PrivateKey = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="
+            CoordinationServer = "wp.example.org:443"
+            StunServers = ["stun1.example.org:4455", "stun2.example.org:4455"]
+
+            [[Peer]]
+            PublicKey = "BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBA="
+            AllowedIPs = "10.0.0.2"
+            "#;
+        let config: Config = toml::from_str(file).unwrap();
+        let cli = ServerOptions {
+            relay_server: Some("relay.example.org".to_owned()),
+            ..Default::default()
+        };
+        let options = config.server_options(cli);
+        assert_eq!(
+            options.coordination_server.as_deref(),
+            Some("wp.example.org:443")
+        );
+        assert_eq!(options.relay_server.as_deref(), Some("relay.example.org"));
+
+        let reloaded: Config = toml::from_str(file).unwrap();
+        assert!(!config.needs_restart(&reloaded));
+        let moved: Config =
+            toml::from_str(&file.replace("wp.example.org:443", "wp.example.org:4430")).unwrap();
+        assert!(config.needs_restart(&moved));
+    }
+
+    #[test]
+    fn allowed_ips_parsing() {
+        let peer: Peer = toml::from_str(
+            r#"
+            PublicKey = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA"
+            AllowedIPs = "10.0.0.2, 192.168.10.7/24,fd00::/64"
+            "#,
+        )
+        .unwrap();
+        let expected: Vec<ipnet::IpNet> = ["10.0.0.2/32", "192.168.10.0/24", "fd00::/64"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        assert_eq!(peer.allowed_ips, expected);
+
+        let peer: Peer = toml::from_str(
+            r#"
+            PublicKey = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA"
+            AllowedIps = ["10.0.0.2/32", "192.168.10.0/24", "fd00::/64"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(peer.allowed_ips, expected);
+
+        assert!(
+            toml::from_str::<Peer>(
+                r#"
+                PublicKey = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA"
+                AllowedIPs = "10.0.0.300"
+                "#,
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn peers_diff() {
+        let peer = |key: &str, ip: &str| Peer {
+            public_key: key.to_owned(),
+            allowed_ips: vec![ip.parse().unwrap()],
+            preshared_key: None,
+            persistent_keepalive: None,
+            endpoint: None,
+        };
+        let old = vec![
+            peer("kept", "10.0.0.2/32"),
+            peer("changed", "10.0.0.3/32"),
+            peer("removed", "10.0.0.4/32"),
+        ];
+        let new = vec![
+            peer("added", "10.0.0.5/32"),
+            peer("changed", "10.0.1.0/24"),
+            peer("kept", "10.0.0.2/32"),
+        ];
+        let diff = PeersDiff::new(&old, &new);
+        assert_eq!(diff.added, vec![peer("added", "10.0.0.5/32")]);
+        assert_eq!(diff.removed, vec![peer("removed", "10.0.0.4/32")]);
+        assert_eq!(
+            diff.changed,
+            vec![(
+                peer("changed", "10.0.0.3/32"),
+                peer("changed", "10.0.1.0/24")
+            )]
+        );
+        assert!(PeersDiff::new(&new, &new).is_empty());
+    }
+}
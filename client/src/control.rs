@@ -0,0 +1,108 @@
+use std::{
+    collections::VecDeque,
+    os::unix::{
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    },
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+    time::Duration,
+};
+
+use shared::control::{self, ControlRequest, ControlResponse};
+
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+// the daemon may be busy announcing
+const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub(crate) struct Request {
+    pub(crate) request: ControlRequest,
+    reply: Sender<ControlResponse>,
+}
+
+impl Request {
+    pub(crate) fn respond(self, response: ControlResponse) {
+        // the client may have given up already
+        let _ = self.reply.send(response);
+    }
+}
+
+fn handle_client(mut stream: UnixStream, requests: &Sender<Request>) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let request: ControlRequest = control::read_message(&mut stream)?;
+    log::debug!("control: {request:?}");
+    let (reply, response) = mpsc::channel();
+    requests
+        .send(Request { request, reply })
+        .map_err(|_| std::io::Error::other("daemon is gone"))?;
+    let response = response
+        .recv_timeout(REPLY_TIMEOUT)
+        .unwrap_or(ControlResponse::Error(
+            "timed out waiting for wireplugd".to_owned(),
+        ));
+    control::write_message(&mut stream, &response)
+}
+
+// Accepts wireplugctl connections on a background thread; the daemon loop
+// picks up the requests and answers them.
+pub(crate) struct ControlSocket {
+    path: String,
+    incoming: Receiver<Request>,
+    queue: VecDeque<Request>,
+}
+
+impl ControlSocket {
+    pub(crate) fn bind(ifname: &str) -> std::io::Result<Self> {
+        let path = control::socket_path(ifname);
+        if UnixStream::connect(&path).is_ok() {
+            return Err(std::io::Error::other(format!(
+                "{path} is in use, is another wireplugd running on {ifname}?"
+            )));
+        }
+        // left behind by an earlier run
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+
+        let (requests, incoming) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(s) => s,
+                    Err(e) => {
+                        log::warn!("control: {e}");
+                        continue;
+                    }
+                };
+                let requests = requests.clone();
+                thread::spawn(move || {
+                    if let Err(e) = handle_client(stream, &requests) {
+                        log::debug!("control: {e}");
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            path,
+            incoming,
+            queue: VecDeque::new(),
+        })
+    }
+
+    pub(crate) fn has_pending(&mut self) -> bool {
+        self.queue.extend(self.incoming.try_iter());
+        !self.queue.is_empty()
+    }
+
+    pub(crate) fn next_request(&mut self) -> Option<Request> {
+        self.has_pending();
+        self.queue.pop_front()
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
@@ -1,44 +1,129 @@
 use std::{
-    io::Write,
-    os::unix::net::UnixStream,
+    collections::HashMap,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
     thread::{self},
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 
+use shared::control::{ControlRequest, ControlResponse, DaemonStatus};
 use wireguard_control::Key;
 
 use crate::{
-    announce, nat,
+    announce, birthday,
+    config::{self, Config, PeersDiff, Servers},
+    control::ControlSocket,
+    nat,
     netstat::{self, NetInfo},
+    state::{self, State},
     utils, wg_interface,
 };
 
+// What handling inactive peers takes from the monitoring loop
+pub(crate) struct LoopContext<'a> {
+    pub servers: &'a Servers,
+    pub state: &'a mut State,
+    pub port_to_announce: &'a mut u16,
+    // sleeps, and returns true to stop early for a control request or a network change
+    pub wait: &'a mut dyn FnMut(Duration) -> bool,
+}
+
 pub(crate) fn handle_inactive_peers(
-    ifname: &String,
+    ifname: &str,
     peer_tracker: &mut wg_interface::PeerTracker,
     peers: &mut Vec<Key>,
     netinfo: NetInfo,
-    port_to_announce: u16,
-    needs_relay: bool,
+    ctx: LoopContext,
 ) -> anyhow::Result<()> {
     const MAX_ANNOUNCE_RETRIES: usize = 3;
+    let LoopContext {
+        servers,
+        state,
+        port_to_announce,
+        wait,
+    } = ctx;
+    let fingerprint = netinfo.fingerprint();
     for _ in 1..=MAX_ANNOUNCE_RETRIES {
-        match announce::announce(ifname, peers, port_to_announce, &netinfo, needs_relay) {
-            Ok(response) => {
-                let peers_updated = wg_interface::update_peers(
+        match announce::announce(
+            ifname,
+            peers,
+            *port_to_announce,
+            &netinfo,
+            netinfo.hard_nat,
+            servers,
+        ) {
+            Ok(mut response) => {
+                let received = Instant::now();
+                let mut rendezvous = HashMap::new();
+                let mut birthday_settled = vec![];
+                for (peer, r) in response.rendezvous {
+                    let at = received + Duration::from_millis(r.delay_ms as u64);
+                    let Some(role) = r.birthday else {
+                        rendezvous.insert(peer, at);
+                        continue;
+                    };
+                    let Ok(key) = Key::from_base64(&peer) else {
+                        continue;
+                    };
+                    let delay = at.saturating_duration_since(Instant::now());
+                    if delay >= wg_interface::RENDEZVOUS_MAX_WAIT {
+                        log::warn!("not waiting {delay:?} for birthday traversal with {peer}");
+                        continue;
+                    }
+                    // the peers are handled again after the next announcement
+                    if wait(delay) {
+                        log::debug!("birthday traversal with {peer} interrupted");
+                        return Ok(());
+                    }
+                    match birthday::traverse(
+                        ifname,
+                        peer_tracker,
+                        &key,
+                        role,
+                        port_to_announce,
+                        wait,
+                    ) {
+                        // the probing below would undo the path that was found
+                        Ok(true) => {
+                            response.peer_endpoints.remove(&peer);
+                            birthday_settled
+                                .push((key, Some(wg_interface::CandidateKind::Birthday)));
+                        }
+                        Ok(false) => (),
+                        Err(e) => log::warn!("birthday traversal failed: {e}"),
+                    }
+                }
+                let new_endpoints = response
+                    .peer_endpoints
+                    .into_iter()
+                    .map(|(peer, endpoint)| {
+                        let at = rendezvous.get(&peer).copied();
+                        (peer, (endpoint, at))
+                    })
+                    .collect();
+                let mut peers_settled = wg_interface::update_peers(
                     ifname,
                     peer_tracker,
-                    response.peer_endpoints,
+                    new_endpoints,
                     netinfo.wan_ipv6.is_some(),
+                    servers,
+                    &state.rankings(&fingerprint),
+                    wait,
                 )?;
-                if !peers_updated.is_empty() {
-                    log::info!(
-                        "some endpoints were updated, waiting for peers to attempt handshakes.."
-                    );
-                    thread::sleep(Duration::from_secs(5));
-                    peers.retain(|p| !peers_updated.contains(p));
+                peers_settled.extend(birthday_settled);
+                let mut ranking_changed = false;
+                for (peer, kind) in &peers_settled {
+                    if let Some(kind) = kind {
+                        ranking_changed |= state.rank(&fingerprint, peer, *kind);
+                    }
                 }
-                return Ok(());
+                if ranking_changed && let Err(e) = state.save() {
+                    log::warn!("could not save state: {e}");
+                }
+                peers.retain(|p| !peers_settled.iter().any(|(settled, _)| settled == p));
+                break;
             }
             Err(e) => {
                 log::warn!("announcement failed: {e}");
@@ -46,26 +131,233 @@ pub(crate) fn handle_inactive_peers(
             }
         }
     }
+
+    let peers_updated = wg_interface::apply_fallback_endpoints(ifname, peer_tracker, peers)?;
+    if !peers_updated.is_empty() {
+        log::info!("some peers fell back to static endpoints, waiting for handshakes..");
+        thread::sleep(Duration::from_secs(5));
+        peers.retain(|p| !peers_updated.contains(p));
+    }
     Ok(())
 }
 
-fn show_stats(ifname: &str, net_info: Option<NetInfo>) -> anyhow::Result<()> {
-    let mut s = UnixStream::connect("/var/run/wireplugd.sock")?;
-    write!(s, "\x1B[2J\x1B[1;1H")?;
-    writeln!(s, "Network:\n-------")?;
-    match net_info {
-        Some(n) => writeln!(s, "{n}")?,
-        None => writeln!(s, "\tN/A")?,
+// Points peers back at endpoints that worked on this network before
+fn restore_endpoints(
+    ifname: &str,
+    peer_tracker: &mut wg_interface::PeerTracker,
+    state: &State,
+    netinfo: Option<NetInfo>,
+) -> anyhow::Result<()> {
+    let Some(netinfo) = netinfo else {
+        return Ok(());
+    };
+    let endpoints = state.endpoints(&netinfo.fingerprint());
+    if endpoints.is_empty() {
+        return Ok(());
+    }
+    let restored = wg_interface::restore_endpoints(ifname, peer_tracker, &endpoints)?;
+    if !restored.is_empty() {
+        log::info!("restored {} last known endpoints", restored.len());
     }
-    let peer_info = wg_interface::get_peer_info(ifname)?;
-    s.write_all(peer_info.as_bytes())?;
     Ok(())
 }
 
-pub(crate) fn monitor_interface(ifname: &String, traverse_nat: bool) -> anyhow::Result<()> {
+fn remember_endpoints(ifname: &str, state: &mut State, netinfo: Option<NetInfo>) {
+    let Some(netinfo) = netinfo else {
+        return;
+    };
+    let endpoints = match wg_interface::get_working_endpoints(ifname) {
+        Ok(endpoints) => endpoints,
+        Err(e) => {
+            log::warn!("could not get working endpoints: {e}");
+            return;
+        }
+    };
+    if state.remember(&netinfo.fingerprint(), &endpoints)
+        && let Err(e) = state.save()
+    {
+        log::warn!("could not save state: {e}");
+    }
+}
+
+// Triggers a reload on SIGHUP or when the config file's mtime changes.
+struct ConfigWatcher {
+    path: String,
+    modified: Option<SystemTime>,
+    sighup: Arc<AtomicBool>,
+}
+
+impl ConfigWatcher {
+    fn new(ifname: &str) -> std::io::Result<Self> {
+        let path = config::path(ifname);
+        let sighup = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&sighup))?;
+        Ok(Self {
+            modified: Self::get_modified(&path),
+            path,
+            sighup,
+        })
+    }
+
+    fn get_modified(path: &str) -> Option<SystemTime> {
+        std::fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    fn should_reload(&mut self) -> bool {
+        let modified = Self::get_modified(&self.path);
+        let file_changed = modified.is_some() && modified != self.modified;
+        self.modified = modified;
+        let sighup = self.sighup.swap(false, Ordering::Relaxed);
+        if sighup {
+            log::info!("SIGHUP received, reloading {}", self.path);
+        } else if file_changed {
+            log::info!("{} changed, reloading", self.path);
+        }
+        sighup || file_changed
+    }
+}
+
+fn reload_config(
+    ifname: &str,
+    peer_tracker: &mut wg_interface::PeerTracker,
+    current: &mut Config,
+    inactive_peers: &mut Vec<Key>,
+) -> anyhow::Result<()> {
+    let new_config = config::read_from_file(ifname)?;
+    if current.needs_restart(&new_config) {
+        log::warn!("changes to the [Interface] section require restarting wireplugd");
+    }
+    let diff = PeersDiff::new(&current.peers, &new_config.peers);
+    if diff.is_empty() {
+        log::info!("no peer changes");
+    } else {
+        let to_announce = wg_interface::apply_peers_diff(ifname, peer_tracker, &diff, &new_config)?;
+        let all_peers = wg_interface::get_all_peers(ifname)?;
+        inactive_peers.retain(|p| all_peers.contains(p));
+        for peer in to_announce {
+            if !inactive_peers.contains(&peer) {
+                inactive_peers.push(peer);
+            }
+        }
+    }
+    current.peers = new_config.peers;
+    Ok(())
+}
+
+// State the control socket can inspect or change
+struct ControlState {
+    paused: bool,
+    nat: Option<String>,
+    // ignore the stored profile on the next network change
+    redetect_nat: bool,
+}
+
+fn handle_control_request(
+    request: &ControlRequest,
+    ifname: &str,
+    state: &mut ControlState,
+    netmon: &mut netstat::NetworkMonitor,
+    peer_tracker: &wg_interface::PeerTracker,
+    inactive_peers: &mut Vec<Key>,
+    port_to_announce: u16,
+) -> ControlResponse {
+    match request {
+        ControlRequest::Status => {
+            match wg_interface::get_device_status(ifname, peer_tracker, inactive_peers) {
+                Ok((listen_port, peers)) => ControlResponse::Status(DaemonStatus {
+                    interface: ifname.to_owned(),
+                    paused: state.paused,
+                    listen_port,
+                    announced_port: port_to_announce,
+                    nat: state.nat.clone(),
+                    network: netmon.get_current().map(|n| n.to_status()),
+                    peers,
+                }),
+                Err(e) => ControlResponse::Error(e.to_string()),
+            }
+        }
+        ControlRequest::Peer(public_key) => {
+            match wg_interface::get_device_status(ifname, peer_tracker, inactive_peers) {
+                Ok((_, peers)) => match peers.into_iter().find(|p| p.public_key == *public_key) {
+                    Some(peer) => ControlResponse::Peer(peer),
+                    None => ControlResponse::Error(format!("no such peer: {public_key}")),
+                },
+                Err(e) => ControlResponse::Error(e.to_string()),
+            }
+        }
+        ControlRequest::Announce if state.paused => {
+            ControlResponse::Error("endpoint management is paused".to_owned())
+        }
+        ControlRequest::Announce => match wg_interface::get_all_peers(ifname) {
+            Ok(peers) => {
+                log::info!("control: announcing all peers");
+                *inactive_peers = peers;
+                ControlResponse::Done
+            }
+            Err(e) => ControlResponse::Error(e.to_string()),
+        },
+        ControlRequest::DetectNat => {
+            log::info!("control: forcing NAT detection");
+            netmon.force_redetection();
+            state.redetect_nat = true;
+            ControlResponse::Done
+        }
+        ControlRequest::Pause => {
+            log::info!("control: pausing endpoint management");
+            state.paused = true;
+            ControlResponse::Done
+        }
+        ControlRequest::Resume => {
+            log::info!("control: resuming endpoint management");
+            state.paused = false;
+            ControlResponse::Done
+        }
+    }
+}
+
+// Like NetworkMonitor::wait, but also returns early when a control request arrives
+fn wait(
+    netmon: &mut netstat::NetworkMonitor,
+    control: &mut Option<ControlSocket>,
+    timeout: Duration,
+) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if control.as_mut().is_some_and(|c| c.has_pending()) {
+            return true;
+        }
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return false;
+        }
+        if netmon.wait(left.min(Duration::from_secs(1))) {
+            return true;
+        }
+    }
+}
+
+// how long UDP is relayed over TCP before checking whether it gets through again
+const UDP_RECHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+pub(crate) fn monitor_interface(
+    ifname: &String,
+    traverse_nat: bool,
+    servers: &Servers,
+    mut config: Option<Config>,
+) -> anyhow::Result<()> {
+    let liveness_thresholds = wg_interface::LivenessThresholds::from_config(config.as_ref());
+    let fallback_endpoints = match &config {
+        Some(c) => c.fallback_endpoints()?,
+        None => HashMap::new(),
+    };
+    let mut config_watcher = match config {
+        Some(_) => Some(ConfigWatcher::new(ifname)?),
+        None => None,
+    };
     let mut netmon = netstat::NetworkMonitor::new(ifname);
-    let mut peers_manager = wg_interface::PeerTracker::new();
+    let mut peers_manager = wg_interface::PeerTracker::new(fallback_endpoints);
     wg_interface::init_peers_activity(ifname, &mut peers_manager)?;
+    let mut state = State::load(ifname);
 
     log::info!("monitoring interface: {ifname} | NAT travesal={traverse_nat}");
 
@@ -73,33 +365,165 @@ pub(crate) fn monitor_interface(ifname: &String, traverse_nat: bool) -> anyhow::
     let mut next_inactivity_check = Instant::now() + peer_is_inactive_duration;
     let mut inactive_peers = vec![];
     let mut port_to_announce = 0;
+    let mut port_mapping: Option<nat::MappedPort> = None;
+    let mut udp_recheck: Option<Instant> = None;
+
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for signal in [signal_hook::consts::SIGTERM, signal_hook::consts::SIGINT] {
+        // a second signal terminates right away
+        signal_hook::flag::register_conditional_shutdown(signal, 1, Arc::clone(&shutdown))?;
+        signal_hook::flag::register(signal, Arc::clone(&shutdown))?;
+    }
+
+    let mut control = match ControlSocket::bind(ifname) {
+        Ok(control) => Some(control),
+        Err(e) => {
+            log::warn!("control socket unavailable: {e}");
+            None
+        }
+    };
+    let mut control_state = ControlState {
+        paused: false,
+        nat: None,
+        redetect_nat: false,
+    };
+
     loop {
-        if let Err(e) = show_stats(ifname, netmon.get_current()) {
-            log::warn!("could not show stats: {e}");
+        if shutdown.load(Ordering::Relaxed) {
+            log::info!("shutting down");
+            // dropping the mapping removes it from the gateway
+            drop(port_mapping);
+            return Ok(());
+        }
+        if let Some(mapping) = port_mapping.as_mut() {
+            match mapping.renew_if_needed() {
+                Ok(()) => port_to_announce = mapping.external_port(),
+                Err(e) => log::warn!(
+                    "failed to renew {} port mapping: {e}",
+                    mapping.protocol_name()
+                ),
+            }
         }
-        match netmon.check_status() {
-            netstat::NetStatus::Online | netstat::NetStatus::ChangedToPrev => (),
+        if let (Some(watcher), Some(current)) = (config_watcher.as_mut(), config.as_mut())
+            && watcher.should_reload()
+            && let Err(e) = reload_config(ifname, &mut peers_manager, current, &mut inactive_peers)
+        {
+            log::warn!("failed to reload config: {e}");
+        }
+        while let Some(request) = control.as_mut().and_then(|c| c.next_request()) {
+            let response = handle_control_request(
+                &request.request,
+                ifname,
+                &mut control_state,
+                &mut netmon,
+                &peers_manager,
+                &mut inactive_peers,
+                port_to_announce,
+            );
+            request.respond(response);
+        }
+        if control_state.paused {
+            wait(&mut netmon, &mut control, Duration::from_secs(10));
+            continue;
+        }
+        if udp_recheck.is_some_and(|at| Instant::now() >= at) {
+            log::info!("checking whether UDP gets through again");
+            netmon.force_redetection();
+        }
+        match netmon.check_status(servers) {
+            netstat::NetStatus::Online => (),
+            netstat::NetStatus::ChangedToPrev => {
+                restore_endpoints(ifname, &mut peers_manager, &state, netmon.get_current())?;
+            }
+            // keep announcing, the coordination server may pair us up for birthday traversal
+            netstat::NetStatus::HardNat if servers.birthday_traversal => (),
             netstat::NetStatus::Offline | netstat::NetStatus::HardNat => {
-                thread::sleep(Duration::from_secs(5));
+                wait(&mut netmon, &mut control, Duration::from_secs(5));
                 continue;
             }
             netstat::NetStatus::ChangedToNew => {
-                let new_port = utils::get_random_port();
-                port_to_announce = match traverse_nat {
+                port_mapping = None;
+                udp_recheck = None;
+                // works even if the servers can't be reached
+                restore_endpoints(ifname, &mut peers_manager, &state, netmon.get_current())?;
+                let fingerprint = netmon.get_current().map(|n| n.fingerprint());
+                let profile = match std::mem::take(&mut control_state.redetect_nat) {
+                    true => None,
+                    false => fingerprint.as_deref().and_then(|fp| state.profile(fp)),
+                };
+                // known networks keep their port, so peers may still have it
+                let new_port = match profile {
+                    Some(profile) => {
+                        log::info!("Network: known network, reusing port {}", profile.port);
+                        profile.port
+                    }
+                    None => utils::get_random_port(),
+                };
+                // there is no NAT to traverse on IPv6-only networks
+                let has_ipv4 = netmon.get_current().is_some_and(|n| n.wan_ipv4.is_some());
+                let mut nat_profile = None;
+                port_to_announce = match traverse_nat && has_ipv4 {
                     true => {
-                        let nat_kind = match nat::detect_kind(new_port) {
+                        let gateway = netmon.get_current().and_then(|n| n.default_gateway);
+                        let nat_kind = match profile.and_then(|p| p.nat) {
+                            Some(known) => {
+                                nat::reuse_profile(known, new_port, &servers.stun, gateway).map(
+                                    |(kind, detected)| {
+                                        let known = profile.and_then(|p| p.classification);
+                                        (kind, detected.or(known))
+                                    },
+                                )
+                            }
+                            None => nat::detect_kind(new_port, &servers.stun, gateway)
+                                .map(|(kind, classification)| (kind, Some(classification))),
+                        };
+                        let (nat_kind, classification) = match nat_kind {
                             Ok(res) => res,
+                            // we are online over TCP, it's UDP that doesn't get through
+                            // to any STUN server
+                            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                                log::debug!("NAT detection: {e}");
+                                (nat::NatKind::UdpBlocked, None)
+                            }
                             Err(e) => {
                                 log::warn!("failed to perform NAT detection: {e}");
+                                // the network is the same, detect it as new again
+                                netmon.force_redetection();
                                 thread::sleep(Duration::from_secs(5));
                                 continue;
                             }
                         };
+                        control_state.nat = Some(nat_kind.to_string());
+                        if let Some(classification) = classification {
+                            log::info!("NAT behaviour: {classification}");
+                        }
+                        netmon.set_nat(classification);
+                        nat_profile = Some(nat::NatProfile::from(&nat_kind));
                         match nat_kind {
                             nat::NatKind::Easy => new_port,
                             nat::NatKind::FixedPortMapping(port_mapping_nat) => {
                                 port_mapping_nat.obsereved_port
                             }
+                            nat::NatKind::Mapped(mapping) => {
+                                log::info!(
+                                    "Destination-Dependent NAT detected, {} mapped external port {} to {new_port}",
+                                    mapping.protocol_name(),
+                                    mapping.external_port()
+                                );
+                                port_mapping.insert(mapping).external_port()
+                            }
+                            nat::NatKind::Sequential(step) => {
+                                log::info!(
+                                    "Destination-Dependent NAT detected, ports are allocated in steps of {step}"
+                                );
+                                new_port
+                            }
+                            nat::NatKind::UdpBlocked => {
+                                log::warn!("UDP seems to be blocked, relaying over TCP");
+                                netmon.set_udp_blocked(true);
+                                udp_recheck = Some(Instant::now() + UDP_RECHECK_INTERVAL);
+                                new_port
+                            }
                             nat::NatKind::Hard => {
                                 log::warn!("Destination-Dependent NAT detected");
                                 netmon.set_hard_nat(true);
@@ -109,6 +533,19 @@ pub(crate) fn monitor_interface(ifname: &String, traverse_nat: bool) -> anyhow::
                     }
                     false => new_port,
                 };
+                if let Some(fingerprint) = fingerprint {
+                    let profile = state::Profile {
+                        nat: nat_profile,
+                        port: new_port,
+                        needs_relay: netmon.needs_relay(),
+                        classification: netmon.get_current().and_then(|n| n.nat),
+                    };
+                    if state.set_profile(&fingerprint, profile)
+                        && let Err(e) = state.save()
+                    {
+                        log::warn!("could not save state: {e}");
+                    }
+                }
 
                 log::debug!("updating listen port to {new_port} ..");
                 // wait before reusing the port
@@ -122,8 +559,13 @@ pub(crate) fn monitor_interface(ifname: &String, traverse_nat: bool) -> anyhow::
 
         if Instant::now() > next_inactivity_check {
             next_inactivity_check += peer_is_inactive_duration;
+            remember_endpoints(ifname, &mut state, netmon.get_current());
             inactive_peers.clear();
-            inactive_peers = wg_interface::get_inactive_peers_by_rx(ifname, &mut peers_manager)?;
+            inactive_peers = wg_interface::get_peers_to_announce(
+                ifname,
+                &mut peers_manager,
+                &liveness_thresholds,
+            )?;
         }
         if !inactive_peers.is_empty() {
             log::info!("{ifname} has {} INACTIVE peers", inactive_peers.len());
@@ -136,10 +578,15 @@ pub(crate) fn monitor_interface(ifname: &String, traverse_nat: bool) -> anyhow::
                 &mut peers_manager,
                 &mut inactive_peers,
                 netinfo,
-                port_to_announce,
-                netmon.needs_relay(),
+                LoopContext {
+                    servers,
+                    state: &mut state,
+                    port_to_announce: &mut port_to_announce,
+                    // control requests and network changes don't wait for probing
+                    wait: &mut |timeout| wait(&mut netmon, &mut control, timeout),
+                },
             )?;
         }
-        thread::sleep(Duration::from_secs(10));
+        wait(&mut netmon, &mut control, Duration::from_secs(10));
     }
 }
@@ -3,12 +3,19 @@ use log::Level;
 use shared::TmpLogger;
 
 mod announce;
+mod birthday;
 mod config;
+mod control;
 mod daemon;
 mod nat;
+mod natpmp;
 #[cfg(target_os = "linux")]
 mod netlink;
 mod netstat;
+mod publicip;
+mod state;
+mod tcp_relay;
+mod upnp;
 mod utils;
 mod wg_interface;
 
@@ -33,6 +40,40 @@ struct Cli {
     no_nat: bool,
     #[arg(short, long)]
     log_level: Option<LogLevelPicker>,
+    #[arg(long, help = "Coordination server (host[:port])")]
+    coordination_server: Option<String>,
+    #[arg(
+        long = "stun-server",
+        help = "STUN server (host[:port]), may be repeated"
+    )]
+    stun_servers: Vec<String>,
+    #[arg(long, help = "Relay server host")]
+    relay_server: Option<String>,
+    #[arg(
+        long,
+        help = "PEM file with CA certificates to trust instead of the web PKI"
+    )]
+    ca_cert: Option<String>,
+    #[arg(
+        long,
+        help = "PEM file with the coordination server's certificate to pin"
+    )]
+    pinned_cert: Option<String>,
+    #[arg(
+        long,
+        help = "Only learn our public IP from the coordination server, never from third parties"
+    )]
+    no_public_ip_lookups: bool,
+    #[arg(
+        long,
+        help = "Let the coordination server arrange birthday-paradox traversal of hard NATs"
+    )]
+    birthday_traversal: bool,
+    #[arg(
+        long,
+        help = "Reach peers through the coordination server over TCP, for networks that block UDP"
+    )]
+    tcp_relay: bool,
 }
 
 fn start(
@@ -40,6 +81,7 @@ fn start(
     no_config: bool,
     log_level: Level,
     traverse_nat: bool,
+    server_options: config::ServerOptions,
 ) -> anyhow::Result<()> {
     log::set_max_level(log_level.to_level_filter());
     log::set_logger(&LOGGER).map_err(|e| anyhow::Error::msg(format!("set_logger(): {e}")))?;
@@ -54,10 +96,21 @@ fn start(
         _ => None,
     };
 
-    wg_interface::configure(ifname, config)?;
+    let server_options = match config.as_ref() {
+        Some(c) => c.server_options(server_options),
+        None => server_options,
+    };
+    let servers = config::Servers::from_options(&server_options)?;
+    log::debug!(
+        "coordination server: {}:{}",
+        servers.coordination.0,
+        servers.coordination.1
+    );
+
+    wg_interface::configure(ifname, config.as_ref())?;
     log::info!("interface configured");
     wg_interface::show_config(ifname)?;
-    daemon::monitor_interface(ifname, traverse_nat)?;
+    daemon::monitor_interface(ifname, traverse_nat, &servers, config)?;
     Ok(())
 }
 
@@ -83,7 +136,39 @@ fn main() {
         None => Level::Info,
     };
 
-    if let Err(e) = start(ifname, cli.no_config, log_level, traverse_nat) {
+    let server_options = config::ServerOptions {
+        coordination_server: cli.coordination_server,
+        stun_servers: match cli.stun_servers.is_empty() {
+            true => None,
+            false => Some(cli.stun_servers),
+        },
+        relay_server: cli.relay_server,
+        ca_cert: cli.ca_cert,
+        pinned_cert: cli.pinned_cert,
+        public_ip_lookups: match cli.no_public_ip_lookups {
+            true => Some(false),
+            false => None,
+        },
+        public_ip_providers: None,
+        public_ip_quorum: None,
+        public_ip_timeout: None,
+        birthday_traversal: match cli.birthday_traversal {
+            true => Some(true),
+            false => None,
+        },
+        tcp_relay: match cli.tcp_relay {
+            true => Some(true),
+            false => None,
+        },
+    };
+
+    if let Err(e) = start(
+        ifname,
+        cli.no_config,
+        log_level,
+        traverse_nat,
+        server_options,
+    ) {
         eprintln!("fatal: {e}");
         std::process::exit(1);
     }
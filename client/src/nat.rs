@@ -1,10 +1,39 @@
-use shared::protocol;
+use rand::Rng;
+use shared::{
+    protocol::{self, NatBehaviour, NatClassification, PortRange},
+    stun,
+};
 use std::{
-    io::Write,
-    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    io::{ErrorKind, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket},
     time::Duration,
 };
 
+use serde::{Deserialize, Serialize};
+
+use crate::{natpmp, upnp};
+
+pub(crate) const STUN_TIMEOUT: Duration = Duration::from_millis(500);
+// UDP gets lost, every test is retried this many times
+const ATTEMPTS: usize = 3;
+const NO_CHANGE: stun::ChangeRequest = stun::ChangeRequest {
+    ip: false,
+    port: false,
+};
+// new mappings opened to measure how a destination-dependent NAT allocates ports
+const ALLOCATION_PROBES: usize = 5;
+// anything further apart isn't worth predicting
+const MAX_ALLOCATION_STEP: u16 = 16;
+// announced after the last observed port, other traffic may take some
+const PREDICTED_PORTS: u16 = 4;
+
+fn bind_for(dst: SocketAddr, local_port: u16) -> std::io::Result<UdpSocket> {
+    match dst {
+        SocketAddr::V4(_) => UdpSocket::bind(format!("0.0.0.0:{local_port}")),
+        SocketAddr::V6(_) => UdpSocket::bind(format!("[::]:{local_port}")),
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct PortMappingNat {
     pub _listen_port: u16,
@@ -20,28 +49,128 @@ impl PortMappingNat {
     }
 }
 
+// A mapping held on the gateway, removed when dropped
+#[derive(Debug)]
+pub(crate) enum MappedPort {
+    Pmp(natpmp::PortMapping),
+    Upnp(upnp::PortMapping),
+}
+
+impl MappedPort {
+    pub(crate) fn protocol_name(&self) -> &'static str {
+        match self {
+            MappedPort::Pmp(mapping) => mapping.protocol_name(),
+            MappedPort::Upnp(_) => "UPnP IGD",
+        }
+    }
+
+    pub(crate) fn external_port(&self) -> u16 {
+        match self {
+            MappedPort::Pmp(mapping) => mapping.external_port(),
+            MappedPort::Upnp(mapping) => mapping.external_port(),
+        }
+    }
+
+    pub(crate) fn renew_if_needed(&mut self) -> std::io::Result<()> {
+        match self {
+            MappedPort::Pmp(mapping) => mapping.renew_if_needed(),
+            MappedPort::Upnp(mapping) => mapping.renew_if_needed(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(crate) enum NatKind {
     Easy,
     FixedPortMapping(PortMappingNat),
+    Mapped(MappedPort),
+    // destination-dependent, but ports are handed out this far apart in order
+    Sequential(u16),
     Hard,
+    // no STUN server answered, though we are online
+    UdpBlocked,
+}
+
+impl std::fmt::Display for NatKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NatKind::Easy => write!(f, "endpoint-independent mapping, same port"),
+            NatKind::FixedPortMapping(nat) => write!(
+                f,
+                "endpoint-independent mapping, port {}",
+                nat.obsereved_port
+            ),
+            NatKind::Mapped(mapping) => write!(
+                f,
+                "destination-dependent mapping, port {} forwarded by {}",
+                mapping.external_port(),
+                mapping.protocol_name()
+            ),
+            NatKind::Sequential(step) => {
+                write!(
+                    f,
+                    "destination-dependent mapping, sequential ports (step {step})"
+                )
+            }
+            NatKind::Hard => write!(f, "destination-dependent mapping"),
+            NatKind::UdpBlocked => write!(f, "UDP blocked, relaying over TCP"),
+        }
+    }
+}
+
+// What detect_kind found on a network, kept to skip detection next time
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub(crate) enum NatProfile {
+    Easy,
+    FixedPortMapping,
+    Mapped,
+    Sequential(u16),
+    Hard,
+    UdpBlocked,
+}
+
+impl From<&NatKind> for NatProfile {
+    fn from(kind: &NatKind) -> Self {
+        match kind {
+            NatKind::Easy => NatProfile::Easy,
+            NatKind::FixedPortMapping(_) => NatProfile::FixedPortMapping,
+            NatKind::Mapped(_) => NatProfile::Mapped,
+            NatKind::Sequential(step) => NatProfile::Sequential(*step),
+            NatKind::Hard => NatProfile::Hard,
+            NatKind::UdpBlocked => NatProfile::UdpBlocked,
+        }
+    }
+}
+
+// Asks the gateway to forward a port when the NAT mapping itself is unusable
+fn map_port(local_port: u16) -> Option<MappedPort> {
+    match natpmp::PortMapping::create(local_port) {
+        Ok(mapping) => return Some(MappedPort::Pmp(mapping)),
+        Err(e) => log::debug!("PCP/NAT-PMP port mapping failed: {e}"),
+    }
+    match upnp::PortMapping::create(local_port) {
+        Ok(mapping) => return Some(MappedPort::Upnp(mapping)),
+        Err(e) => log::debug!("UPnP IGD port mapping failed: {e}"),
+    }
+    None
 }
 
-fn send_stun_request(
+pub(crate) fn send_stun_request(
     dst: SocketAddr,
     local_port: u16,
+    timeout: Duration,
 ) -> Result<protocol::WireplugStunResponse, std::io::Error> {
     let mut buf = Vec::with_capacity(std::mem::size_of::<protocol::WireplugStunRequest>() + 4);
 
-    buf.write(&protocol::WIREPLUG_PROTOCOL_MAGIC)?;
-    buf.write(&protocol::WIREPLUG_PROTOCOL_VERSION)?;
+    buf.write_all(&protocol::WIREPLUG_PROTOCOL_MAGIC)?;
+    buf.write_all(&protocol::WIREPLUG_PROTOCOL_VERSION)?;
 
     let request = protocol::WireplugStunRequest::new(local_port);
     buf = postcard::to_extend(&request, buf)
         .map_err(|e| std::io::Error::other(format!("encoding error: {e}")))?;
 
-    let socket = UdpSocket::bind(format!("0.0.0.0:{local_port}"))?;
-    socket.set_read_timeout(Some(Duration::from_millis(500)))?;
+    let socket = bind_for(dst, local_port)?;
+    socket.set_read_timeout(Some(timeout))?;
     if buf.len() != socket.send_to(&buf, dst)? {
         return Err(std::io::Error::other("send_stun_request() failed"));
     }
@@ -54,43 +183,461 @@ fn send_stun_request(
     Ok(response)
 }
 
-pub fn detect_kind(local_port: u16) -> Result<NatKind, std::io::Error> {
-    let stun1 = (shared::WIREPLUG_ORG_STUN1, shared::WIREPLUG_STUN_PORT)
-        .to_socket_addrs()?
-        .next()
-        .expect("could not resolve STUN address");
-    let stun2 = (shared::WIREPLUG_ORG_STUN2, shared::WIREPLUG_STUN_PORT)
-        .to_socket_addrs()?
-        .next()
-        .expect("could not resolve STUN address");
-    let nat = match (
-        send_stun_request(stun1, local_port)?.result,
-        send_stun_request(stun2, local_port)?.result,
-    ) {
-        (protocol::WireplugStunResult::SamePort, protocol::WireplugStunResult::SamePort) => {
-            NatKind::Easy
+// Sends a Binding request over `socket` until it is answered. With a
+// CHANGE-REQUEST the answer comes from another address or port.
+fn binding(
+    socket: &UdpSocket,
+    dst: SocketAddr,
+    change: stun::ChangeRequest,
+) -> std::io::Result<stun::BindingResponse> {
+    let transaction_id: stun::TransactionId = rand::rng().random();
+    let request = stun::encode_change_request(&transaction_id, change);
+    let mut buf = [0u8; 1024];
+    for _ in 0..ATTEMPTS {
+        socket.send_to(&request, dst)?;
+        loop {
+            let (n, from) = match socket.recv_from(&mut buf) {
+                Ok(res) => res,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            if (from == dst || change != NO_CHANGE)
+                && let Some(response) = stun::parse_binding_response(&buf[..n], &transaction_id)
+            {
+                return Ok(response);
+            }
         }
-        (
-            protocol::WireplugStunResult::DifferentPort(port1),
-            protocol::WireplugStunResult::DifferentPort(port2),
-        ) => {
-            if port1 == port2 {
-                let observed_port = port1;
-                NatKind::FixedPortMapping(PortMappingNat::new(local_port, observed_port))
-            } else {
-                NatKind::Hard
+    }
+    Err(std::io::Error::new(
+        ErrorKind::TimedOut,
+        format!("no STUN response from {dst}"),
+    ))
+}
+
+// RFC 5389 Binding request, returns our address as seen by `dst`
+pub(crate) fn send_binding_request(
+    dst: SocketAddr,
+    local_port: u16,
+    timeout: Duration,
+) -> std::io::Result<SocketAddr> {
+    let socket = bind_for(dst, local_port)?;
+    socket.set_read_timeout(Some(timeout))?;
+    binding(&socket, dst, NO_CHANGE).map(|r| r.mapped)
+}
+
+// Standard STUN first, then the wireplug format for older wpcod servers
+fn get_observed_port(dst: SocketAddr, local_port: u16) -> std::io::Result<u16> {
+    match send_binding_request(dst, local_port, STUN_TIMEOUT) {
+        Ok(observed) => {
+            log::debug!("{dst} observed {observed}");
+            return Ok(observed.port());
+        }
+        Err(e) => log::debug!("STUN Binding request to {dst} failed: {e}"),
+    }
+    let response = send_stun_request(dst, local_port, STUN_TIMEOUT)?;
+    log::debug!("{dst} observed {}", response.observed_addr);
+    match response.result {
+        protocol::WireplugStunResult::SamePort => Ok(local_port),
+        protocol::WireplugStunResult::DifferentPort(port) => Ok(port),
+    }
+}
+
+// TimedOut tells the caller that UDP doesn't seem to get through at all
+fn no_stun_response(all_timed_out: bool) -> std::io::Error {
+    let kind = match all_timed_out {
+        true => ErrorKind::TimedOut,
+        false => ErrorKind::Other,
+    };
+    std::io::Error::new(kind, "no STUN server responded")
+}
+
+fn resolve_ipv4(host: &str, port: u16) -> std::io::Result<Option<SocketAddr>> {
+    Ok((host, port).to_socket_addrs()?.find(|a| a.is_ipv4()))
+}
+
+// 100.64.0.0/10, RFC 6598
+fn is_shared_address(ip: Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    octets[0] == 100 && octets[1] & 0xC0 == 64
+}
+
+// The address this host reaches `dst` from
+fn local_address(dst: SocketAddr) -> Option<IpAddr> {
+    let socket = bind_for(dst, 0).ok()?;
+    socket.connect(dst).ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
+
+// STUN servers only ever see the carrier's public address. Its NAT shows on
+// this side, as the address this host goes out from or as the gateway.
+fn behind_cgnat(local: Option<IpAddr>, gateway: Option<Ipv4Addr>) -> bool {
+    matches!(local, Some(IpAddr::V4(ip)) if is_shared_address(ip))
+        || gateway.is_some_and(is_shared_address)
+}
+
+// RFC 5780 4.3. Servers without OTHER-ADDRESS only tell whether the mapping
+// depends on the destination, which is then assumed to include the port.
+fn mapping_behaviour(
+    socket: &UdpSocket,
+    primary: SocketAddr,
+    first: &stun::BindingResponse,
+    servers: &[SocketAddr],
+) -> Option<NatBehaviour> {
+    let Some(other) = first.other else {
+        let second = servers
+            .iter()
+            .filter(|s| s.ip() != primary.ip())
+            .find_map(|s| binding(socket, *s, NO_CHANGE).ok())?;
+        return Some(match second.mapped == first.mapped {
+            true => NatBehaviour::EndpointIndependent,
+            false => NatBehaviour::AddressAndPortDependent,
+        });
+    };
+    // the alternate address, primary port
+    let second = binding(
+        socket,
+        SocketAddr::new(other.ip(), primary.port()),
+        NO_CHANGE,
+    )
+    .inspect_err(|e| log::debug!("mapping test II: {e}"))
+    .ok()?;
+    if second.mapped == first.mapped {
+        return Some(NatBehaviour::EndpointIndependent);
+    }
+    // the alternate address and port
+    let third = binding(socket, other, NO_CHANGE)
+        .inspect_err(|e| log::debug!("mapping test III: {e}"))
+        .ok()?;
+    Some(match third.mapped == second.mapped {
+        true => NatBehaviour::AddressDependent,
+        false => NatBehaviour::AddressAndPortDependent,
+    })
+}
+
+// RFC 5780 4.4, from a fresh port that has only talked to `primary`
+fn filtering_behaviour(primary: SocketAddr) -> std::io::Result<NatBehaviour> {
+    let socket = bind_for(primary, 0)?;
+    socket.set_read_timeout(Some(STUN_TIMEOUT))?;
+    binding(&socket, primary, NO_CHANGE)?;
+    let answered = |change| match binding(&socket, primary, change) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::TimedOut => Ok(false),
+        Err(e) => Err(e),
+    };
+    let change_both = stun::ChangeRequest {
+        ip: true,
+        port: true,
+    };
+    if answered(change_both)? {
+        return Ok(NatBehaviour::EndpointIndependent);
+    }
+    let change_port = stun::ChangeRequest {
+        ip: false,
+        port: true,
+    };
+    if answered(change_port)? {
+        return Ok(NatBehaviour::AddressDependent);
+    }
+    Ok(NatBehaviour::AddressAndPortDependent)
+}
+
+// RFC 5780 4.5: a request sent to our own mapped address comes back to us
+// if the NAT hairpins
+fn hairpinning(socket: &UdpSocket, mapped: SocketAddr) -> bool {
+    let transaction_id: stun::TransactionId = rand::rng().random();
+    let request = stun::encode_binding_request(&transaction_id);
+    let mut buf = [0u8; 1024];
+    for _ in 0..ATTEMPTS {
+        if socket.send_to(&request, mapped).is_err() {
+            return false;
+        }
+        while let Ok(n) = socket.recv(&mut buf) {
+            if stun::parse_binding_request(&buf[..n]) == Some(transaction_id) {
+                return true;
             }
         }
-        (
-            protocol::WireplugStunResult::SamePort,
-            protocol::WireplugStunResult::DifferentPort(_),
-        )
-        | (
-            protocol::WireplugStunResult::DifferentPort(_),
-            protocol::WireplugStunResult::SamePort,
-        ) => {
-            return Err(std::io::Error::other("NAT inconsistent result"));
+    }
+    false
+}
+
+// The distance between consecutive mappings, if the NAT hands out ports one
+// after another. Other traffic through the NAT may take a port in between.
+fn allocation_step(ports: &[u16]) -> Option<u16> {
+    let deltas = ports
+        .windows(2)
+        .map(|w| w[1].wrapping_sub(w[0]))
+        .collect::<Vec<_>>();
+    let step = *deltas.iter().min()?;
+    if step == 0
+        || deltas
+            .iter()
+            .any(|d| *d > MAX_ALLOCATION_STEP || d % step != 0)
+    {
+        return None;
+    }
+    let regular = deltas.iter().filter(|d| **d == step).count();
+    (regular * 2 > deltas.len()).then_some(step)
+}
+
+// Opens a few new mappings towards `primary`, each from a fresh port
+fn measure_allocation_step(primary: SocketAddr) -> std::io::Result<Option<u16>> {
+    let mut sockets = vec![];
+    let mut ports = vec![];
+    for _ in 0..ALLOCATION_PROBES {
+        let socket = bind_for(primary, 0)?;
+        socket.set_read_timeout(Some(STUN_TIMEOUT))?;
+        ports.push(binding(&socket, primary, NO_CHANGE)?.mapped.port());
+        // keeps the OS from handing out the same local port again
+        sockets.push(socket);
+    }
+    log::debug!("{primary} observed ports {ports:?} for new mappings");
+    Ok(allocation_step(&ports))
+}
+
+// Where the next mappings of a sequential NAT will likely be, right after
+// the one a fresh port gets now
+pub(crate) fn predict_ports(
+    step: u16,
+    stun_servers: &[(String, u16)],
+) -> std::io::Result<PortRange> {
+    for (host, port) in stun_servers {
+        let Some(stun) = resolve_ipv4(host, *port)? else {
+            continue;
+        };
+        let socket = bind_for(stun, 0)?;
+        socket.set_read_timeout(Some(STUN_TIMEOUT))?;
+        let observed = match binding(&socket, stun, NO_CHANGE) {
+            Ok(response) => response.mapped.port(),
+            Err(e) => {
+                log::debug!("STUN Binding request to {stun} failed: {e}");
+                continue;
+            }
+        };
+        return Ok(PortRange {
+            first: observed.saturating_add(step),
+            step,
+            count: PREDICTED_PORTS,
+        });
+    }
+    Err(std::io::Error::other("no STUN server responded"))
+}
+
+// Runs the RFC 5780 tests from `local_port` against the first STUN server
+// that answers. Fails with TimedOut only if every configured server was
+// resolved and none of them answered any of their attempts.
+pub(crate) fn classify(
+    local_port: u16,
+    stun_servers: &[(String, u16)],
+    gateway: Option<Ipv4Addr>,
+) -> std::io::Result<(NatClassification, SocketAddr)> {
+    let mut servers = vec![];
+    for (host, port) in stun_servers {
+        // NAT is an IPv4 affair
+        match resolve_ipv4(host, *port) {
+            Ok(Some(stun)) => servers.push(stun),
+            Ok(None) => log::warn!("could not resolve STUN server {host}"),
+            Err(e) => log::warn!("could not resolve STUN server {host}: {e}"),
         }
+    }
+    let socket = UdpSocket::bind(format!("0.0.0.0:{local_port}"))?;
+    socket.set_read_timeout(Some(STUN_TIMEOUT))?;
+    let mut all_timed_out = !servers.is_empty() && servers.len() == stun_servers.len();
+    let (primary, first) = servers
+        .iter()
+        .find_map(|stun| match binding(&socket, *stun, NO_CHANGE) {
+            Ok(response) => Some((*stun, response)),
+            Err(e) => {
+                log::debug!("STUN Binding request to {stun} failed: {e}");
+                all_timed_out &= e.kind() == ErrorKind::TimedOut;
+                None
+            }
+        })
+        .ok_or_else(|| no_stun_response(all_timed_out))?;
+    log::debug!("{primary} observed {}", first.mapped);
+    let mapping = mapping_behaviour(&socket, primary, &first, &servers);
+    let hairpinning = hairpinning(&socket, first.mapped);
+    let filtering = match first.other {
+        Some(_) => filtering_behaviour(primary)
+            .inspect_err(|e| log::debug!("filtering tests failed: {e}"))
+            .ok(),
+        None => None,
     };
-    Ok(nat)
+    let allocation_step = match mapping {
+        Some(NatBehaviour::AddressDependent | NatBehaviour::AddressAndPortDependent) => {
+            measure_allocation_step(primary)
+                .inspect_err(|e| log::debug!("allocation tests failed: {e}"))
+                .ok()
+                .flatten()
+        }
+        _ => None,
+    };
+    let cgnat = behind_cgnat(local_address(primary), gateway);
+    let classification = NatClassification {
+        mapping,
+        filtering,
+        hairpinning,
+        port_preservation: first.mapped.port() == local_port,
+        cgnat,
+        allocation_step,
+    };
+    Ok((classification, first.mapped))
+}
+
+// Rebuilds the NatKind of a known network. Only the external port of a fixed
+// mapping is asked for again, and a port mapping is requested again. Blocked
+// UDP may have been a passing outage, so that is detected again. Comes with
+// the new classification if the NAT was detected again.
+pub(crate) fn reuse_profile(
+    profile: NatProfile,
+    local_port: u16,
+    stun_servers: &[(String, u16)],
+    gateway: Option<Ipv4Addr>,
+) -> Result<(NatKind, Option<NatClassification>), std::io::Error> {
+    let detect_again = || {
+        detect_kind(local_port, stun_servers, gateway)
+            .map(|(kind, classification)| (kind, Some(classification)))
+    };
+    match profile {
+        NatProfile::Easy => Ok((NatKind::Easy, None)),
+        NatProfile::Sequential(step) => Ok((NatKind::Sequential(step), None)),
+        NatProfile::Hard => Ok((NatKind::Hard, None)),
+        NatProfile::UdpBlocked => detect_again(),
+        NatProfile::FixedPortMapping => {
+            let mut all_timed_out = !stun_servers.is_empty();
+            for (host, port) in stun_servers {
+                let Ok(Some(stun)) = resolve_ipv4(host, *port) else {
+                    log::warn!("could not resolve STUN server {host}");
+                    all_timed_out = false;
+                    continue;
+                };
+                let observed_port = match get_observed_port(stun, local_port) {
+                    Ok(observed_port) => observed_port,
+                    Err(e) => {
+                        log::debug!("{stun} did not tell the observed port: {e}");
+                        all_timed_out &=
+                            matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut);
+                        continue;
+                    }
+                };
+                let kind = match observed_port == local_port {
+                    true => NatKind::Easy,
+                    false => {
+                        NatKind::FixedPortMapping(PortMappingNat::new(local_port, observed_port))
+                    }
+                };
+                return Ok((kind, None));
+            }
+            Err(no_stun_response(all_timed_out))
+        }
+        NatProfile::Mapped => match map_port(local_port) {
+            Some(mapping) => Ok((NatKind::Mapped(mapping), None)),
+            None => detect_again(),
+        },
+    }
+}
+
+pub fn detect_kind(
+    local_port: u16,
+    stun_servers: &[(String, u16)],
+    gateway: Option<Ipv4Addr>,
+) -> Result<(NatKind, NatClassification), std::io::Error> {
+    let (classification, mapped) = classify(local_port, stun_servers, gateway)?;
+    let nat = match classification.mapping {
+        Some(NatBehaviour::AddressDependent | NatBehaviour::AddressAndPortDependent) => {
+            match (map_port(local_port), classification.allocation_step) {
+                (Some(mapping), _) => NatKind::Mapped(mapping),
+                (None, Some(step)) => NatKind::Sequential(step),
+                (None, None) => NatKind::Hard,
+            }
+        }
+        // a single answer gives the benefit of the doubt
+        Some(NatBehaviour::EndpointIndependent) | None => match classification.port_preservation {
+            true => NatKind::Easy,
+            false => NatKind::FixedPortMapping(PortMappingNat::new(local_port, mapped.port())),
+        },
+    };
+    Ok((nat, classification))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        STUN_TIMEOUT, allocation_step, behind_cgnat, classify, is_shared_address,
+        send_binding_request,
+    };
+    use shared::stun;
+    use std::{io::ErrorKind, net::UdpSocket, thread};
+
+    #[test]
+    fn binding_request_returns_observed_address() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            let (n, from) = server.recv_from(&mut buf).unwrap();
+            let transaction_id = stun::parse_binding_request(&buf[..n]).unwrap();
+            // a stray response must be ignored
+            server
+                .send_to(&stun::encode_binding_response(&[0u8; 12], from, None), from)
+                .unwrap();
+            server
+                .send_to(
+                    &stun::encode_binding_response(&transaction_id, from, None),
+                    from,
+                )
+                .unwrap();
+        });
+
+        let local_port = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let observed = send_binding_request(server_addr, local_port, STUN_TIMEOUT).unwrap();
+        assert_eq!(observed.port(), local_port);
+        assert!(observed.ip().is_loopback());
+    }
+
+    #[test]
+    fn udp_is_blocked_only_when_every_server_times_out() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = silent.local_addr().unwrap().port();
+        let dead = [("127.0.0.1".to_owned(), port)];
+        let e = classify(0, &dead, None).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+        // a server that wasn't asked might have answered
+        let unresolved = [("::1".to_owned(), port), ("127.0.0.1".to_owned(), port)];
+        let e = classify(0, &unresolved, None).unwrap_err();
+        assert_ne!(e.kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn detects_carrier_grade_nat_range() {
+        assert!(is_shared_address("100.64.0.1".parse().unwrap()));
+        assert!(is_shared_address("100.127.255.254".parse().unwrap()));
+        assert!(!is_shared_address("100.128.0.1".parse().unwrap()));
+        assert!(!is_shared_address("192.168.1.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn detects_carrier_grade_nat_from_the_local_side() {
+        let shared = Some("100.72.1.2".parse().unwrap());
+        let private = Some("192.168.1.2".parse().unwrap());
+        assert!(behind_cgnat(shared, None));
+        assert!(behind_cgnat(private, Some("100.64.0.1".parse().unwrap())));
+        assert!(!behind_cgnat(private, Some("192.168.1.1".parse().unwrap())));
+        assert!(!behind_cgnat(None, None));
+    }
+
+    #[test]
+    fn measures_sequential_allocation() {
+        assert_eq!(allocation_step(&[40000, 40001, 40002, 40003]), Some(1));
+        assert_eq!(allocation_step(&[40000, 40002, 40006, 40008]), Some(2));
+        assert_eq!(allocation_step(&[40000, 51234, 2211, 61000]), None);
+        assert_eq!(allocation_step(&[40000, 40000, 40000]), None);
+        assert_eq!(allocation_step(&[40000, 40003, 40006, 40007]), None);
+        assert_eq!(allocation_step(&[40000]), None);
+    }
 }
@@ -0,0 +1,402 @@
+use std::{
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+
+use crate::utils;
+
+const SERVER_PORT: u16 = 5351;
+const NATPMP_VERSION: u8 = 0;
+const PCP_VERSION: u8 = 2;
+const REQUESTED_LIFETIME: u32 = 2 * 60 * 60;
+// RFC 6886 starts at 250ms and doubles, but we give up much sooner
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+const MAX_ATTEMPTS: usize = 4;
+
+const RESPONSE_BIT: u8 = 0x80;
+const NATPMP_OP_EXTERNAL_ADDRESS: u8 = 0;
+const NATPMP_OP_MAP_UDP: u8 = 1;
+const PCP_OP_MAP: u8 = 1;
+const PCP_RESULT_UNSUPP_VERSION: u8 = 1;
+const IPPROTO_UDP: u8 = 17;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Protocol {
+    Pcp,
+    NatPmp,
+}
+
+fn unsupported() -> std::io::Error {
+    std::io::Error::new(ErrorKind::Unsupported, "gateway does not support PCP")
+}
+
+fn bad_response() -> std::io::Error {
+    std::io::Error::other("bad response from gateway")
+}
+
+fn local_ip(gateway: SocketAddr) -> std::io::Result<Ipv4Addr> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(gateway)?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(ip) => Ok(ip),
+        IpAddr::V6(_) => Err(std::io::Error::other("gateway is not reachable over IPv4")),
+    }
+}
+
+// Sends a request to the gateway, retransmitting until it answers
+fn exchange(gateway: SocketAddr, request: &[u8], response: &mut [u8]) -> std::io::Result<usize> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(gateway)?;
+    let mut timeout = INITIAL_TIMEOUT;
+    for _ in 0..MAX_ATTEMPTS {
+        socket.send(request)?;
+        socket.set_read_timeout(Some(timeout))?;
+        match socket.recv(response) {
+            Ok(n) => return Ok(n),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                timeout *= 2;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Err(std::io::Error::new(
+        ErrorKind::TimedOut,
+        format!("no response from {gateway}"),
+    ))
+}
+
+fn pcp_map(
+    gateway: SocketAddr,
+    nonce: &[u8; 12],
+    internal_port: u16,
+    external_port: u16,
+    lifetime: u32,
+) -> std::io::Result<(SocketAddrV4, u32)> {
+    let mut request = Vec::with_capacity(60);
+    request.extend([PCP_VERSION, PCP_OP_MAP, 0, 0]);
+    request.extend(lifetime.to_be_bytes());
+    request.extend(local_ip(gateway)?.to_ipv6_mapped().octets());
+    request.extend(nonce);
+    request.extend([IPPROTO_UDP, 0, 0, 0]);
+    request.extend(internal_port.to_be_bytes());
+    request.extend(external_port.to_be_bytes());
+    // an IPv4-mapped unspecified address asks for an IPv4 mapping
+    request.extend(Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+
+    let mut buf = [0u8; 1100];
+    let n = exchange(gateway, &request, &mut buf)?;
+    let r = &buf[..n];
+    // a NAT-PMP only gateway answers with its own version
+    if r.first() == Some(&NATPMP_VERSION) {
+        return Err(unsupported());
+    }
+    if r.len() < 24 || r[0] != PCP_VERSION || r[1] != PCP_OP_MAP | RESPONSE_BIT {
+        return Err(bad_response());
+    }
+    match r[3] {
+        0 => (),
+        PCP_RESULT_UNSUPP_VERSION => return Err(unsupported()),
+        code => return Err(std::io::Error::other(format!("PCP result code {code}"))),
+    }
+    if r.len() < 60 || r[24..36] != nonce[..] {
+        return Err(bad_response());
+    }
+    let lifetime = u32::from_be_bytes([r[4], r[5], r[6], r[7]]);
+    let port = u16::from_be_bytes([r[42], r[43]]);
+    let mut ip = [0u8; 16];
+    ip.copy_from_slice(&r[44..60]);
+    let ip = Ipv6Addr::from(ip)
+        .to_ipv4_mapped()
+        .ok_or(std::io::Error::other("PCP mapped a non-IPv4 address"))?;
+    Ok((SocketAddrV4::new(ip, port), lifetime))
+}
+
+fn natpmp_check(r: &[u8], opcode: u8, len: usize) -> std::io::Result<()> {
+    if r.len() < 4 || r[0] != NATPMP_VERSION || r[1] != opcode | RESPONSE_BIT {
+        return Err(bad_response());
+    }
+    match u16::from_be_bytes([r[2], r[3]]) {
+        0 if r.len() >= len => Ok(()),
+        0 => Err(bad_response()),
+        code => Err(std::io::Error::other(format!("NAT-PMP result code {code}"))),
+    }
+}
+
+fn natpmp_external_ip(gateway: SocketAddr) -> std::io::Result<Ipv4Addr> {
+    let mut buf = [0u8; 16];
+    let n = exchange(
+        gateway,
+        &[NATPMP_VERSION, NATPMP_OP_EXTERNAL_ADDRESS],
+        &mut buf,
+    )?;
+    natpmp_check(&buf[..n], NATPMP_OP_EXTERNAL_ADDRESS, 12)?;
+    Ok(Ipv4Addr::new(buf[8], buf[9], buf[10], buf[11]))
+}
+
+fn natpmp_map(
+    gateway: SocketAddr,
+    internal_port: u16,
+    external_port: u16,
+    lifetime: u32,
+) -> std::io::Result<(u16, u32)> {
+    let mut request = Vec::with_capacity(12);
+    request.extend([NATPMP_VERSION, NATPMP_OP_MAP_UDP, 0, 0]);
+    request.extend(internal_port.to_be_bytes());
+    request.extend(external_port.to_be_bytes());
+    request.extend(lifetime.to_be_bytes());
+
+    let mut buf = [0u8; 16];
+    let n = exchange(gateway, &request, &mut buf)?;
+    natpmp_check(&buf[..n], NATPMP_OP_MAP_UDP, 16)?;
+    let port = u16::from_be_bytes([buf[10], buf[11]]);
+    let lifetime = u32::from_be_bytes([buf[12], buf[13], buf[14], buf[15]]);
+    Ok((port, lifetime))
+}
+
+fn request_mapping(
+    gateway: SocketAddr,
+    protocol: Protocol,
+    nonce: &[u8; 12],
+    internal_port: u16,
+    external_port: u16,
+) -> std::io::Result<(SocketAddrV4, u32)> {
+    let (external, lifetime) = match protocol {
+        Protocol::Pcp => pcp_map(
+            gateway,
+            nonce,
+            internal_port,
+            external_port,
+            REQUESTED_LIFETIME,
+        )?,
+        Protocol::NatPmp => {
+            let (port, lifetime) =
+                natpmp_map(gateway, internal_port, external_port, REQUESTED_LIFETIME)?;
+            (
+                SocketAddrV4::new(natpmp_external_ip(gateway)?, port),
+                lifetime,
+            )
+        }
+    };
+    if lifetime == 0 {
+        return Err(std::io::Error::other("gateway granted no lifetime"));
+    }
+    Ok((external, lifetime))
+}
+
+// renew halfway through the lifetime
+fn renewal_time(lifetime: u32) -> Instant {
+    Instant::now() + Duration::from_secs(lifetime as u64 / 2)
+}
+
+// A UDP port mapping made with PCP, or NAT-PMP if the gateway only speaks that.
+// It is refreshed while held and deleted on drop.
+#[derive(Debug)]
+pub(crate) struct PortMapping {
+    gateway: SocketAddr,
+    protocol: Protocol,
+    nonce: [u8; 12],
+    internal_port: u16,
+    external: SocketAddrV4,
+    renew_at: Instant,
+}
+
+impl PortMapping {
+    pub(crate) fn create(internal_port: u16) -> std::io::Result<Self> {
+        let gateway = utils::get_default_gateway()?;
+        Self::create_on(
+            SocketAddr::new(IpAddr::V4(gateway), SERVER_PORT),
+            internal_port,
+        )
+    }
+
+    fn create_on(gateway: SocketAddr, internal_port: u16) -> std::io::Result<Self> {
+        let nonce = rand::rng().random();
+        let mut protocol = Protocol::Pcp;
+        let (external, lifetime) =
+            match request_mapping(gateway, protocol, &nonce, internal_port, internal_port) {
+                Err(e) if e.kind() == ErrorKind::Unsupported => {
+                    log::debug!("{gateway} does not support PCP, trying NAT-PMP");
+                    protocol = Protocol::NatPmp;
+                    request_mapping(gateway, protocol, &nonce, internal_port, internal_port)?
+                }
+                res => res?,
+            };
+        let mapping = Self {
+            gateway,
+            protocol,
+            nonce,
+            internal_port,
+            external,
+            renew_at: renewal_time(lifetime),
+        };
+        log::debug!(
+            "{}: {external} mapped to local port {internal_port}",
+            mapping.protocol_name()
+        );
+        Ok(mapping)
+    }
+
+    fn delete(&self) -> std::io::Result<()> {
+        match self.protocol {
+            Protocol::Pcp => {
+                pcp_map(
+                    self.gateway,
+                    &self.nonce,
+                    self.internal_port,
+                    self.external.port(),
+                    0,
+                )?;
+            }
+            Protocol::NatPmp => {
+                natpmp_map(self.gateway, self.internal_port, 0, 0)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn protocol_name(&self) -> &'static str {
+        match self.protocol {
+            Protocol::Pcp => "PCP",
+            Protocol::NatPmp => "NAT-PMP",
+        }
+    }
+
+    pub(crate) fn external_port(&self) -> u16 {
+        self.external.port()
+    }
+
+    pub(crate) fn renew_if_needed(&mut self) -> std::io::Result<()> {
+        if Instant::now() < self.renew_at {
+            return Ok(());
+        }
+        log::debug!(
+            "{}: renewing mapping for {}",
+            self.protocol_name(),
+            self.external
+        );
+        let (external, lifetime) = request_mapping(
+            self.gateway,
+            self.protocol,
+            &self.nonce,
+            self.internal_port,
+            self.external.port(),
+        )?;
+        if external != self.external {
+            log::warn!(
+                "{}: mapping moved from {} to {external}",
+                self.protocol_name(),
+                self.external
+            );
+        }
+        self.external = external;
+        self.renew_at = renewal_time(lifetime);
+        Ok(())
+    }
+}
+
+impl Drop for PortMapping {
+    fn drop(&mut self) {
+        match self.delete() {
+            Ok(()) => log::debug!(
+                "{}: removed mapping for {}",
+                self.protocol_name(),
+                self.external
+            ),
+            Err(e) => log::warn!(
+                "{}: failed to remove port mapping: {e}",
+                self.protocol_name()
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PortMapping;
+    use std::{
+        net::{SocketAddr, UdpSocket},
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    type Requests = Arc<Mutex<Vec<(u8, u8, u32)>>>;
+
+    // Answers PCP MAP requests, or NAT-PMP requests only when `speaks_pcp` is false.
+    // Records (version, opcode, lifetime) for every request.
+    fn start_fake_gateway(speaks_pcp: bool) -> (SocketAddr, Requests) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let recorded = Arc::clone(&requests);
+        thread::spawn(move || {
+            let mut buf = [0u8; 1100];
+            let epoch = 1234u32.to_be_bytes();
+            while let Ok((n, from)) = socket.recv_from(&mut buf) {
+                let req = &buf[..n];
+                let lifetime = match (req[0], req[1]) {
+                    (2, _) => u32::from_be_bytes([req[4], req[5], req[6], req[7]]),
+                    (0, 1) => u32::from_be_bytes([req[8], req[9], req[10], req[11]]),
+                    _ => 0,
+                };
+                recorded.lock().unwrap().push((req[0], req[1], lifetime));
+                let mut res = vec![];
+                match (req[0], req[1], speaks_pcp) {
+                    (2, 1, true) => {
+                        res.extend([2, 0x81, 0, 0]);
+                        res.extend(lifetime.to_be_bytes());
+                        res.extend(epoch);
+                        res.extend([0u8; 12]);
+                        res.extend(&req[24..42]);
+                        res.extend(40000u16.to_be_bytes());
+                        res.extend([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 198, 51, 100, 7]);
+                    }
+                    (0, 0, false) => {
+                        res.extend([0, 0x80, 0, 0]);
+                        res.extend(epoch);
+                        res.extend([198, 51, 100, 8]);
+                    }
+                    (0, 1, false) => {
+                        res.extend([0, 0x81, 0, 0]);
+                        res.extend(epoch);
+                        res.extend(&req[4..6]);
+                        res.extend(40001u16.to_be_bytes());
+                        res.extend(lifetime.to_be_bytes());
+                    }
+                    // unsupported version
+                    (_, op, _) => {
+                        res.extend([0, 0x80 | op, 0, 1]);
+                        res.extend(epoch);
+                    }
+                }
+                socket.send_to(&res, from).unwrap();
+            }
+        });
+        (addr, requests)
+    }
+
+    #[test]
+    fn maps_with_pcp() {
+        let (gateway, requests) = start_fake_gateway(true);
+        let mapping = PortMapping::create_on(gateway, 51820).unwrap();
+        assert_eq!(mapping.protocol_name(), "PCP");
+        assert_eq!(mapping.external.to_string(), "198.51.100.7:40000");
+        drop(mapping);
+        assert_eq!(*requests.lock().unwrap(), vec![(2, 1, 7200), (2, 1, 0)]);
+    }
+
+    #[test]
+    fn falls_back_to_natpmp() {
+        let (gateway, requests) = start_fake_gateway(false);
+        let mapping = PortMapping::create_on(gateway, 51820).unwrap();
+        assert_eq!(mapping.protocol_name(), "NAT-PMP");
+        assert_eq!(mapping.external.to_string(), "198.51.100.8:40001");
+        drop(mapping);
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![(2, 1, 7200), (0, 1, 7200), (0, 0, 0), (0, 1, 0)]
+        );
+    }
+}
@@ -1,5 +1,7 @@
 use ipnet::IpNet;
-use netlink_packet_core::{NLM_F_ACK, NLM_F_CREATE, NLM_F_REPLACE, NLM_F_REQUEST};
+use netlink_packet_core::{
+    NLM_F_ACK, NLM_F_CREATE, NLM_F_REPLACE, NLM_F_REQUEST, NetlinkMessage, NetlinkPayload,
+};
 use netlink_packet_route::{
     AddressFamily, RouteNetlinkMessage,
     address::{self, AddressHeader, AddressMessage},
@@ -7,7 +9,13 @@ use netlink_packet_route::{
     route::{self, RouteHeader, RouteMessage},
 };
 use netlink_request::netlink_request_rtnl;
-use std::{io, net::IpAddr};
+use netlink_sys::{Socket, SocketAddr, protocols::NETLINK_ROUTE};
+use std::{
+    io,
+    net::IpAddr,
+    os::fd::AsRawFd,
+    time::{Duration, Instant},
+};
 use wireguard_control::InterfaceName;
 
 fn if_nametoindex(interface: &InterfaceName) -> Result<u32, io::Error> {
@@ -74,7 +82,7 @@ pub fn set_addr(interface: &InterfaceName, addr: IpNet) -> Result<(), io::Error>
     Ok(())
 }
 
-pub fn add_route(interface: &InterfaceName, cidr: IpNet) -> Result<bool, io::Error> {
+fn route_message(interface: &InterfaceName, cidr: IpNet) -> Result<RouteMessage, io::Error> {
     let if_index = if_nametoindex(interface)?;
     let (address_family, dst) = match cidr {
         IpNet::V4(network) => (
@@ -98,7 +106,11 @@ pub fn add_route(interface: &InterfaceName, cidr: IpNet) -> Result<bool, io::Err
     let mut message = RouteMessage::default();
     message.header = header;
     message.attributes = vec![dst, route::RouteAttribute::Oif(if_index)];
+    Ok(message)
+}
 
+pub fn add_route(interface: &InterfaceName, cidr: IpNet) -> Result<bool, io::Error> {
+    let message = route_message(interface, cidr)?;
     match netlink_request_rtnl(RouteNetlinkMessage::NewRoute(message), None) {
         Ok(_) => {
             log::debug!("added route {} to interface {}", cidr, interface);
@@ -111,3 +123,124 @@ pub fn add_route(interface: &InterfaceName, cidr: IpNet) -> Result<bool, io::Err
         Err(e) => Err(e),
     }
 }
+
+pub fn del_route(interface: &InterfaceName, cidr: IpNet) -> Result<(), io::Error> {
+    let message = route_message(interface, cidr)?;
+    match netlink_request_rtnl(RouteNetlinkMessage::DelRoute(message), None) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            log::debug!("route {} did not exist.", cidr);
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Address and route changes reported by the kernel, excluding those on the
+// WireGuard interface itself.
+pub struct NetworkEvents {
+    socket: Socket,
+    wg_index: u32,
+}
+
+impl NetworkEvents {
+    // events arrive in bursts (e.g. DHCP renewals), wait for them to settle
+    const SETTLE_TIME: Duration = Duration::from_secs(1);
+
+    pub fn subscribe(interface: &InterfaceName) -> Result<Self, io::Error> {
+        let wg_index = if_nametoindex(interface)?;
+        let mut socket = Socket::new(NETLINK_ROUTE)?;
+        let groups = libc::RTMGRP_IPV4_IFADDR
+            | libc::RTMGRP_IPV6_IFADDR
+            | libc::RTMGRP_IPV4_ROUTE
+            | libc::RTMGRP_IPV6_ROUTE;
+        socket.bind(&SocketAddr::new(0, groups as u32))?;
+        socket.set_non_blocking(true)?;
+        Ok(Self { socket, wg_index })
+    }
+
+    fn poll(&self, timeout: Duration) -> Result<bool, io::Error> {
+        let mut fds = libc::pollfd {
+            fd: self.socket.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX);
+        match unsafe { libc::poll(&mut fds, 1, timeout) } {
+            -1 => {
+                let e = io::Error::last_os_error();
+                match e.kind() {
+                    io::ErrorKind::Interrupted => Ok(false),
+                    _ => Err(e),
+                }
+            }
+            0 => Ok(false),
+            _ => Ok(true),
+        }
+    }
+
+    fn is_relevant(&self, message: &RouteNetlinkMessage) -> bool {
+        match message {
+            RouteNetlinkMessage::NewAddress(m) | RouteNetlinkMessage::DelAddress(m) => {
+                m.header.index != self.wg_index
+            }
+            RouteNetlinkMessage::NewRoute(m) | RouteNetlinkMessage::DelRoute(m) => {
+                m.header.destination_prefix_length == 0
+                    && !m
+                        .attributes
+                        .contains(&route::RouteAttribute::Oif(self.wg_index))
+            }
+            _ => false,
+        }
+    }
+
+    fn drain(&self) -> Result<bool, io::Error> {
+        let mut relevant = false;
+        loop {
+            let mut buf = Vec::with_capacity(8192);
+            match self.socket.recv(&mut buf, 0) {
+                Ok(_) => (),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(relevant),
+                Err(e) => return Err(e),
+            }
+            let mut offset = 0;
+            while offset < buf.len() {
+                let message =
+                    match NetlinkMessage::<RouteNetlinkMessage>::deserialize(&buf[offset..]) {
+                        Ok(m) => m,
+                        Err(e) => {
+                            log::trace!("netlink: could not parse message: {e}");
+                            break;
+                        }
+                    };
+                let length = message.header.length as usize;
+                if let NetlinkPayload::InnerMessage(m) = &message.payload
+                    && self.is_relevant(m)
+                {
+                    log::trace!("netlink: {m:?}");
+                    relevant = true;
+                }
+                if length == 0 {
+                    break;
+                }
+                offset += length;
+            }
+        }
+    }
+
+    // Blocks for up to `timeout`, returns whether the network has changed.
+    pub fn wait(&self, timeout: Duration) -> Result<bool, io::Error> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() || !self.poll(remaining)? {
+                return Ok(false);
+            }
+            if self.drain()? {
+                std::thread::sleep(Self::SETTLE_TIME);
+                self.drain()?;
+                return Ok(true);
+            }
+        }
+    }
+}
@@ -1,22 +1,27 @@
 use std::{
     fmt::Display,
     net::{Ipv4Addr, Ipv6Addr},
+    time::{Duration, Instant},
 };
 
 use ipnet::IpNet;
+use shared::{control::NetworkStatus, protocol::NatClassification};
 
-use crate::utils;
+use crate::{config::Servers, publicip, utils};
 
 #[derive(Debug, Clone)]
 pub(crate) struct NetInfo {
-    wan_ipv4: Option<Ipv4Addr>,
+    pub(crate) wan_ipv4: Option<Ipv4Addr>,
     pub(crate) wan_ipv6: Option<Ipv6Addr>,
     pub(crate) lan_addrs: Vec<IpNet>,
     pub(crate) hard_nat: bool,
-    /*
-    default_gateway: String,
-    dns_server: String,
-     */
+    // peers are reached over TCP
+    pub(crate) udp_blocked: bool,
+    pub(crate) default_gateway: Option<Ipv4Addr>,
+    pub(crate) gateway_mac: Option<String>,
+    pub(crate) nat: Option<NatClassification>,
+    // enough public IP providers agreed
+    confirmed: bool,
 }
 
 impl PartialEq for NetInfo {
@@ -24,6 +29,8 @@ impl PartialEq for NetInfo {
         self.wan_ipv4 == other.wan_ipv4
             && self.wan_ipv6 == other.wan_ipv6
             && self.lan_addrs == other.lan_addrs
+            && self.default_gateway == other.default_gateway
+            && self.gateway_mac == other.gateway_mac
     }
 }
 
@@ -44,22 +51,15 @@ impl Display for NetInfo {
         for l in &self.lan_addrs {
             writeln!(f, "\tLAN IP: {:?}", l)?;
         }
+        if let Some(gateway) = self.default_gateway {
+            writeln!(f, "\tGateway: {gateway}")?;
+        }
         Ok(())
     }
 }
 impl NetInfo {
-    fn detect(if_name: &str) -> Self {
-        let (mut wan_ipv4, mut wan_ipv6) = innernet_publicip::get_both();
-        if wan_ipv4.is_none() && wan_ipv6.is_none() {
-            (wan_ipv4, wan_ipv6) = utils::get_ip64_over_https();
-            if wan_ipv4.is_some() || wan_ipv6.is_some() {
-                log::warn!(
-                    "Network: quad9 is unreachable, ip found via HTTPS {:?} / {:?}",
-                    wan_ipv4,
-                    wan_ipv6
-                );
-            }
-        }
+    fn detect(if_name: &str, servers: &Servers) -> Self {
+        let lookup = publicip::lookup(if_name, servers);
         let lan_addrs = match utils::get_lan_addrs(if_name) {
             Ok(v) => v,
             Err(e) => {
@@ -67,12 +67,63 @@ impl NetInfo {
                 vec![]
             }
         };
+        let default_gateway = utils::get_default_gateway()
+            .inspect_err(|e| log::debug!("Network: {e}"))
+            .ok();
+        let gateway_mac = default_gateway.and_then(|gateway| {
+            utils::get_mac_addr(gateway)
+                .inspect_err(|e| log::debug!("Network: {e}"))
+                .ok()
+        });
         NetInfo {
-            wan_ipv4,
-            wan_ipv6,
+            wan_ipv4: lookup.ipv4,
+            wan_ipv6: lookup.ipv6,
             lan_addrs,
             hard_nat: false,
+            udp_blocked: false,
+            default_gateway,
+            gateway_mac,
+            nat: None,
+            confirmed: lookup.confirmed,
+        }
+    }
+    pub(crate) fn to_status(&self) -> NetworkStatus {
+        NetworkStatus {
+            wan_ipv4: self.wan_ipv4,
+            wan_ipv6: self.wan_ipv6,
+            lan_addrs: self.lan_addrs.clone(),
+            default_gateway: self.default_gateway,
+            gateway_mac: self.gateway_mac.clone(),
+            nat: self.nat,
+            needs_relay: self.hard_nat,
+        }
+    }
+    // Identifies the network: gateway, LAN prefixes and public IPs
+    pub(crate) fn fingerprint(&self) -> String {
+        let mut parts = vec![];
+        if let Some(gateway) = self.default_gateway {
+            parts.push(gateway.to_string());
+        }
+        if let Some(mac) = &self.gateway_mac {
+            parts.push(mac.to_owned());
+        }
+        if let Some(ip) = self.wan_ipv4 {
+            parts.push(ip.to_string());
+        }
+        if let Some(ip) = self.wan_ipv6 {
+            // the interface ID may rotate, the prefix does not
+            if let Ok(net) = IpNet::new(ip.into(), 64) {
+                parts.push(net.trunc().to_string());
+            }
         }
+        let mut lan_prefixes = self
+            .lan_addrs
+            .iter()
+            .map(|a| a.trunc().to_string())
+            .collect::<Vec<_>>();
+        lan_prefixes.sort();
+        parts.extend(lan_prefixes);
+        parts.join(",")
     }
     fn online(&self) -> bool {
         self.wan_ipv4.is_some() || self.wan_ipv6.is_some()
@@ -82,11 +133,24 @@ impl NetInfo {
     }
 }
 
-#[derive(Clone)]
+// How often to re-detect the network when the OS notifies us about changes
+#[cfg(target_os = "linux")]
+const SAFETY_NET_POLL: Duration = Duration::from_secs(5 * 60);
+
+const UNCONFIRMED_ACCEPT_AFTER: u32 = 3;
+const UNCONFIRMED_RETRY: Duration = Duration::from_secs(15);
+
 pub(crate) struct NetworkMonitor {
     current: Option<NetInfo>,
     last_online: Option<NetInfo>,
     wg_if_name: String,
+    // None means detection runs on every check
+    poll_interval: Option<Duration>,
+    next_detection: Instant,
+    // a result too few providers agreed on, and how many times in a row it was seen
+    unconfirmed: Option<(NetInfo, u32)>,
+    #[cfg(target_os = "linux")]
+    events: Option<crate::netlink::NetworkEvents>,
 }
 
 pub(crate) enum NetStatus {
@@ -99,20 +163,108 @@ pub(crate) enum NetStatus {
 
 impl NetworkMonitor {
     pub fn new(wg_if_name: &str) -> Self {
+        #[cfg(target_os = "linux")]
+        let events = match wg_if_name
+            .parse()
+            .map_err(std::io::Error::from)
+            .and_then(|ifname| crate::netlink::NetworkEvents::subscribe(&ifname))
+        {
+            Ok(events) => Some(events),
+            Err(e) => {
+                log::warn!("Network: could not subscribe to netlink events, polling instead: {e}");
+                None
+            }
+        };
+        #[cfg(target_os = "linux")]
+        let poll_interval = events.as_ref().map(|_| SAFETY_NET_POLL);
+        #[cfg(not(target_os = "linux"))]
+        let poll_interval = None;
+
         Self {
             current: None,
             last_online: None,
             wg_if_name: wg_if_name.to_owned(),
+            poll_interval,
+            next_detection: Instant::now(),
+            unconfirmed: None,
+            #[cfg(target_os = "linux")]
+            events,
         }
     }
+
+    // Sleeps for up to `timeout`, returning true early if the OS reports a network change.
+    pub fn wait(&mut self, timeout: Duration) -> bool {
+        #[cfg(target_os = "linux")]
+        if let Some(events) = &self.events {
+            match events.wait(timeout) {
+                Ok(true) => {
+                    log::debug!("Network: change reported by netlink");
+                    self.next_detection = Instant::now();
+                    return true;
+                }
+                Ok(false) => (),
+                Err(e) => {
+                    log::warn!("Network: netlink error, falling back to polling: {e}");
+                    self.events = None;
+                    self.poll_interval = None;
+                    std::thread::sleep(timeout);
+                }
+            }
+            return false;
+        }
+        std::thread::sleep(timeout);
+        false
+    }
+
+    // The next check treats the network as new, which picks a new port and detects the NAT again
+    pub fn force_redetection(&mut self) {
+        self.current = None;
+        self.next_detection = Instant::now();
+    }
+
+    fn detection_due(&self) -> bool {
+        match (&self.current, self.poll_interval) {
+            (Some(current), Some(_)) if current.online() => Instant::now() >= self.next_detection,
+            _ => true,
+        }
+    }
+    // An unconfirmed result is accepted once it stops changing
+    fn seen_repeatedly(&mut self, info: &NetInfo) -> bool {
+        match &mut self.unconfirmed {
+            Some((seen, count)) if seen == info => *count += 1,
+            _ => self.unconfirmed = Some((info.clone(), 1)),
+        }
+        self.unconfirmed
+            .as_ref()
+            .is_some_and(|(_, count)| *count >= UNCONFIRMED_ACCEPT_AFTER)
+    }
     pub fn set_hard_nat(&mut self, hard_nat: bool) {
         if let Some(c) = &mut self.current {
             c.hard_nat = hard_nat
         }
     }
+    pub(crate) fn set_udp_blocked(&mut self, udp_blocked: bool) {
+        if let Some(c) = &mut self.current {
+            c.udp_blocked = udp_blocked
+        }
+    }
+    pub(crate) fn set_nat(&mut self, nat: Option<NatClassification>) {
+        if let Some(c) = &mut self.current {
+            c.nat = nat
+        }
+    }
 
-    pub fn check_status(&mut self) -> NetStatus {
-        let new_info = NetInfo::detect(&self.wg_if_name);
+    pub fn check_status(&mut self, servers: &Servers) -> NetStatus {
+        if !self.detection_due() {
+            return match self.needs_relay() {
+                true => NetStatus::HardNat,
+                false => NetStatus::Online,
+            };
+        }
+        if let Some(poll_interval) = self.poll_interval {
+            self.next_detection = Instant::now() + poll_interval;
+        }
+        let new_info = NetInfo::detect(&self.wg_if_name, servers);
         log::trace!("Network: {new_info:?}");
 
         let Some(current) = self.current.clone() else {
@@ -127,6 +279,16 @@ impl NetworkMonitor {
             self.current = Some(new_info);
             return NetStatus::Offline;
         }
+        if new_info != current && !new_info.confirmed && !self.seen_repeatedly(&new_info) {
+            log::debug!("Network: public IP providers disagree, ignoring {new_info:?} for now");
+            self.next_detection = self.next_detection.min(Instant::now() + UNCONFIRMED_RETRY);
+            return match (current.offline(), current.hard_nat) {
+                (true, _) => NetStatus::Offline,
+                (false, true) => NetStatus::HardNat,
+                (false, false) => NetStatus::Online,
+            };
+        }
+        self.unconfirmed = None;
         if new_info == current {
             return match self.current.as_ref().is_some_and(|c| c.hard_nat) {
                 true => NetStatus::HardNat,
@@ -137,7 +299,10 @@ impl NetworkMonitor {
         if current.offline() && new_info == self.last_online {
             log::trace!("Network: changed to previous");
             let last_online_hard_nat = self.last_online.as_ref().is_some_and(|lo| lo.hard_nat);
+            let last_online_udp_blocked =
+                self.last_online.as_ref().is_some_and(|lo| lo.udp_blocked);
             self.current = new_info;
+            self.set_udp_blocked(last_online_udp_blocked);
             return match last_online_hard_nat {
                 true => NetStatus::HardNat,
                 false => NetStatus::ChangedToPrev,
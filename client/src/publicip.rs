@@ -0,0 +1,305 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{announce, config::Servers, nat, utils};
+
+pub(crate) type PublicIps = (Option<Ipv4Addr>, Option<Ipv6Addr>);
+
+pub(crate) trait Provider {
+    fn name(&self) -> &'static str;
+    fn lookup(&self, if_name: &str, servers: &Servers, timeout: Duration) -> PublicIps;
+}
+
+// Asks the coordination server which address our announcement came from
+struct Coordination;
+
+impl Provider for Coordination {
+    fn name(&self) -> &'static str {
+        "coordination server"
+    }
+    fn lookup(&self, if_name: &str, servers: &Servers, timeout: Duration) -> PublicIps {
+        announce::get_public_ips(if_name, servers, timeout)
+    }
+}
+
+fn split(addrs: impl Iterator<Item = Option<SocketAddr>>) -> PublicIps {
+    let (mut ipv4, mut ipv6) = (None, None);
+    for addr in addrs.flatten() {
+        match addr.ip().to_canonical() {
+            IpAddr::V4(ip) => ipv4 = ipv4.or(Some(ip)),
+            IpAddr::V6(ip) => ipv6 = ipv6.or(Some(ip)),
+        }
+    }
+    (ipv4, ipv6)
+}
+
+// The first STUN server that answers over each address family
+fn lookup_over_stun(
+    servers: &Servers,
+    send: impl Fn(SocketAddr) -> std::io::Result<SocketAddr>,
+) -> PublicIps {
+    let observe = |ipv6: bool| {
+        servers.stun.iter().find_map(|(host, port)| {
+            let dst = (host.as_str(), *port)
+                .to_socket_addrs()
+                .ok()?
+                .find(|a| a.is_ipv6() == ipv6)?;
+            send(dst).inspect_err(|e| log::trace!("{dst}: {e}")).ok()
+        })
+    };
+    split([observe(false), observe(true)].into_iter())
+}
+
+// RFC 5389 Binding requests to the configured STUN servers
+struct Stun;
+
+impl Provider for Stun {
+    fn name(&self) -> &'static str {
+        "STUN"
+    }
+    fn lookup(&self, _if_name: &str, servers: &Servers, timeout: Duration) -> PublicIps {
+        lookup_over_stun(servers, |dst| nat::send_binding_request(dst, 0, timeout))
+    }
+}
+
+// The older wireplug STUN format, answered by wpcod only
+struct WireplugStun;
+
+impl Provider for WireplugStun {
+    fn name(&self) -> &'static str {
+        "wireplug STUN"
+    }
+    fn lookup(&self, _if_name: &str, servers: &Servers, timeout: Duration) -> PublicIps {
+        lookup_over_stun(servers, |dst| {
+            nat::send_stun_request(dst, 0, timeout).map(|r| r.observed_addr)
+        })
+    }
+}
+
+// Special DNS names that resolve to the client's address (via innernet)
+struct Dns;
+
+impl Provider for Dns {
+    fn name(&self) -> &'static str {
+        "DNS"
+    }
+    fn lookup(&self, _if_name: &str, _servers: &Servers, timeout: Duration) -> PublicIps {
+        innernet_publicip::get_both_with_timeout(timeout)
+    }
+}
+
+// ipify over HTTPS
+struct Https;
+
+impl Provider for Https {
+    fn name(&self) -> &'static str {
+        "HTTPS"
+    }
+    fn lookup(&self, _if_name: &str, _servers: &Servers, timeout: Duration) -> PublicIps {
+        utils::get_ip64_over_https(timeout)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ProviderKind {
+    Coordination,
+    Stun,
+    WireplugStun,
+    Dns,
+    Https,
+}
+
+impl ProviderKind {
+    fn provider(self) -> Box<dyn Provider> {
+        match self {
+            ProviderKind::Coordination => Box::new(Coordination),
+            ProviderKind::Stun => Box::new(Stun),
+            ProviderKind::WireplugStun => Box::new(WireplugStun),
+            ProviderKind::Dns => Box::new(Dns),
+            ProviderKind::Https => Box::new(Https),
+        }
+    }
+
+    // third parties learn that we run wireplugd, and from where
+    fn is_third_party(self) -> bool {
+        matches!(self, ProviderKind::Dns | ProviderKind::Https)
+    }
+}
+
+const DEFAULT_PROVIDERS: [ProviderKind; 4] = [
+    ProviderKind::Coordination,
+    ProviderKind::Stun,
+    ProviderKind::Dns,
+    ProviderKind::Https,
+];
+const DEFAULT_QUORUM: usize = 2;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug)]
+pub(crate) struct PublicIpConfig {
+    providers: Vec<ProviderKind>,
+    quorum: usize,
+    timeout: Duration,
+}
+
+impl PublicIpConfig {
+    pub(crate) fn new(
+        providers: Option<Vec<ProviderKind>>,
+        third_party: bool,
+        quorum: Option<usize>,
+        timeout: Option<u64>,
+    ) -> anyhow::Result<Self> {
+        let providers = match providers {
+            Some(p) => p,
+            None => DEFAULT_PROVIDERS
+                .into_iter()
+                .filter(|p| third_party || !p.is_third_party())
+                .collect(),
+        };
+        if providers.is_empty() {
+            return Err(anyhow::Error::msg("no public IP providers enabled"));
+        }
+        // can't ask for more agreement than there are providers
+        let quorum = quorum.unwrap_or(DEFAULT_QUORUM).clamp(1, providers.len());
+        Ok(Self {
+            providers,
+            quorum,
+            timeout: timeout.map(Duration::from_secs).unwrap_or(DEFAULT_TIMEOUT),
+        })
+    }
+}
+
+// A provider that found no address for a family votes None for it
+struct Votes<T>(Vec<(Option<T>, usize)>);
+
+impl<T: PartialEq + Copy> Votes<T> {
+    fn add(&mut self, ip: Option<T>) {
+        match self.0.iter_mut().find(|(v, _)| *v == ip) {
+            Some((_, n)) => *n += 1,
+            None => self.0.push((ip, 1)),
+        }
+    }
+
+    fn decided(&self, quorum: usize) -> bool {
+        self.0.iter().any(|(_, n)| *n >= quorum)
+    }
+
+    // The answer with the most votes, an address over None and then the
+    // earliest on a tie, and whether it is confirmed. Nothing but None
+    // is a confirmed None.
+    fn winner(&self, quorum: usize) -> (Option<T>, bool) {
+        let mut best: Option<(Option<T>, usize)> = None;
+        for (ip, n) in &self.0 {
+            if best.is_none_or(|(best_ip, best_n)| {
+                *n > best_n || (*n == best_n && best_ip.is_none() && ip.is_some())
+            }) {
+                best = Some((*ip, *n));
+            }
+        }
+        match best {
+            Some((Some(ip), n)) => (Some(ip), n >= quorum),
+            _ => (None, true),
+        }
+    }
+}
+
+pub(crate) struct Lookup {
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
+    // enough providers agree on the result
+    pub confirmed: bool,
+}
+
+// Counts `answers` until a quorum agrees on both address families. Answers
+// are only asked for as they are needed.
+fn tally(answers: impl Iterator<Item = PublicIps>, quorum: usize) -> Lookup {
+    let mut ipv4 = Votes(vec![]);
+    let mut ipv6 = Votes(vec![]);
+    for (v4, v6) in answers {
+        ipv4.add(v4);
+        ipv6.add(v6);
+        if ipv4.decided(quorum) && ipv6.decided(quorum) {
+            break;
+        }
+    }
+    let (ipv4, ipv4_confirmed) = ipv4.winner(quorum);
+    let (ipv6, ipv6_confirmed) = ipv6.winner(quorum);
+    Lookup {
+        ipv4,
+        ipv6,
+        confirmed: ipv4_confirmed && ipv6_confirmed,
+    }
+}
+
+// Asks the providers in order until a quorum agrees on both address families
+pub(crate) fn lookup(if_name: &str, servers: &Servers) -> Lookup {
+    let config = &servers.public_ip;
+    let answers = config.providers.iter().map(|kind| {
+        let provider = kind.provider();
+        let (v4, v6) = provider.lookup(if_name, servers, config.timeout);
+        log::trace!("Network: {} says {v4:?} / {v6:?}", provider.name());
+        (v4, v6)
+    });
+    tally(answers, config.quorum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quorum_confirms_address() {
+        let a = Ipv4Addr::new(192, 0, 2, 1);
+        let b = Ipv4Addr::new(198, 51, 100, 1);
+        let mut votes = Votes(vec![]);
+        votes.add(Some(b));
+        votes.add(None);
+        votes.add(Some(a));
+        assert!(!votes.decided(2));
+        assert_eq!(votes.winner(2), (Some(b), false));
+        votes.add(Some(a));
+        assert!(votes.decided(2));
+        assert_eq!(votes.winner(2), (Some(a), true));
+    }
+
+    #[test]
+    fn no_answers_is_confirmed_offline() {
+        let mut votes = Votes::<Ipv6Addr>(vec![]);
+        votes.add(None);
+        votes.add(None);
+        assert_eq!(votes.winner(2), (None, true));
+    }
+
+    #[test]
+    fn ipv4_only_quorum_stops_early() {
+        let a = Ipv4Addr::new(192, 0, 2, 1);
+        let mut asked = vec![];
+        let answers = DEFAULT_PROVIDERS.into_iter().map(|kind| {
+            asked.push(kind);
+            (Some(a), None)
+        });
+        let lookup = tally(answers, DEFAULT_QUORUM);
+        assert_eq!(asked, vec![ProviderKind::Coordination, ProviderKind::Stun]);
+        assert_eq!((lookup.ipv4, lookup.ipv6), (Some(a), None));
+        assert!(lookup.confirmed);
+    }
+
+    #[test]
+    fn quorum_is_clamped_to_providers() {
+        let config =
+            PublicIpConfig::new(Some(vec![ProviderKind::Coordination]), true, None, None).unwrap();
+        assert_eq!(config.quorum, 1);
+        let config = PublicIpConfig::new(None, false, Some(5), None).unwrap();
+        assert_eq!(
+            config.providers,
+            vec![ProviderKind::Coordination, ProviderKind::Stun]
+        );
+        assert_eq!(config.quorum, 2);
+        assert!(PublicIpConfig::new(Some(vec![]), true, None, None).is_err());
+    }
+}
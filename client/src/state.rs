@@ -0,0 +1,273 @@
+use std::{
+    collections::HashMap,
+    io::Error,
+    net::SocketAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use shared::protocol::NatClassification;
+use wireguard_control::Key;
+
+use crate::{nat::NatProfile, wg_interface::CandidateKind};
+
+static STATE_PATH: &str = "/var/lib/wireplugd";
+// oldest networks are forgotten first
+const MAX_NETWORKS: usize = 32;
+
+pub(crate) fn path(ifname: &str) -> String {
+    format!("{STATE_PATH}/{ifname}.toml")
+}
+
+// Endpoints that produced a handshake, and which kinds of candidates worked
+// for each peer, and the NAT profile, per network
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct State {
+    #[serde(default, rename = "Network")]
+    networks: Vec<Network>,
+    #[serde(skip)]
+    path: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+struct Network {
+    fingerprint: String,
+    last_seen: u64,
+    // base64 public key -> endpoint
+    endpoints: HashMap<String, SocketAddr>,
+    // base64 public key -> most recently successful first
+    #[serde(default)]
+    rankings: HashMap<String, Vec<CandidateKind>>,
+    profile: Option<Profile>,
+}
+
+// What we learned about a network's NAT
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct Profile {
+    // None on IPv6-only networks
+    pub nat: Option<NatProfile>,
+    pub port: u16,
+    pub needs_relay: bool,
+    pub classification: Option<NatClassification>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl State {
+    pub(crate) fn load(ifname: &str) -> Self {
+        let path = path(ifname);
+        let mut state = match std::fs::read_to_string(&path) {
+            Ok(s) => toml::from_str(&s).unwrap_or_else(|e| {
+                log::warn!("ignoring {path}: {e}");
+                State::default()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => State::default(),
+            Err(e) => {
+                log::warn!("could not read {path}: {e}");
+                State::default()
+            }
+        };
+        state.path = path;
+        state
+    }
+
+    pub(crate) fn save(&self) -> std::io::Result<()> {
+        std::fs::create_dir_all(STATE_PATH)?;
+        let state =
+            toml::to_string(self).map_err(|e| Error::other(format!("serialization error: {e}")))?;
+        // never leave a half written file behind
+        let tmp_path = format!("{}.tmp", self.path);
+        std::fs::write(&tmp_path, state)?;
+        std::fs::rename(&tmp_path, &self.path)
+    }
+
+    pub(crate) fn endpoints(&self, fingerprint: &str) -> HashMap<Key, SocketAddr> {
+        self.networks
+            .iter()
+            .find(|n| n.fingerprint == fingerprint)
+            .map(|n| {
+                n.endpoints
+                    .iter()
+                    .filter_map(|(k, sa)| Some((Key::from_base64(k).ok()?, *sa)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn network_mut(&mut self, fingerprint: &str) -> &mut Network {
+        if let Some(i) = self
+            .networks
+            .iter()
+            .position(|n| n.fingerprint == fingerprint)
+        {
+            return &mut self.networks[i];
+        }
+        if self.networks.len() >= MAX_NETWORKS
+            && let Some(oldest) = self
+                .networks
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, n)| n.last_seen)
+                .map(|(i, _)| i)
+        {
+            self.networks.swap_remove(oldest);
+        }
+        self.networks.push(Network {
+            fingerprint: fingerprint.to_owned(),
+            last_seen: now(),
+            endpoints: HashMap::new(),
+            rankings: HashMap::new(),
+            profile: None,
+        });
+        self.networks.last_mut().expect("just pushed")
+    }
+
+    pub(crate) fn profile(&self, fingerprint: &str) -> Option<Profile> {
+        self.networks
+            .iter()
+            .find(|n| n.fingerprint == fingerprint)
+            .and_then(|n| n.profile)
+    }
+
+    // Returns true if the profile changed
+    pub(crate) fn set_profile(&mut self, fingerprint: &str, profile: Profile) -> bool {
+        let network = self.network_mut(fingerprint);
+        if network.profile == Some(profile) {
+            return false;
+        }
+        network.profile = Some(profile);
+        network.last_seen = now();
+        true
+    }
+
+    pub(crate) fn rankings(&self, fingerprint: &str) -> HashMap<Key, Vec<CandidateKind>> {
+        self.networks
+            .iter()
+            .find(|n| n.fingerprint == fingerprint)
+            .map(|n| {
+                n.rankings
+                    .iter()
+                    .filter_map(|(k, r)| Some((Key::from_base64(k).ok()?, r.clone())))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    // Moves `kind` to the front of the peer's ranking, returns true if it wasn't there already
+    pub(crate) fn rank(&mut self, fingerprint: &str, peer: &Key, kind: CandidateKind) -> bool {
+        let network = self.network_mut(fingerprint);
+        let ranking = network.rankings.entry(peer.to_base64()).or_default();
+        if ranking.first() == Some(&kind) {
+            return false;
+        }
+        ranking.retain(|k| *k != kind);
+        ranking.insert(0, kind);
+        network.last_seen = now();
+        true
+    }
+
+    // Returns true if anything new was learned. Loopback endpoints are TCP relay
+    // shims, which are gone by the time they'd be restored.
+    pub(crate) fn remember(&mut self, fingerprint: &str, endpoints: &[(Key, SocketAddr)]) -> bool {
+        let endpoints = endpoints
+            .iter()
+            .filter(|(_, endpoint)| !endpoint.ip().is_loopback())
+            .collect::<Vec<_>>();
+        if endpoints.is_empty() {
+            return false;
+        }
+        let network = self.network_mut(fingerprint);
+        let mut changed = false;
+        for (key, endpoint) in endpoints {
+            if network.endpoints.insert(key.to_base64(), *endpoint) != Some(*endpoint) {
+                changed = true;
+            }
+        }
+        // refreshed only with new endpoints, to avoid rewriting the file every check
+        if changed {
+            network.last_seen = now();
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remembers_endpoints_per_network() {
+        let key = Key::generate_private().get_public();
+        let home: SocketAddr = "192.0.2.10:51820".parse().unwrap();
+        let office: SocketAddr = "198.51.100.7:4500".parse().unwrap();
+
+        let mut state = State::default();
+        assert!(state.remember("home", &[(key.clone(), home)]));
+        assert!(!state.remember("home", &[(key.clone(), home)]));
+        assert!(state.remember("office", &[(key.clone(), office)]));
+        let shim: SocketAddr = "127.0.0.1:40123".parse().unwrap();
+        let relayed = Key::generate_private().get_public();
+        assert!(!state.remember("home", &[(relayed.clone(), shim)]));
+
+        let state: State = toml::from_str(&toml::to_string(&state).unwrap()).unwrap();
+        assert_eq!(state.endpoints("home").get(&key), Some(&home));
+        assert_eq!(state.endpoints("office").get(&key), Some(&office));
+        assert!(state.endpoints("cafe").is_empty());
+        assert!(!state.endpoints("home").contains_key(&relayed));
+    }
+
+    #[test]
+    fn ranks_candidates() {
+        let key = Key::generate_private().get_public();
+        let mut state = State::default();
+        assert!(state.rank("home", &key, CandidateKind::Ipv4));
+        assert!(state.rank("home", &key, CandidateKind::Lan));
+        assert!(!state.rank("home", &key, CandidateKind::Lan));
+
+        let state: State = toml::from_str(&toml::to_string(&state).unwrap()).unwrap();
+        assert_eq!(
+            state.rankings("home").get(&key),
+            Some(&vec![CandidateKind::Lan, CandidateKind::Ipv4])
+        );
+        assert!(state.rankings("office").is_empty());
+    }
+
+    #[test]
+    fn keeps_profile_per_network() {
+        let profile = Profile {
+            nat: Some(NatProfile::FixedPortMapping),
+            port: 40000,
+            needs_relay: false,
+            classification: None,
+        };
+        let mut state = State::default();
+        assert!(state.set_profile("home", profile));
+        assert!(!state.set_profile("home", profile));
+
+        let state: State = toml::from_str(&toml::to_string(&state).unwrap()).unwrap();
+        assert_eq!(state.profile("home"), Some(profile));
+        assert_eq!(state.profile("office"), None);
+    }
+
+    #[test]
+    fn forgets_oldest_network() {
+        let key = Key::generate_private().get_public();
+        let sa: SocketAddr = "192.0.2.10:51820".parse().unwrap();
+        let mut state = State::default();
+        for i in 0..=MAX_NETWORKS {
+            state.remember(&format!("net{i}"), &[(key.clone(), sa)]);
+            state.networks.last_mut().unwrap().last_seen = i as u64;
+        }
+        assert_eq!(state.networks.len(), MAX_NETWORKS);
+        assert!(state.endpoints("net0").is_empty());
+        assert!(!state.endpoints(&format!("net{MAX_NETWORKS}")).is_empty());
+    }
+}
@@ -0,0 +1,144 @@
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use wireguard_control::Key;
+
+use crate::{announce, config::Servers, wg_interface};
+
+// both directions are checked this often
+const POLL: Duration = Duration::from_millis(5);
+
+// Frames are a WireGuard datagram prefixed with its length, little-endian
+fn write_frame<W: Write>(stream: &mut W, packet: &[u8]) -> std::io::Result<()> {
+    let len =
+        u16::try_from(packet.len()).map_err(|_| std::io::Error::other("datagram too large"))?;
+    let mut frame = Vec::with_capacity(packet.len() + 2);
+    frame.extend_from_slice(&len.to_le_bytes());
+    frame.extend_from_slice(packet);
+    stream.write_all(&frame)?;
+    stream.flush()
+}
+
+// Takes the first complete frame off `buf`
+fn next_frame(buf: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let len = u16::from_le_bytes(buf.get(..2)?.try_into().ok()?) as usize;
+    let packet = buf.get(2..2 + len)?.to_vec();
+    buf.drain(..2 + len);
+    Some(packet)
+}
+
+fn timed_out(e: &std::io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+// Carries datagrams between WireGuard and the relay until either goes away
+fn run<S: Read + Write>(
+    mut stream: S,
+    socket: UdpSocket,
+    mut wg_addr: SocketAddr,
+    running: &AtomicBool,
+) -> std::io::Result<()> {
+    let mut datagram = vec![0u8; u16::MAX as usize];
+    let mut received = vec![];
+    let mut buf = [0u8; 4096];
+    while running.load(Ordering::Relaxed) {
+        match socket.recv_from(&mut datagram) {
+            Ok((n, from)) => {
+                // WireGuard's listen port may have changed
+                wg_addr = from;
+                write_frame(&mut stream, &datagram[..n])?;
+            }
+            Err(e) if timed_out(&e) => (),
+            Err(e) => return Err(e),
+        }
+        match stream.read(&mut buf) {
+            Ok(0) => return Err(std::io::Error::other("closed by the coordination server")),
+            Ok(n) => {
+                received.extend_from_slice(&buf[..n]);
+                while let Some(packet) = next_frame(&mut received) {
+                    socket.send_to(&packet, wg_addr)?;
+                }
+            }
+            Err(e) if timed_out(&e) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// A local UDP port WireGuard sends a peer's traffic to, relayed over TLS by
+// the coordination server. Stops when dropped.
+pub(crate) struct Shim {
+    local_addr: SocketAddr,
+    running: Arc<AtomicBool>,
+}
+
+impl Shim {
+    pub(crate) fn start(if_name: &str, peer: &Key, servers: &Servers) -> std::io::Result<Self> {
+        let stream = announce::connect_tcp_relay(if_name, servers, peer)?;
+        stream.sock.set_read_timeout(Some(POLL))?;
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+        socket.set_read_timeout(Some(POLL))?;
+        let wg_port = wg_interface::get_listen_port(if_name)?
+            .ok_or(std::io::Error::other("no listen port"))?;
+        let wg_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, wg_port));
+        let local_addr = socket.local_addr()?;
+        let running = Arc::new(AtomicBool::new(true));
+        let shim_running = Arc::clone(&running);
+        let peer = peer.to_base64();
+        thread::spawn(move || {
+            if let Err(e) = run(stream, socket, wg_addr, &shim_running) {
+                log::info!("TCP relay to {peer} stopped: {e}");
+            }
+            shim_running.store(false, Ordering::Relaxed);
+        });
+        log::debug!("relaying over TCP via {local_addr}");
+        Ok(Self {
+            local_addr,
+            running,
+        })
+    }
+
+    pub(crate) fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub(crate) fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Shim {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_survive_partial_reads() {
+        let mut stream = vec![];
+        write_frame(&mut stream, b"first").unwrap();
+        write_frame(&mut stream, b"second").unwrap();
+
+        let mut received = stream[..4].to_vec();
+        assert_eq!(next_frame(&mut received), None);
+        received.extend_from_slice(&stream[4..10]);
+        assert_eq!(next_frame(&mut received), Some(b"first".to_vec()));
+        assert_eq!(next_frame(&mut received), None);
+        received.extend_from_slice(&stream[10..]);
+        assert_eq!(next_frame(&mut received), Some(b"second".to_vec()));
+        assert!(received.is_empty());
+    }
+}
@@ -0,0 +1,482 @@
+use std::{
+    io::{Read, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use crate::utils;
+
+const SSDP_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250)), 1900);
+const SEARCH_TARGETS: [&str; 2] = [
+    "urn:schemas-upnp-org:device:InternetGatewayDevice:2",
+    "urn:schemas-upnp-org:device:InternetGatewayDevice:1",
+];
+// in order of preference
+const SERVICE_TYPES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+const TIMEOUT: Duration = Duration::from_secs(2);
+const LEASE_DURATION: u32 = 60 * 60;
+const MAX_MAPPING_ATTEMPTS: usize = 4;
+
+// UPnP error codes we can recover from
+const ERR_CONFLICT_IN_MAPPING_ENTRY: u16 = 718;
+const ERR_ONLY_PERMANENT_LEASES_SUPPORTED: u16 = 725;
+
+enum SoapError {
+    Io(std::io::Error),
+    Upnp(u16),
+}
+
+impl From<std::io::Error> for SoapError {
+    fn from(e: std::io::Error) -> Self {
+        SoapError::Io(e)
+    }
+}
+
+impl From<SoapError> for std::io::Error {
+    fn from(e: SoapError) -> Self {
+        match e {
+            SoapError::Io(e) => e,
+            SoapError::Upnp(code) => std::io::Error::other(format!("UPnP error {code}")),
+        }
+    }
+}
+
+fn get_tag<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{tag}>"))? + tag.len() + 2;
+    let end = start + xml[start..].find(&format!("</{tag}>"))?;
+    Some(xml[start..end].trim())
+}
+
+// splits "http://host:port/path" into a socket address and a path
+fn parse_url(url: &str) -> Option<(SocketAddr, String)> {
+    let rest = url.strip_prefix("http://")?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], rest[i..].to_owned()),
+        None => (rest, "/".to_owned()),
+    };
+    let addr = match authority.to_socket_addrs() {
+        Ok(mut addrs) => addrs.next()?,
+        Err(_) => (authority, 80).to_socket_addrs().ok()?.next()?,
+    };
+    Some((addr, path))
+}
+
+fn decode_chunked(mut body: &str) -> String {
+    let mut out = String::new();
+    while let Some((size, rest)) = body.split_once("\r\n") {
+        let Ok(size) = usize::from_str_radix(size.trim(), 16) else {
+            break;
+        };
+        if size == 0 || rest.len() < size {
+            break;
+        }
+        out.push_str(&rest[..size]);
+        body = rest[size..].trim_start_matches("\r\n");
+    }
+    out
+}
+
+// Minimal HTTP/1.1 exchange, returns the status code and the body
+fn http_request(addr: SocketAddr, request: &str) -> std::io::Result<(u16, String)> {
+    let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    stream.write_all(request.as_bytes())?;
+    let mut response = vec![];
+    stream.read_to_end(&mut response)?;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or(std::io::Error::other("bad HTTP response"))?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .ok_or(std::io::Error::other("bad HTTP status line"))?;
+    let body = match head
+        .to_ascii_lowercase()
+        .contains("transfer-encoding: chunked")
+    {
+        true => decode_chunked(body),
+        false => body.to_owned(),
+    };
+    Ok((status, body))
+}
+
+#[derive(Debug)]
+pub(crate) struct Gateway {
+    control_addr: SocketAddr,
+    control_path: String,
+    service_type: String,
+}
+
+impl Gateway {
+    pub(crate) fn discover() -> std::io::Result<Self> {
+        Self::discover_at(SSDP_ADDR)
+    }
+
+    fn discover_at(ssdp_addr: SocketAddr) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_read_timeout(Some(TIMEOUT))?;
+        for st in SEARCH_TARGETS {
+            let request = format!(
+                "M-SEARCH * HTTP/1.1\r\n\
+                HOST: {SSDP_ADDR}\r\n\
+                MAN: \"ssdp:discover\"\r\n\
+                MX: 1\r\n\
+                ST: {st}\r\n\
+                \r\n"
+            );
+            socket.send_to(request.as_bytes(), ssdp_addr)?;
+        }
+
+        let deadline = Instant::now() + TIMEOUT;
+        let mut buf = [0u8; 2048];
+        while Instant::now() < deadline {
+            let n = socket.recv(&mut buf)?;
+            let response = String::from_utf8_lossy(&buf[..n]);
+            let location = response.lines().find_map(|l| {
+                let (k, v) = l.split_once(':')?;
+                k.trim().eq_ignore_ascii_case("location").then(|| v.trim())
+            });
+            let Some(location) = location else {
+                continue;
+            };
+            log::trace!("UPnP: found IGD description at {location}");
+            match Self::from_description(location) {
+                Ok(gateway) => return Ok(gateway),
+                Err(e) => log::debug!("UPnP: {location}: {e}"),
+            }
+        }
+        Err(std::io::Error::other("no UPnP IGD found"))
+    }
+
+    fn from_description(location: &str) -> std::io::Result<Self> {
+        let (addr, path) =
+            parse_url(location).ok_or(std::io::Error::other("unsupported location URL"))?;
+        let request = format!(
+            "GET {path} HTTP/1.1\r\n\
+            Host: {addr}\r\n\
+            Connection: close\r\n\
+            \r\n"
+        );
+        let (status, description) = http_request(addr, &request)?;
+        if status != 200 {
+            return Err(std::io::Error::other(format!("HTTP status {status}")));
+        }
+
+        let services = description
+            .split("<service>")
+            .skip(1)
+            .filter_map(|s| Some((get_tag(s, "serviceType")?, get_tag(s, "controlURL")?)))
+            .collect::<Vec<_>>();
+        for service_type in SERVICE_TYPES {
+            let Some((_, control_url)) = services.iter().find(|(t, _)| *t == service_type) else {
+                continue;
+            };
+            let (control_addr, control_path) = match control_url.starts_with("http://") {
+                true => parse_url(control_url).ok_or(std::io::Error::other("bad control URL"))?,
+                false => (addr, control_url.to_string()),
+            };
+            return Ok(Self {
+                control_addr,
+                control_path,
+                service_type: service_type.to_owned(),
+            });
+        }
+        Err(std::io::Error::other("IGD has no WAN connection service"))
+    }
+
+    fn soap(&self, action: &str, args: &[(&str, String)]) -> Result<String, SoapError> {
+        let args = args
+            .iter()
+            .map(|(k, v)| format!("<{k}>{v}</{k}>"))
+            .collect::<String>();
+        let body = format!(
+            "<?xml version=\"1.0\"?>\
+            <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+            s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+            <s:Body><u:{action} xmlns:u=\"{}\">{args}</u:{action}></s:Body>\
+            </s:Envelope>",
+            self.service_type
+        );
+        let request = format!(
+            "POST {} HTTP/1.1\r\n\
+            Host: {}\r\n\
+            Content-Type: text/xml; charset=\"utf-8\"\r\n\
+            SOAPAction: \"{}#{action}\"\r\n\
+            Content-Length: {}\r\n\
+            Connection: close\r\n\
+            \r\n\
+            {body}",
+            self.control_path,
+            self.control_addr,
+            self.service_type,
+            body.len()
+        );
+        let (status, response) = http_request(self.control_addr, &request)?;
+        if status == 200 {
+            return Ok(response);
+        }
+        match get_tag(&response, "errorCode").and_then(|c| c.parse().ok()) {
+            Some(code) => Err(SoapError::Upnp(code)),
+            None => Err(SoapError::Io(std::io::Error::other(format!(
+                "{action} failed with HTTP status {status}"
+            )))),
+        }
+    }
+
+    pub(crate) fn external_ip(&self) -> std::io::Result<IpAddr> {
+        let response = self.soap("GetExternalIPAddress", &[])?;
+        get_tag(&response, "NewExternalIPAddress")
+            .and_then(|ip| ip.parse().ok())
+            .ok_or(std::io::Error::other("bad GetExternalIPAddress response"))
+    }
+
+    fn local_ip(&self) -> std::io::Result<IpAddr> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(self.control_addr)?;
+        Ok(socket.local_addr()?.ip())
+    }
+
+    fn add_port_mapping(
+        &self,
+        external_port: u16,
+        internal_port: u16,
+        lease_duration: u32,
+    ) -> Result<(), SoapError> {
+        let internal_client = self.local_ip()?;
+        self.soap(
+            "AddPortMapping",
+            &[
+                ("NewRemoteHost", String::new()),
+                ("NewExternalPort", external_port.to_string()),
+                ("NewProtocol", "UDP".to_owned()),
+                ("NewInternalPort", internal_port.to_string()),
+                ("NewInternalClient", internal_client.to_string()),
+                ("NewEnabled", "1".to_owned()),
+                ("NewPortMappingDescription", "wireplugd".to_owned()),
+                ("NewLeaseDuration", lease_duration.to_string()),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn delete_port_mapping(&self, external_port: u16) -> std::io::Result<()> {
+        self.soap(
+            "DeletePortMapping",
+            &[
+                ("NewRemoteHost", String::new()),
+                ("NewExternalPort", external_port.to_string()),
+                ("NewProtocol", "UDP".to_owned()),
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+// A UDP port mapping on the IGD that is renewed while held and removed on drop.
+#[derive(Debug)]
+pub(crate) struct PortMapping {
+    gateway: Gateway,
+    external_port: u16,
+    internal_port: u16,
+    lease_duration: u32,
+    renew_at: Option<Instant>,
+}
+
+impl PortMapping {
+    pub(crate) fn create(internal_port: u16) -> std::io::Result<Self> {
+        Self::create_on(Gateway::discover()?, internal_port)
+    }
+
+    fn create_on(gateway: Gateway, internal_port: u16) -> std::io::Result<Self> {
+        if let Ok(ip) = gateway.external_ip() {
+            log::debug!("UPnP: IGD reports external address {ip}");
+        }
+        let mut lease_duration = LEASE_DURATION;
+        let mut external_port = internal_port;
+        for _ in 0..MAX_MAPPING_ATTEMPTS {
+            match gateway.add_port_mapping(external_port, internal_port, lease_duration) {
+                Ok(()) => {
+                    let mut mapping = Self {
+                        gateway,
+                        external_port,
+                        internal_port,
+                        lease_duration,
+                        renew_at: None,
+                    };
+                    mapping.schedule_renewal();
+                    return Ok(mapping);
+                }
+                Err(SoapError::Upnp(ERR_ONLY_PERMANENT_LEASES_SUPPORTED)) => {
+                    log::debug!("UPnP: IGD only supports permanent leases");
+                    lease_duration = 0;
+                }
+                Err(SoapError::Upnp(ERR_CONFLICT_IN_MAPPING_ENTRY)) => {
+                    log::debug!("UPnP: external port {external_port} is taken");
+                    external_port = utils::get_random_port();
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err(std::io::Error::other(
+            "UPnP: could not create a port mapping",
+        ))
+    }
+
+    fn schedule_renewal(&mut self) {
+        self.renew_at = match self.lease_duration {
+            0 => None,
+            lease => Some(Instant::now() + Duration::from_secs(lease as u64 / 2)),
+        };
+    }
+
+    pub(crate) fn external_port(&self) -> u16 {
+        self.external_port
+    }
+
+    pub(crate) fn renew_if_needed(&mut self) -> std::io::Result<()> {
+        let Some(renew_at) = self.renew_at else {
+            return Ok(());
+        };
+        if Instant::now() < renew_at {
+            return Ok(());
+        }
+        log::debug!("UPnP: renewing mapping for port {}", self.external_port);
+        self.gateway.add_port_mapping(
+            self.external_port,
+            self.internal_port,
+            self.lease_duration,
+        )?;
+        self.schedule_renewal();
+        Ok(())
+    }
+}
+
+impl Drop for PortMapping {
+    fn drop(&mut self) {
+        match self.gateway.delete_port_mapping(self.external_port) {
+            Ok(()) => log::debug!("UPnP: removed mapping for port {}", self.external_port),
+            Err(e) => log::warn!("UPnP: failed to remove port mapping: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Gateway, PortMapping};
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, UdpSocket},
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    // Answers SSDP searches and serves a description and a control endpoint.
+    // The first AddPortMapping is refused with a conflict.
+    fn start_fake_igd(actions: Arc<Mutex<Vec<String>>>) -> std::net::SocketAddr {
+        let ssdp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let ssdp_addr = ssdp.local_addr().unwrap();
+        let http = TcpListener::bind("127.0.0.1:0").unwrap();
+        let http_addr = http.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            while let Ok((_, from)) = ssdp.recv_from(&mut buf) {
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\n\
+                    ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+                    LOCATION: http://{http_addr}/rootDesc.xml\r\n\
+                    \r\n"
+                );
+                ssdp.send_to(response.as_bytes(), from).unwrap();
+            }
+        });
+
+        thread::spawn(move || {
+            for stream in http.incoming() {
+                let mut stream = stream.unwrap();
+                let mut buf = [0u8; 4096];
+                let n = stream.read(&mut buf).unwrap();
+                let request = String::from_utf8_lossy(&buf[..n]).to_string();
+                let (status, body) = if request.starts_with("GET /rootDesc.xml") {
+                    (
+                        "200 OK",
+                        "<root><device><serviceList>\
+                        <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+                        <controlURL>/ctl/L3F</controlURL></service>\
+                        <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+                        <controlURL>/ctl/IPConn</controlURL></service>\
+                        </serviceList></device></root>"
+                            .to_owned(),
+                    )
+                } else {
+                    let action = request
+                        .split("#")
+                        .nth(1)
+                        .and_then(|s| s.split('"').next())
+                        .unwrap()
+                        .to_owned();
+                    let mut actions = actions.lock().unwrap();
+                    actions.push(action.clone());
+                    match action.as_str() {
+                        "AddPortMapping"
+                            if actions.iter().filter(|a| *a == "AddPortMapping").count() == 1 =>
+                        {
+                            (
+                                "500 Internal Server Error",
+                                "<s:Envelope><s:Body><s:Fault><detail><UPnPError>\
+                            <errorCode>718</errorCode></UPnPError></detail></s:Fault>\
+                            </s:Body></s:Envelope>"
+                                    .to_owned(),
+                            )
+                        }
+                        "GetExternalIPAddress" => (
+                            "200 OK",
+                            "<NewExternalIPAddress>198.51.100.7</NewExternalIPAddress>".to_owned(),
+                        ),
+                        _ => ("200 OK", String::new()),
+                    }
+                };
+                let response = format!(
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        ssdp_addr
+    }
+
+    #[test]
+    fn maps_and_unmaps_port() {
+        let actions = Arc::new(Mutex::new(vec![]));
+        let ssdp_addr = start_fake_igd(Arc::clone(&actions));
+
+        let gateway = Gateway::discover_at(ssdp_addr).unwrap();
+        assert_eq!(gateway.control_path, "/ctl/IPConn");
+        assert_eq!(gateway.external_ip().unwrap().to_string(), "198.51.100.7");
+
+        let mapping = PortMapping::create_on(gateway, 51820).unwrap();
+        // the requested port was taken, so a different one was mapped
+        assert_ne!(mapping.external_port(), 51820);
+        drop(mapping);
+
+        assert_eq!(
+            *actions.lock().unwrap(),
+            vec![
+                "GetExternalIPAddress",
+                "GetExternalIPAddress",
+                "AddPortMapping",
+                "AddPortMapping",
+                "DeletePortMapping"
+            ]
+        );
+    }
+}
@@ -1,13 +1,19 @@
-use std::fmt::Write as OtherWrite;
 use std::{
     io::{Read, Write},
-    net::{Ipv4Addr, TcpStream},
+    net::{Ipv4Addr, TcpStream, ToSocketAddrs},
+    sync::Arc,
     time::Duration,
 };
 
 use getifaddrs::{InterfaceFlags, getifaddrs};
 use ipnet::IpNet;
 use rand::Rng;
+use rustls::{
+    DigitallySignedStruct, SignatureScheme,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::CryptoProvider,
+    pki_types::{CertificateDer, ServerName, UnixTime, pem::PemObject},
+};
 
 pub(crate) fn get_random_port() -> u16 {
     let mut rng = rand::rng();
@@ -42,17 +48,119 @@ pub(crate) fn get_lan_addrs(if_wg: &str) -> std::io::Result<Vec<IpNet>> {
     Ok(lan_ips)
 }
 
-pub(crate) fn get_tls_client_connection(name: &str) -> anyhow::Result<rustls::ClientConnection> {
-    let root_store = rustls::RootCertStore {
-        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
-    };
-    let config = rustls::ClientConfig::builder_with_provider(
-        rustls::crypto::ring::default_provider().into(),
-    )
-    .with_safe_default_protocol_versions()?
-    .with_root_certificates(root_store)
+#[derive(Clone, Debug)]
+pub(crate) enum TlsTrust {
+    WebPki,
+    CustomCa(rustls::RootCertStore),
+    Pinned(CertificateDer<'static>),
+}
+
+impl TlsTrust {
+    pub(crate) fn load(ca_cert: Option<&str>, pinned_cert: Option<&str>) -> anyhow::Result<Self> {
+        match (ca_cert, pinned_cert) {
+            (None, None) => Ok(TlsTrust::WebPki),
+            (Some(_), Some(_)) => Err(anyhow::Error::msg(
+                "a custom CA and a pinned certificate are mutually exclusive",
+            )),
+            (Some(path), None) => {
+                let mut root_store = rustls::RootCertStore::empty();
+                for cert in CertificateDer::pem_file_iter(path)? {
+                    root_store.add(cert?)?;
+                }
+                if root_store.is_empty() {
+                    return Err(anyhow::Error::msg(format!(
+                        "no certificates found in {path}"
+                    )));
+                }
+                Ok(TlsTrust::CustomCa(root_store))
+            }
+            (None, Some(path)) => Ok(TlsTrust::Pinned(CertificateDer::from_pem_file(path)?)),
+        }
+    }
+}
+
+// Accepts exactly one server certificate, regardless of its issuer or name.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    cert: CertificateDer<'static>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match end_entity.as_ref() == self.cert.as_ref() {
+            true => Ok(ServerCertVerified::assertion()),
+            false => Err(rustls::Error::General(
+                "server certificate does not match the pinned certificate".to_owned(),
+            )),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+pub(crate) fn get_tls_client_connection(
+    name: &str,
+    trust: &TlsTrust,
+    alpn: Option<&[u8]>,
+) -> anyhow::Result<rustls::ClientConnection> {
+    let provider: Arc<CryptoProvider> = rustls::crypto::ring::default_provider().into();
+    let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let mut config = match trust {
+        TlsTrust::WebPki => builder.with_root_certificates(rustls::RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        }),
+        TlsTrust::CustomCa(root_store) => builder.with_root_certificates(root_store.clone()),
+        TlsTrust::Pinned(cert) => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier {
+                cert: cert.clone(),
+                provider,
+            })),
+    }
     .with_no_client_auth();
-    let config = std::sync::Arc::new(config);
+    config.alpn_protocols = alpn.into_iter().map(|p| p.to_vec()).collect();
+    let config = Arc::new(config);
     Ok(rustls::ClientConnection::new(
         config,
         name.to_owned().try_into()?,
@@ -80,21 +188,21 @@ pub(crate) fn find_lan_candidates(if_wg: &str, peer_lan_addrs: &Vec<IpNet>) -> V
     candidates
 }
 
-fn get_ip_over_https(api_url: &str) -> Option<String> {
-    let mut socket = TcpStream::connect((api_url, 443)).ok()?;
-    socket
-        .set_read_timeout(Some(Duration::from_millis(500)))
-        .ok();
-    let mut client_connection = get_tls_client_connection(api_url).ok()?;
+fn get_ip_over_https(api_url: &str, timeout: Duration) -> Option<String> {
+    let addr = (api_url, 443).to_socket_addrs().ok()?.next()?;
+    let mut socket = TcpStream::connect_timeout(&addr, timeout).ok()?;
+    socket.set_read_timeout(Some(timeout)).ok();
+    let mut client_connection = get_tls_client_connection(api_url, &TlsTrust::WebPki, None).ok()?;
     let mut stream = rustls::Stream::new(&mut client_connection, &mut socket);
 
-    let buf = "GET / HTTP/1.1\r\n\
-        Host: api.ipify.org\r\n\
+    let buf = format!(
+        "GET / HTTP/1.1\r\n\
+        Host: {api_url}\r\n\
         User-Agent: wireplugd/0.1\r\n\
         Accept: */*\r\n\
         \r\n"
-        .as_bytes();
-    stream.write_all(buf).ok()?;
+    );
+    stream.write_all(buf.as_bytes()).ok()?;
     let mut buf = [0u8; 1024];
 
     let n = stream.read(&mut buf).ok()?;
@@ -105,34 +213,102 @@ fn get_ip_over_https(api_url: &str) -> Option<String> {
     Some(s.to_owned())
 }
 
-pub(crate) fn get_ip64_over_https() -> (Option<Ipv4Addr>, Option<std::net::Ipv6Addr>) {
-    let ipv4 = get_ip_over_https("api.ipify.org").and_then(|s| s.parse().ok());
-    let ipv6 = get_ip_over_https("api6.ipify.org").and_then(|s| s.parse().ok());
+pub(crate) fn get_ip64_over_https(
+    timeout: Duration,
+) -> (Option<Ipv4Addr>, Option<std::net::Ipv6Addr>) {
+    let ipv4 = get_ip_over_https("api.ipify.org", timeout).and_then(|s| s.parse().ok());
+    let ipv6 = get_ip_over_https("api6.ipify.org", timeout).and_then(|s| s.parse().ok());
     (ipv4, ipv6)
 }
 
-pub(crate) fn get_size_str(size: u64) -> anyhow::Result<String> {
-    let mut str = String::new();
-    match size {
-        0..=1023 => write!(str, "{size}Bytes")?,
-        1024..=1048575 => {
-            let size = size / 1024;
-            write!(str, "{size}KiB")?
-        }
-        1048576.. => {
-            let size = size as f64 / (1024.0 * 1024.0);
-            write!(str, "{size:.2}MiB")?
-        }
-    }
-    Ok(str)
+#[cfg(target_os = "linux")]
+pub(crate) fn get_default_gateway() -> std::io::Result<Ipv4Addr> {
+    // Iface Destination Gateway ... with addresses printed in host byte order
+    let routes = std::fs::read_to_string("/proc/net/route")?;
+    routes
+        .lines()
+        .skip(1)
+        .filter_map(|l| {
+            let fields = l.split_whitespace().collect::<Vec<_>>();
+            match fields.get(1..3)? {
+                ["00000000", gateway] => u32::from_str_radix(gateway, 16).ok(),
+                _ => None,
+            }
+        })
+        .map(|gateway| Ipv4Addr::from(gateway.to_ne_bytes()))
+        .find(|gateway| !gateway.is_unspecified())
+        .ok_or(std::io::Error::other("no default gateway"))
+}
+
+#[cfg(any(target_os = "macos", target_os = "openbsd"))]
+pub(crate) fn get_default_gateway() -> std::io::Result<Ipv4Addr> {
+    let output = std::process::Command::new("route")
+        .args(["-n", "get", "default"])
+        .output()?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|l| l.trim().strip_prefix("gateway:")?.trim().parse().ok())
+        .ok_or(std::io::Error::other("no default gateway"))
 }
+
+fn is_mac(s: &str) -> bool {
+    let octets = s.split(':').collect::<Vec<_>>();
+    octets.len() == 6
+        && octets
+            .iter()
+            .all(|o| (1..=2).contains(&o.len()) && u8::from_str_radix(o, 16).is_ok())
+        && octets.iter().any(|o| u8::from_str_radix(o, 16) != Ok(0))
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn get_mac_addr(ip: Ipv4Addr) -> std::io::Result<String> {
+    // IP address  HW type  Flags  HW address  Mask  Device
+    let arp = std::fs::read_to_string("/proc/net/arp")?;
+    let ip = ip.to_string();
+    arp.lines()
+        .skip(1)
+        .filter_map(|l| {
+            let fields = l.split_whitespace().collect::<Vec<_>>();
+            match fields.as_slice() {
+                [addr, _, _, mac, ..] if *addr == ip && is_mac(mac) => Some(mac.to_lowercase()),
+                _ => None,
+            }
+        })
+        .next()
+        .ok_or(std::io::Error::other(format!(
+            "{ip} is not in the ARP table"
+        )))
+}
+
+#[cfg(any(target_os = "macos", target_os = "openbsd"))]
+pub(crate) fn get_mac_addr(ip: Ipv4Addr) -> std::io::Result<String> {
+    let output = std::process::Command::new("arp")
+        .args(["-n", &ip.to_string()])
+        .output()?;
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .find(|t| is_mac(t))
+        .map(|mac| {
+            // macOS leaves out leading zeros
+            mac.split(':')
+                .map(|o| format!("{:0>2}", o.to_lowercase()))
+                .collect::<Vec<_>>()
+                .join(":")
+        })
+        .ok_or(std::io::Error::other(format!(
+            "{ip} is not in the ARP table"
+        )))
+}
+
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use crate::utils::get_ip64_over_https;
 
     #[test]
     fn it_works() {
-        let (ipv4, ipv6) = get_ip64_over_https();
+        let (ipv4, ipv6) = get_ip64_over_https(Duration::from_secs(2));
         assert!(
             ipv4.is_some() || ipv6.is_some(),
             "get_ip64_over_https() failed"
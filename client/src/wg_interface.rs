@@ -1,69 +1,243 @@
-use std::fmt::Write as OtherWrite;
 #[cfg(not(target_os = "linux"))]
 use std::io;
 use std::{
     collections::HashMap,
     net::{IpAddr, SocketAddr, ToSocketAddrs},
     str::FromStr,
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 
 use ipnet::IpNet;
-use shared::protocol::{self};
+use serde::{Deserialize, Serialize};
+use shared::{
+    control::{PeerLiveness, PeerStatus},
+    protocol::{self},
+};
 use wireguard_control::{
-    Backend, Device, DeviceUpdate, InterfaceName, Key, KeyPair, PeerConfigBuilder, PeerInfo,
+    AllowedIp, Backend, Device, DeviceUpdate, InterfaceName, Key, KeyPair, PeerConfigBuilder,
+    PeerInfo,
 };
 
-use crate::{config::Config, utils};
+use crate::{
+    birthday,
+    config::{Config, Peer, PeersDiff, Servers},
+    tcp_relay, utils,
+};
 
 pub const COMMON_PKA: u16 = 25;
 // WireGuard's rekey interval, and some
 pub const LAST_HANDSHAKE_MAX: u64 = 180;
+// WireGuard rekeys every 120s while traffic flows, allow for the handshake to be retried
+const HANDSHAKE_STALE_AFTER: u64 = 135;
+const MISSED_KEEPALIVES: u32 = 3;
+// a stale link may still recover by itself, so it is announced less often
+const STALE_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct LivenessThresholds {
+    pub stale_after: Duration,
+    pub unreachable_after: Duration,
+    pub missed_keepalives: u32,
+}
+
+impl LivenessThresholds {
+    pub(crate) fn from_config(config: Option<&Config>) -> Self {
+        let interface = config.map(|c| &c.interface);
+        Self {
+            stale_after: Duration::from_secs(
+                interface
+                    .and_then(|i| i.handshake_stale_after)
+                    .unwrap_or(HANDSHAKE_STALE_AFTER),
+            ),
+            unreachable_after: Duration::from_secs(
+                interface
+                    .and_then(|i| i.handshake_timeout)
+                    .unwrap_or(LAST_HANDSHAKE_MAX),
+            ),
+            missed_keepalives: interface
+                .and_then(|i| i.missed_keepalives)
+                .unwrap_or(MISSED_KEEPALIVES),
+        }
+    }
+}
+
+// What a peer looked like between two liveness checks
+struct Observation {
+    handshake_age: Option<Duration>,
+    rx_grew: bool,
+    tx_grew: bool,
+    silent_for: Duration,
+    keepalive: Option<u16>,
+}
+
+// an interval of 0 is how WireGuard reports keepalives as off
+fn keepalive_interval(configured: Option<u16>) -> Option<u16> {
+    configured.filter(|&pka| pka != 0)
+}
+
+fn classify(o: &Observation, thresholds: &LivenessThresholds) -> PeerLiveness {
+    let Some(handshake_age) = o.handshake_age else {
+        return PeerLiveness::Unreachable;
+    };
+    // without keepalives and with nothing to send, WireGuard stops handshaking;
+    // an old handshake says nothing about the link then
+    if o.keepalive.is_none() && !o.tx_grew {
+        return PeerLiveness::Connected;
+    }
+    if handshake_age > thresholds.unreachable_after {
+        return PeerLiveness::Unreachable;
+    }
+    if handshake_age > thresholds.stale_after {
+        return PeerLiveness::Stale;
+    }
+    if o.rx_grew {
+        return PeerLiveness::Connected;
+    }
+    match o.keepalive {
+        Some(pka)
+            if o.silent_for > Duration::from_secs(pka as u64) * thresholds.missed_keepalives =>
+        {
+            PeerLiveness::Stale
+        }
+        _ => PeerLiveness::Connected,
+    }
+}
 
 struct WireplugPeerInfo {
     pub current_endpoint: Option<SocketAddr>,
     pub rx: u64,
+    pub tx: u64,
+    pub last_rx: Instant,
+    pub liveness: Option<PeerLiveness>,
+    // when the peer was last announced for being stale
+    pub stale_announced: Option<Instant>,
 }
 
 impl WireplugPeerInfo {
-    fn new(current_endpoint: Option<SocketAddr>, rx: u64) -> Self {
+    fn new(current_endpoint: Option<SocketAddr>, rx: u64, tx: u64) -> Self {
         Self {
             current_endpoint,
             rx,
+            tx,
+            last_rx: Instant::now(),
+            liveness: None,
+            stale_announced: None,
         }
     }
 }
 pub struct PeerTracker {
     peers: HashMap<Key, WireplugPeerInfo>,
+    fallback_endpoints: HashMap<Key, String>,
+    // peers reached through the coordination server over TCP
+    tcp_relays: HashMap<Key, tcp_relay::Shim>,
+    // candidates that were probed without an answer, tried last next time
+    failed_probes: HashMap<Key, Vec<SocketAddr>>,
 }
 
 impl PeerTracker {
-    pub fn new() -> Self {
+    pub fn new(fallback_endpoints: HashMap<Key, String>) -> Self {
         Self {
             peers: HashMap::new(),
+            fallback_endpoints,
+            tcp_relays: HashMap::new(),
+            failed_probes: HashMap::new(),
+        }
+    }
+
+    // The local end of the TCP relay to `peer`, started if needed
+    fn tcp_relay(
+        &mut self,
+        if_name: &str,
+        peer: &Key,
+        servers: &Servers,
+    ) -> Result<SocketAddr, std::io::Error> {
+        if let Some(shim) = self.tcp_relays.get(peer)
+            && shim.is_running()
+        {
+            return Ok(shim.local_addr());
+        }
+        let shim = tcp_relay::Shim::start(if_name, peer, servers)?;
+        let local_addr = shim.local_addr();
+        self.tcp_relays.insert(peer.to_owned(), shim);
+        Ok(local_addr)
+    }
+
+    fn track(&mut self, peer: &PeerInfo) {
+        self.peers
+            .entry(peer.config.public_key.to_owned())
+            .or_insert_with(|| {
+                WireplugPeerInfo::new(
+                    peer.config.endpoint,
+                    peer.stats.rx_bytes,
+                    peer.stats.tx_bytes,
+                )
+            });
+    }
+
+    fn forget(&mut self, peer: &Key) {
+        self.peers.remove(peer);
+        self.fallback_endpoints.remove(peer);
+        self.tcp_relays.remove(peer);
+        self.failed_probes.remove(peer);
+    }
+
+    fn check(&mut self, peer: &PeerInfo, thresholds: &LivenessThresholds) -> PeerLiveness {
+        self.track(peer);
+        let info = self
+            .peers
+            .get_mut(&peer.config.public_key)
+            .expect("tracked above");
+        let rx_grew = peer.stats.rx_bytes > info.rx;
+        let tx_grew = peer.stats.tx_bytes > info.tx;
+        if rx_grew {
+            info.last_rx = Instant::now();
+        }
+        info.rx = peer.stats.rx_bytes;
+        info.tx = peer.stats.tx_bytes;
+        let observation = Observation {
+            handshake_age: peer
+                .stats
+                .last_handshake_time
+                .map(|t| SystemTime::now().duration_since(t).unwrap_or_default()),
+            rx_grew,
+            tx_grew,
+            silent_for: info.last_rx.elapsed(),
+            keepalive: keepalive_interval(peer.config.persistent_keepalive_interval),
+        };
+        let liveness = classify(&observation, thresholds);
+        if info.liveness != Some(liveness) {
+            log::debug!(
+                "peer {} is {liveness} (last handshake {:?} ago)",
+                peer.config.public_key.to_base64(),
+                observation.handshake_age
+            );
         }
+        info.liveness = Some(liveness);
+        liveness
     }
 
-    fn update(&mut self, peer: &PeerInfo) -> bool {
-        let new_rx = peer.stats.rx_bytes;
-        let msg = format!("\tpeer: {} .. ", &peer.config.public_key.to_base64());
-        match self.peers.get_mut(&peer.config.public_key) {
-            Some(info) => {
-                if new_rx > info.rx {
-                    info.rx = new_rx;
-                    log::trace!("{msg} OK");
-                    return true;
+    // Unreachable peers are announced on every check, stale ones every
+    // STALE_ANNOUNCE_INTERVAL until they are connected again
+    fn needs_announcing(&mut self, peer: &Key, liveness: PeerLiveness, now: Instant) -> bool {
+        let Some(info) = self.peers.get_mut(peer) else {
+            return false;
+        };
+        match liveness {
+            PeerLiveness::Unreachable => true,
+            PeerLiveness::Stale => {
+                let due = info
+                    .stale_announced
+                    .is_none_or(|t| now.duration_since(t) >= STALE_ANNOUNCE_INTERVAL);
+                if due {
+                    info.stale_announced = Some(now);
                 }
+                due
             }
-            None => {
-                let info = WireplugPeerInfo::new(peer.config.endpoint, new_rx);
-                self.peers.insert(peer.config.public_key.to_owned(), info);
-                log::trace!("{msg} NEW");
-                return true;
+            PeerLiveness::Connected => {
+                info.stale_announced = None;
+                false
             }
         }
-        log::trace!("{msg} INACTIVE");
-        false
     }
 }
 
@@ -93,43 +267,48 @@ pub(crate) fn show_config(ifname: &str) -> Result<(), std::io::Error> {
     Ok(())
 }
 
-pub(crate) fn get_peer_info(ifname: &str) -> anyhow::Result<String> {
+fn get_peer_status(peer: &PeerInfo, liveness: Option<PeerLiveness>, inactive: bool) -> PeerStatus {
+    PeerStatus {
+        public_key: peer.config.public_key.to_base64(),
+        endpoint: peer.config.endpoint,
+        allowed_ips: peer
+            .config
+            .allowed_ips
+            .iter()
+            .filter_map(|aip| IpNet::new(aip.address, aip.cidr).ok())
+            .collect(),
+        last_handshake_secs: peer
+            .stats
+            .last_handshake_time
+            .and_then(|t| SystemTime::now().duration_since(t).ok())
+            .map(|d| d.as_secs()),
+        rx_bytes: peer.stats.rx_bytes,
+        tx_bytes: peer.stats.tx_bytes,
+        persistent_keepalive: peer.config.persistent_keepalive_interval,
+        liveness,
+        inactive,
+    }
+}
+
+// Returns the listen port and the status of every peer, sorted by public key
+pub(crate) fn get_device_status(
+    ifname: &str,
+    peer_tracker: &PeerTracker,
+    inactive_peers: &[Key],
+) -> Result<(Option<u16>, Vec<PeerStatus>), std::io::Error> {
     let ifname: InterfaceName = ifname.parse()?;
     let dev = Device::get(&ifname, Backend::default())?;
-    let mut w = String::new();
-    writeln!(w, "Peers:\n------")?;
-    let now = SystemTime::now();
-    let mut peers = dev.peers.clone();
-    peers.sort_by(|a, b| {
-        a.config
-            .public_key
-            .as_bytes()
-            .cmp(b.config.public_key.as_bytes())
-    });
-    for peer in peers {
-        let peer_pubkey = peer.config.public_key.to_base64();
-        writeln!(w, "\tpublic key: {peer_pubkey}")?;
-        write!(w, "\tendpoint: ")?;
-        match peer.config.endpoint {
-            Some(e) => writeln!(w, "{e}")?,
-            None => writeln!(w, "N/A")?,
-        };
-        let last_handshake = match peer.stats.last_handshake_time {
-            Some(last_handshake_time) => now
-                .duration_since(last_handshake_time)?
-                .as_secs()
-                .to_string(),
-            None => "NA".to_string(),
-        };
-        writeln!(w, "\tlast handshake: {last_handshake} seconds ago")?;
-        writeln!(w, "\ttx: {}", utils::get_size_str(peer.stats.tx_bytes)?)?;
-        writeln!(w, "\trx: {}", utils::get_size_str(peer.stats.rx_bytes)?)?;
-        writeln!(
-            w,
-            "\t--------------------------------------------------------"
-        )?;
-    }
-    Ok(w)
+    let mut peers = dev
+        .peers
+        .iter()
+        .map(|p| {
+            let key = &p.config.public_key;
+            let liveness = peer_tracker.peers.get(key).and_then(|i| i.liveness);
+            get_peer_status(p, liveness, inactive_peers.contains(key))
+        })
+        .collect::<Vec<_>>();
+    peers.sort_by(|a, b| a.public_key.cmp(&b.public_key));
+    Ok((dev.listen_port, peers))
 }
 
 #[cfg(any(target_os = "macos", target_os = "openbsd"))]
@@ -202,6 +381,30 @@ pub fn add_route(ifname: &InterfaceName, cidr: IpNet) -> Result<bool, io::Error>
     }
 }
 
+#[cfg(target_os = "linux")]
+use crate::netlink::del_route;
+
+#[cfg(target_os = "macos")]
+pub fn del_route(ifname: &InterfaceName, cidr: IpNet) -> Result<(), io::Error> {
+    let real_interface = wireguard_control::backends::userspace::resolve_tun(ifname)?;
+    cmd(
+        "route",
+        &[
+            "-n",
+            "delete",
+            if matches!(cidr, IpNet::V4(_)) {
+                "-inet"
+            } else {
+                "-inet6"
+            },
+            &cidr.to_string(),
+            "-interface",
+            &real_interface,
+        ],
+    )?;
+    Ok(())
+}
+
 fn configure_inet(ifname: &InterfaceName, config: &Config) -> anyhow::Result<()> {
     let addr = IpNet::from_str(config.interface.address.as_str())
         .map_err(|e| std::io::Error::other(format!("Parsing Error: {e}")))?;
@@ -213,24 +416,76 @@ fn configure_inet(ifname: &InterfaceName, config: &Config) -> anyhow::Result<()>
     #[cfg(not(target_os = "openbsd"))]
     add_route(ifname, addr)?;
 
+    #[cfg(not(target_os = "openbsd"))]
+    for peer in &config.peers {
+        for allowed_ip in &peer.allowed_ips {
+            add_peer_route(ifname, *allowed_ip)?;
+        }
+    }
+
     Ok(())
 }
 
-pub(crate) fn configure(ifname: &str, config: Option<Config>) -> anyhow::Result<()> {
+#[cfg(not(target_os = "openbsd"))]
+fn add_peer_route(ifname: &InterfaceName, allowed_ip: IpNet) -> Result<(), std::io::Error> {
+    if allowed_ip.prefix_len() == 0 {
+        log::warn!("{ifname}: not routing {allowed_ip}, default routes are not supported");
+        return Ok(());
+    }
+    if add_route(ifname, allowed_ip)? {
+        log::debug!("{ifname}: added route to {allowed_ip}");
+    }
+    Ok(())
+}
+
+fn peer_config_builder(
+    ifname: &InterfaceName,
+    peer: &Peer,
+    with_endpoint: bool,
+) -> anyhow::Result<PeerConfigBuilder> {
+    let public_key = Key::from_base64(&peer.public_key)
+        .map_err(|e| std::io::Error::other(format!("Could not parse key: {e}")))?;
+    let pka = peer.persistent_keepalive.unwrap_or(COMMON_PKA);
+    let mut peer_config = PeerConfigBuilder::new(&public_key)
+        .set_persistent_keepalive_interval(pka)
+        .add_allowed_ips(
+            &peer
+                .allowed_ips
+                .iter()
+                .map(|net| AllowedIp {
+                    address: net.addr(),
+                    cidr: net.prefix_len(),
+                })
+                .collect::<Vec<_>>(),
+        );
+    peer_config = match &peer.preshared_key {
+        Some(preshared_key) => {
+            peer_config.set_preshared_key(Key::from_base64(preshared_key).map_err(|e| {
+                std::io::Error::other(format!("Could not parse preshared key: {e}"))
+            })?)
+        }
+        None => peer_config.unset_preshared_key(),
+    };
+    if with_endpoint && let Some(endpoint) = &peer.endpoint {
+        match resolve_endpoint(endpoint) {
+            Some(sa) => peer_config = peer_config.set_endpoint(sa),
+            None => log::warn!("{ifname}: could not resolve endpoint {endpoint}"),
+        }
+    }
+    log::debug!("{ifname}: setting wgpka={pka} on {}", peer.public_key);
+    Ok(peer_config)
+}
+
+pub(crate) fn configure(ifname: &str, config: Option<&Config>) -> anyhow::Result<()> {
     let ifname: InterfaceName = ifname.parse()?;
     match config {
         Some(config) => {
             log::debug!("{ifname}: configuring using config file");
-            let mut peers = vec![];
-            for peer in &config.peers {
-                let peer_config = PeerConfigBuilder::new(
-                    &Key::from_base64(&peer.public_key)
-                        .map_err(|e| std::io::Error::other(format!("Could not parse key: {e}")))?,
-                )
-                .set_persistent_keepalive_interval(COMMON_PKA)
-                .add_allowed_ip(IpAddr::from_str(peer.allowed_ips.as_str())?, 32);
-                peers.push(peer_config);
-            }
+            let peers = config
+                .peers
+                .iter()
+                .map(|peer| peer_config_builder(&ifname, peer, true))
+                .collect::<Result<Vec<_>, _>>()?;
 
             let update = DeviceUpdate::new()
                 .set_keypair(KeyPair::from_private(Key::from_base64(
@@ -238,10 +493,9 @@ pub(crate) fn configure(ifname: &str, config: Option<Config>) -> anyhow::Result<
                 )?))
                 .add_peers(&peers);
 
-            log::debug!("{ifname}: setting wgpka={} on all peers", COMMON_PKA);
             update.apply(&ifname, Backend::default())?;
 
-            configure_inet(&ifname, &config)?;
+            configure_inet(&ifname, config)?;
         }
         None => {
             let device = Device::get(&ifname, Backend::default())?;
@@ -263,6 +517,91 @@ pub(crate) fn configure(ifname: &str, config: Option<Config>) -> anyhow::Result<
     Ok(())
 }
 
+// Applies only what changed between two versions of the config file; peers that
+// are left untouched keep both their WireGuard state and their PeerTracker entry.
+// Returns the keys of peers that should be announced.
+pub(crate) fn apply_peers_diff(
+    ifname: &str,
+    peer_tracker: &mut PeerTracker,
+    diff: &PeersDiff,
+    new_config: &Config,
+) -> anyhow::Result<Vec<Key>> {
+    let ifname: InterfaceName = ifname.parse()?;
+    let mut update = DeviceUpdate::new();
+    let mut to_announce = vec![];
+
+    for peer in &diff.removed {
+        let key = Key::from_base64(&peer.public_key)?;
+        log::info!("{ifname}: removing peer {}", peer.public_key);
+        update = update.remove_peer_by_key(&key);
+        peer_tracker.forget(&key);
+    }
+    for peer in &diff.added {
+        log::info!("{ifname}: adding peer {}", peer.public_key);
+        update = update.add_peer(peer_config_builder(&ifname, peer, true)?);
+        to_announce.push(Key::from_base64(&peer.public_key)?);
+    }
+    for (old_peer, peer) in &diff.changed {
+        log::info!("{ifname}: updating peer {}", peer.public_key);
+        let endpoint_changed = old_peer.endpoint != peer.endpoint;
+        update = update
+            .add_peer(peer_config_builder(&ifname, peer, endpoint_changed)?.replace_allowed_ips());
+        if endpoint_changed {
+            to_announce.push(Key::from_base64(&peer.public_key)?);
+        }
+    }
+    update.apply(&ifname, Backend::default())?;
+
+    let device = Device::get(&ifname, Backend::default())?;
+    for p in &device.peers {
+        peer_tracker.track(p);
+    }
+    peer_tracker.fallback_endpoints = new_config.fallback_endpoints()?;
+
+    #[cfg(not(target_os = "openbsd"))]
+    update_peer_routes(&ifname, diff, new_config)?;
+
+    Ok(to_announce)
+}
+
+#[cfg(not(target_os = "openbsd"))]
+fn update_peer_routes(
+    ifname: &InterfaceName,
+    diff: &PeersDiff,
+    new_config: &Config,
+) -> anyhow::Result<()> {
+    let interface_net = IpNet::from_str(new_config.interface.address.as_str())
+        .map_err(|e| std::io::Error::other(format!("Parsing Error: {e}")))?
+        .trunc();
+    let still_routed = |net: &IpNet| {
+        *net == interface_net || new_config.peers.iter().any(|p| p.allowed_ips.contains(net))
+    };
+    let old_nets = diff
+        .removed
+        .iter()
+        .chain(diff.changed.iter().map(|(old_peer, _)| old_peer))
+        .flat_map(|p| p.allowed_ips.iter());
+    for net in old_nets {
+        if net.prefix_len() != 0 && !still_routed(net) {
+            del_route(ifname, *net)?;
+            log::debug!("{ifname}: removed route to {net}");
+        }
+    }
+    let new_nets = diff
+        .added
+        .iter()
+        .chain(diff.changed.iter().map(|(_, new_peer)| new_peer))
+        .flat_map(|p| p.allowed_ips.iter());
+    for net in new_nets {
+        add_peer_route(ifname, *net)?;
+    }
+    Ok(())
+}
+
+fn resolve_endpoint(endpoint: &str) -> Option<SocketAddr> {
+    endpoint.to_socket_addrs().ok()?.next()
+}
+
 fn update_peer(
     iface: &InterfaceName,
     peer_tracker: &mut PeerTracker,
@@ -295,6 +634,10 @@ fn update_peer(
     let peer_config = PeerConfigBuilder::new(peer).set_endpoint(new_endpoint);
     let update = DeviceUpdate::new().add_peers(&[peer_config]);
     update.apply(iface, Backend::default())?;
+    // the TCP relay isn't needed anymore
+    if !new_endpoint.ip().is_loopback() {
+        peer_tracker.tcp_relays.remove(peer);
+    }
     peer_tracker
         .peers
         .entry(peer.to_owned())
@@ -303,77 +646,513 @@ fn update_peer(
     Ok(true)
 }
 
-pub(crate) fn update_peers(
+// Candidates are tried in this order unless a ranking says otherwise
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CandidateKind {
+    Lan,
+    Ipv6,
+    Ipv4,
+    // ports a sequential NAT is expected to hand out next
+    Predicted,
+    Relay,
+    TcpRelay,
+    // a path birthday traversal found, never probed
+    Birthday,
+}
+
+#[derive(Debug)]
+struct Candidate {
+    kind: CandidateKind,
+    addr: SocketAddr,
+    // sent to along with `addr`, the rest of a predicted port range
+    spray: Vec<SocketAddr>,
+}
+
+// WireGuard retransmits an unanswered handshake after REKEY_TIMEOUT plus up
+// to a third of a second of jitter
+const REKEY_TIMEOUT: Duration = Duration::from_secs(5);
+const REKEY_JITTER: Duration = Duration::from_millis(334);
+// How long to wait for a handshake before trying the next candidate. Covers
+// one retransmit, and a round trip for its answer.
+const PROBE_TIMEOUT: Duration = REKEY_TIMEOUT
+    .saturating_add(REKEY_JITTER)
+    .saturating_add(Duration::from_secs(1));
+// no new rounds are started after this, the rest is probed on the next announcement
+const MAX_PROBE_TIME: Duration = Duration::from_secs(15);
+// WireGuard re-keys a session it initiated once it is REKEY_AFTER_TIME (120s) old,
+// one the peer initiated only after REJECT_AFTER_TIME - KEEPALIVE - REKEY_TIMEOUT.
+// Until then a punch sends a keepalive, not a handshake.
+const SESSION_REKEYED_AFTER: Duration = Duration::from_secs(180 - 10 - 5);
+// a rendezvous further away than this is not waited for
+pub(crate) const RENDEZVOUS_MAX_WAIT: Duration = Duration::from_secs(35);
+
+fn gather_candidates(
     if_name: &str,
     peer_tracker: &mut PeerTracker,
-    new_endpoints: HashMap<String, protocol::WireplugEndpoint>,
+    peer_pubkey: &Key,
+    peer_endpoint: protocol::WireplugEndpoint,
     local_has_ipv6: bool,
-) -> Result<Vec<Key>, std::io::Error> {
-    let iface = if_name.parse()?;
-    let mut peers_updated = vec![];
-    for (peer, peer_endpoint) in new_endpoints {
-        let Ok(peer_pubkey) = Key::from_base64(&peer) else {
-            log::error!("bad peer pubkey");
-            continue;
-        };
-        match peer_endpoint {
-            protocol::WireplugEndpoint::Unknown => log::debug!("wireplug.org: {peer} is unknown"),
-            protocol::WireplugEndpoint::LocalNetwork {
-                ipv6,
-                lan_addrs,
-                wg_port,
-            } => {
-                let ip_to_use = if local_has_ipv6 && let Some(ipv6) = ipv6 {
-                    IpAddr::V6(ipv6)
-                } else {
-                    let candidates = utils::find_lan_candidates(if_name, &lan_addrs);
-                    log::trace!(
-                        "wireplug.org: {peer} is on our local network. LAN candidates: {:?}",
-                        candidates
-                    );
-                    match candidates.first() {
-                        Some(addr) => addr.addr(),
-                        None => continue,
-                    }
-                };
-                let sa = SocketAddr::new(ip_to_use, wg_port);
-                log::debug!("wireplug.org: {peer} is @{:?}", &sa);
-                if update_peer(&iface, peer_tracker, &peer_pubkey, sa)? {
-                    peers_updated.push(peer_pubkey);
+    servers: &Servers,
+) -> Vec<Candidate> {
+    let peer = peer_pubkey.to_base64();
+    let relay_host = servers.relay.as_str();
+    let mut candidates = vec![];
+    match peer_endpoint {
+        protocol::WireplugEndpoint::Unknown => log::debug!("wireplug.org: {peer} is unknown"),
+        protocol::WireplugEndpoint::LocalNetwork {
+            ipv6,
+            lan_addrs,
+            wg_port,
+        } => {
+            let lan_candidates = utils::find_lan_candidates(if_name, &lan_addrs);
+            log::trace!(
+                "wireplug.org: {peer} is on our local network. LAN candidates: {:?}",
+                lan_candidates
+            );
+            for addr in lan_candidates {
+                candidates.push(Candidate {
+                    kind: CandidateKind::Lan,
+                    addr: SocketAddr::new(addr.addr(), wg_port),
+                    spray: vec![],
+                });
+            }
+            if local_has_ipv6 && let Some(ipv6) = ipv6 {
+                candidates.push(Candidate {
+                    kind: CandidateKind::Ipv6,
+                    addr: SocketAddr::new(IpAddr::V6(ipv6), wg_port),
+                    spray: vec![],
+                });
+            }
+        }
+        protocol::WireplugEndpoint::RemoteNetwork {
+            ipv4,
+            ipv6,
+            wg_port,
+            predicted_ports,
+        } => {
+            if local_has_ipv6 && let Some(ipv6) = ipv6 {
+                candidates.push(Candidate {
+                    kind: CandidateKind::Ipv6,
+                    addr: SocketAddr::new(IpAddr::V6(ipv6), wg_port),
+                    spray: vec![],
+                });
+            }
+            if let Some(ipv4) = ipv4 {
+                candidates.push(Candidate {
+                    kind: CandidateKind::Ipv4,
+                    addr: SocketAddr::new(IpAddr::V4(ipv4), wg_port),
+                    spray: vec![],
+                });
+                // the whole range is tried at once, which port the NAT hands
+                // out next is only a guess
+                let mut predicted = predicted_ports
+                    .iter()
+                    .flat_map(|range| range.ports())
+                    .filter(|p| *p != wg_port)
+                    .map(|port| SocketAddr::new(IpAddr::V4(ipv4), port));
+                if let Some(addr) = predicted.next() {
+                    candidates.push(Candidate {
+                        kind: CandidateKind::Predicted,
+                        addr,
+                        spray: predicted.collect(),
+                    });
                 }
             }
-            protocol::WireplugEndpoint::RemoteNetwork {
-                ipv4,
-                ipv6,
-                wg_port,
-            } => {
-                let ip_to_use = if local_has_ipv6 && let Some(ipv6) = ipv6 {
-                    IpAddr::V6(ipv6)
-                } else {
-                    ipv4.map(IpAddr::V4)
-                        .ok_or(std::io::Error::other("peer has no ip"))?
+        }
+        protocol::WireplugEndpoint::Relay {
+            id: _id,
+            ip: Some(ip),
+            port,
+        } => candidates.push(Candidate {
+            kind: CandidateKind::Relay,
+            addr: SocketAddr::new(ip, port),
+            spray: vec![],
+        }),
+        // the coordination server's own relay
+        protocol::WireplugEndpoint::Relay {
+            id: _id,
+            ip: None,
+            port,
+        } => match (relay_host, port).to_socket_addrs().map(|mut a| a.next()) {
+            Ok(Some(addr)) => candidates.push(Candidate {
+                kind: CandidateKind::Relay,
+                addr,
+                spray: vec![],
+            }),
+            _ => log::warn!("could not resolve relay address {relay_host}"),
+        },
+        protocol::WireplugEndpoint::TcpRelay => {
+            match peer_tracker.tcp_relay(if_name, peer_pubkey, servers) {
+                Ok(addr) => candidates.push(Candidate {
+                    kind: CandidateKind::TcpRelay,
+                    addr,
+                    spray: vec![],
+                }),
+                Err(e) => log::warn!("could not relay to {peer} over TCP: {e}"),
+            }
+        }
+    }
+    candidates
+}
+
+// a peer's last handshake and current endpoint
+type Session = (Option<SystemTime>, Option<SocketAddr>);
+
+// Whether WireGuard still holds a session with `peer` that a punch won't replace,
+// over one of its candidates. After a network change the session may run over
+// an endpoint the server no longer offers, like the peer's old LAN address.
+fn has_session(
+    sessions: &HashMap<Key, Session>,
+    peer: &Key,
+    candidates: &[Candidate],
+    now: SystemTime,
+) -> bool {
+    let Some((Some(handshake), Some(endpoint))) = sessions.get(peer) else {
+        return false;
+    };
+    now.duration_since(*handshake).unwrap_or_default() < SESSION_REKEYED_AFTER
+        && candidates.iter().any(|c| c.addr == *endpoint)
+}
+
+// The last handshake and the current endpoint of every peer
+fn get_sessions(iface: &InterfaceName) -> Result<HashMap<Key, Session>, std::io::Error> {
+    let device = Device::get(iface, Backend::default())?;
+    Ok(device
+        .peers
+        .into_iter()
+        .map(|p| {
+            (
+                p.config.public_key,
+                (p.stats.last_handshake_time, p.config.endpoint),
+            )
+        })
+        .collect())
+}
+
+fn get_last_handshakes(
+    iface: &InterfaceName,
+) -> Result<HashMap<Key, Option<SystemTime>>, std::io::Error> {
+    let device = Device::get(iface, Backend::default())?;
+    Ok(device
+        .peers
+        .into_iter()
+        .map(|p| (p.config.public_key, p.stats.last_handshake_time))
+        .collect())
+}
+
+// Points the peer at `endpoint` and makes WireGuard send a handshake right now.
+// Turning persistent keepalive back on sends a keepalive, which needs a handshake.
+pub(crate) fn punch(
+    iface: &InterfaceName,
+    peer_tracker: &mut PeerTracker,
+    peer: &Key,
+    endpoint: SocketAddr,
+) -> Result<(), std::io::Error> {
+    update_peer(iface, peer_tracker, peer, endpoint)?;
+    // keepalives the user turned off are turned off again afterwards
+    let configured = Device::get(iface, Backend::default())?
+        .peers
+        .into_iter()
+        .find(|p| p.config.public_key == *peer)
+        .and_then(|p| p.config.persistent_keepalive_interval)
+        .unwrap_or(0);
+    let intervals = match configured {
+        0 => vec![0, COMMON_PKA, 0],
+        pka => vec![0, pka],
+    };
+    for interval in intervals {
+        let peer_config = PeerConfigBuilder::new(peer).set_persistent_keepalive_interval(interval);
+        DeviceUpdate::new()
+            .add_peers(&[peer_config])
+            .apply(iface, Backend::default())?;
+    }
+    Ok(())
+}
+
+// Tries the n-th candidate of every unsettled peer at once, until each peer
+// completes a handshake, runs out of candidates or MAX_PROBE_TIME is up.
+// `punched` peers were just sent a handshake at a rendezvous, after
+// `punched_since`. `wait` sleeps, and returns true to stop probing early.
+fn probe_candidates(
+    iface: &InterfaceName,
+    peer_tracker: &mut PeerTracker,
+    mut candidates: Vec<(Key, Vec<Candidate>)>,
+    punched: &[(Key, SocketAddr)],
+    punched_since: SystemTime,
+    wait: &mut dyn FnMut(Duration) -> bool,
+) -> Result<Vec<(Key, CandidateKind)>, std::io::Error> {
+    let mut settled = vec![];
+    // peers that sent nothing back over IPv6
+    let mut ipv6_timed_out = vec![];
+    let probe_deadline = Instant::now() + MAX_PROBE_TIME;
+    let mut interrupted = false;
+    for round in 0.. {
+        let probing = candidates
+            .iter()
+            .filter_map(|(peer, c)| Some((peer.to_owned(), c.get(round)?)))
+            .collect::<Vec<_>>();
+        if probing.is_empty() || interrupted || Instant::now() >= probe_deadline {
+            break;
+        }
+        let started = SystemTime::now();
+        let was_punched = |peer: &Key, candidate: &Candidate| {
+            round == 0 && punched.contains(&(peer.to_owned(), candidate.addr))
+        };
+        let spray = probing
+            .iter()
+            .filter(|(peer, c)| !was_punched(peer, c))
+            .flat_map(|(_, c)| c.spray.iter().copied())
+            .collect::<Vec<_>>();
+        if !spray.is_empty() {
+            log::debug!("spraying {} predicted ports", spray.len());
+            if let Err(e) = birthday::open_towards(iface.as_str_lossy().as_ref(), &spray) {
+                log::warn!("could not spray predicted ports: {e}");
+            }
+        }
+        for (peer, candidate) in &probing {
+            // a second handshake would replace the one the peer is answering
+            if was_punched(peer, candidate) {
+                continue;
+            }
+            log::debug!(
+                "probing {} @{} ({:?})",
+                peer.to_base64(),
+                candidate.addr,
+                candidate.kind
+            );
+            // WireGuard only sends a handshake for traffic or a keepalive
+            punch(iface, peer_tracker, peer, candidate.addr)?;
+        }
+        let deadline = Instant::now() + PROBE_TIMEOUT;
+        let mut round_settled = vec![];
+        while Instant::now() < deadline && round_settled.len() < probing.len() {
+            if wait(Duration::from_millis(500)) {
+                log::debug!("probing interrupted");
+                interrupted = true;
+                break;
+            }
+            let handshakes = get_last_handshakes(iface)?;
+            for (peer, candidate) in &probing {
+                let since = match was_punched(peer, candidate) {
+                    true => punched_since,
+                    false => started,
                 };
-                let sa = SocketAddr::new(ip_to_use, wg_port);
-                log::debug!("wireplug.org: {peer} is @{:?}", &sa);
-                if update_peer(&iface, peer_tracker, &peer_pubkey, sa)? {
-                    peers_updated.push(peer_pubkey);
+                if !round_settled.iter().any(|(p, _)| p == peer)
+                    && handshakes
+                        .get(peer)
+                        .copied()
+                        .flatten()
+                        .is_some_and(|t| t > since)
+                {
+                    log::info!("{} is reachable @{}", peer.to_base64(), candidate.addr);
+                    if candidate.kind != CandidateKind::Ipv6 && ipv6_timed_out.contains(peer) {
+                        log::info!(
+                            "IPv6 path to {} is filtered, falling back to IPv4",
+                            peer.to_base64()
+                        );
+                    }
+                    round_settled.push((peer.to_owned(), candidate.kind));
                 }
             }
-            protocol::WireplugEndpoint::Relay { id: _id, port } => {
-                let relay = (shared::WIREPLUG_ORG_RELAY, port)
-                    .to_socket_addrs()?
-                    .next()
-                    .expect("could not resolve relay address");
-                log::debug!("wireplug.org: {peer} is relayed by wireplug.org");
-                if update_peer(&iface, peer_tracker, &peer_pubkey, relay)? {
-                    peers_updated.push(peer_pubkey);
-                }
+        }
+        for (peer, c) in &probing {
+            if round_settled.iter().any(|(p, _)| p == peer) {
+                peer_tracker.failed_probes.remove(peer);
+                continue;
+            }
+            // an interrupted round says nothing about its candidates
+            if interrupted {
+                continue;
+            }
+            if c.kind == CandidateKind::Ipv6 {
+                ipv6_timed_out.push(peer.to_owned());
             }
+            peer_tracker
+                .failed_probes
+                .entry(peer.to_owned())
+                .or_default()
+                .push(c.addr);
+        }
+        candidates.retain(|(peer, _)| !round_settled.iter().any(|(p, _)| p == peer));
+        settled.extend(round_settled);
+    }
+    // nothing worked, leave the best candidate for WireGuard to keep trying
+    for (peer, c) in candidates {
+        // once all of them failed, the next announcement starts over
+        if peer_tracker
+            .failed_probes
+            .get(&peer)
+            .is_some_and(|failed| c.iter().all(|c| failed.contains(&c.addr)))
+        {
+            peer_tracker.failed_probes.remove(&peer);
+        }
+        if let Some(best) = c.first() {
+            update_peer(iface, peer_tracker, &peer, best.addr)?;
+        }
+    }
+    Ok(settled)
+}
+
+// Takes every peer's endpoint and the time of its rendezvous, if one was arranged.
+// Returns the peers that need no more probing, with the kind of candidate that
+// completed a handshake. Peers that still had a session were not probed.
+// Waiting for a rendezvous goes through `wait` like probing does.
+pub(crate) fn update_peers(
+    if_name: &str,
+    peer_tracker: &mut PeerTracker,
+    new_endpoints: HashMap<String, (protocol::WireplugEndpoint, Option<Instant>)>,
+    local_has_ipv6: bool,
+    servers: &Servers,
+    rankings: &HashMap<Key, Vec<CandidateKind>>,
+    wait: &mut dyn FnMut(Duration) -> bool,
+) -> Result<Vec<(Key, Option<CandidateKind>)>, std::io::Error> {
+    let iface = if_name.parse()?;
+    let sessions = get_sessions(&iface)?;
+    let now = SystemTime::now();
+    let mut settled = vec![];
+    let mut candidates = vec![];
+    let mut punches = vec![];
+    for (peer, (peer_endpoint, rendezvous)) in new_endpoints {
+        let Ok(peer_pubkey) = Key::from_base64(&peer) else {
+            log::error!("bad peer pubkey");
+            continue;
+        };
+        let mut peer_candidates = gather_candidates(
+            if_name,
+            peer_tracker,
+            &peer_pubkey,
+            peer_endpoint,
+            local_has_ipv6,
+            servers,
+        );
+        if peer_candidates.is_empty() {
+            continue;
+        }
+        if has_session(&sessions, &peer_pubkey, &peer_candidates, now) {
+            log::debug!("{peer} still has a session, not probing");
+            settled.push((peer_pubkey, None));
+            continue;
+        }
+        // what worked on this network before goes first
+        if let Some(ranking) = rankings.get(&peer_pubkey) {
+            peer_candidates.sort_by_key(|c| {
+                ranking
+                    .iter()
+                    .position(|k| *k == c.kind)
+                    .unwrap_or(ranking.len())
+            });
+        }
+        if let Some(failed) = peer_tracker.failed_probes.get(&peer_pubkey) {
+            peer_candidates.sort_by_key(|c| failed.contains(&c.addr));
+        }
+        log::debug!("wireplug.org: {peer} candidates: {:?}", &peer_candidates);
+        if let Some(at) = rendezvous
+            && at.saturating_duration_since(Instant::now()) < RENDEZVOUS_MAX_WAIT
+        {
+            punches.push((at, peer_pubkey.clone(), peer_candidates[0].addr));
+        }
+        candidates.push((peer_pubkey, peer_candidates));
+    }
+    // the peer sends its handshake at the same moment, so both NATs or IPv6 firewalls let them in
+    punches.sort_by_key(|(at, _, _)| *at);
+    let punched_since = SystemTime::now();
+    let mut punched = vec![];
+    for (at, peer, endpoint) in punches {
+        log::debug!("rendezvous with {} @{endpoint}", peer.to_base64());
+        // the peers left are probed after the next announcement
+        if wait(at.saturating_duration_since(Instant::now())) {
+            log::debug!("rendezvous interrupted");
+            return Ok(settled);
+        }
+        punch(&iface, peer_tracker, &peer, endpoint)?;
+        punched.push((peer, endpoint));
+    }
+    let probed = probe_candidates(
+        &iface,
+        peer_tracker,
+        candidates,
+        &punched,
+        punched_since,
+        wait,
+    )?;
+    settled.extend(probed.into_iter().map(|(peer, kind)| (peer, Some(kind))));
+    Ok(settled)
+}
+
+// Point peers the coordination server couldn't help with back at their
+// configured static endpoints. DNS is resolved again on every attempt.
+pub(crate) fn apply_fallback_endpoints(
+    if_name: &str,
+    peer_tracker: &mut PeerTracker,
+    peers: &[Key],
+) -> Result<Vec<Key>, std::io::Error> {
+    let iface = if_name.parse()?;
+    let mut peers_updated = vec![];
+    for peer in peers {
+        let Some(endpoint) = peer_tracker.fallback_endpoints.get(peer) else {
+            continue;
+        };
+        let Some(sa) = resolve_endpoint(endpoint) else {
+            log::warn!("could not resolve fallback endpoint {endpoint}");
+            continue;
+        };
+        log::debug!("{} falls back to static endpoint @{sa:?}", peer.to_base64());
+        if update_peer(&iface, peer_tracker, peer, sa)? {
+            peers_updated.push(peer.to_owned());
         }
     }
     Ok(peers_updated)
 }
 
+// Endpoints of peers that completed a handshake recently
+pub(crate) fn get_working_endpoints(
+    if_name: &str,
+) -> Result<Vec<(Key, SocketAddr)>, std::io::Error> {
+    let iface = if_name.parse()?;
+    let device = Device::get(&iface, Backend::default())?;
+    let now = SystemTime::now();
+    Ok(device
+        .peers
+        .iter()
+        .filter(|p| {
+            p.stats
+                .last_handshake_time
+                .and_then(|t| now.duration_since(t).ok())
+                .is_some_and(|age| age <= Duration::from_secs(LAST_HANDSHAKE_MAX))
+        })
+        .filter_map(|p| Some((p.config.public_key.to_owned(), p.config.endpoint?)))
+        .collect())
+}
+
+// Points peers at endpoints that worked before, skipping peers that are no
+// longer configured
+pub(crate) fn restore_endpoints(
+    if_name: &str,
+    peer_tracker: &mut PeerTracker,
+    endpoints: &HashMap<Key, SocketAddr>,
+) -> Result<Vec<Key>, std::io::Error> {
+    let iface = if_name.parse()?;
+    let mut peers_updated = vec![];
+    for peer in get_all_peers(if_name)? {
+        let Some(endpoint) = endpoints.get(&peer) else {
+            continue;
+        };
+        log::debug!(
+            "{} restored to last known endpoint @{endpoint}",
+            peer.to_base64()
+        );
+        if update_peer(&iface, peer_tracker, &peer, *endpoint)? {
+            peers_updated.push(peer);
+        }
+    }
+    Ok(peers_updated)
+}
+
+pub(crate) fn get_listen_port(ifname: &str) -> Result<Option<u16>, std::io::Error> {
+    let iface: InterfaceName = ifname.parse()?;
+    Ok(Device::get(&iface, Backend::default())?.listen_port)
+}
+
 pub(crate) fn update_port(ifname: &str, new_port: u16) -> Result<(), std::io::Error> {
     let iface: InterfaceName = ifname.parse()?;
     let update = DeviceUpdate::new().set_listen_port(new_port);
@@ -388,48 +1167,28 @@ pub(crate) fn init_peers_activity(
     log::trace!("init_peers_activity()");
     let iface = if_name.parse()?;
     let device = Device::get(&iface, Backend::default())?;
-    device.peers.iter().for_each(|p| {
-        let _ = peer_tracker.update(p);
-    });
+    device.peers.iter().for_each(|p| peer_tracker.track(p));
     Ok(())
 }
 
-#[allow(dead_code)]
-pub(crate) fn get_inactive_peers_by_last_handshake(if_name: &str) -> anyhow::Result<Vec<Key>> {
-    log::trace!("get_inactive_peers_by_handshake()");
-    let iface = if_name.parse()?;
-    let device = Device::get(&iface, Backend::default())?;
-    log::trace!("{if_name} has {} peers", device.peers.len());
-    let now = SystemTime::now();
-    Ok(device
-        .peers
-        .iter()
-        .filter(|p| match p.stats.last_handshake_time {
-            Some(last_handshake) => match now.duration_since(last_handshake) {
-                Ok(duration) => duration > Duration::from_secs(LAST_HANDSHAKE_MAX),
-                Err(e) => {
-                    log::warn!("failed to get duration since last handshake ({e})");
-                    true
-                }
-            },
-            None => true,
-        })
-        .map(|p| p.config.public_key.to_owned())
-        .collect::<Vec<_>>())
-}
-
-pub(crate) fn get_inactive_peers_by_rx(
+// Classifies every peer and returns the ones to announce again
+pub(crate) fn get_peers_to_announce(
     if_name: &str,
     peer_tracker: &mut PeerTracker,
+    thresholds: &LivenessThresholds,
 ) -> Result<Vec<Key>, std::io::Error> {
-    log::trace!("get_inactive_peers_by_txrx()");
+    log::trace!("get_peers_to_announce()");
     let iface = if_name.parse()?;
     let device = Device::get(&iface, Backend::default())?;
     log::trace!("{if_name} has {} peers", device.peers.len());
+    let now = Instant::now();
     Ok(device
         .peers
         .iter()
-        .filter(|p| p.stats.last_handshake_time.is_none() || !peer_tracker.update(p))
+        .filter(|p| {
+            let liveness = peer_tracker.check(p, thresholds);
+            peer_tracker.needs_announcing(&p.config.public_key, liveness, now)
+        })
         .map(|p| p.config.public_key.to_owned())
         .collect::<Vec<_>>())
 }
@@ -445,3 +1204,125 @@ pub(crate) fn get_all_peers(if_name: &str) -> Result<Vec<Key>, std::io::Error> {
         .map(|p| p.config.public_key.to_owned())
         .collect::<Vec<_>>())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLDS: LivenessThresholds = LivenessThresholds {
+        stale_after: Duration::from_secs(HANDSHAKE_STALE_AFTER),
+        unreachable_after: Duration::from_secs(LAST_HANDSHAKE_MAX),
+        missed_keepalives: MISSED_KEEPALIVES,
+    };
+
+    fn observe(handshake_age: Option<u64>, rx_grew: bool, silent_for: u64) -> Observation {
+        Observation {
+            handshake_age: handshake_age.map(Duration::from_secs),
+            rx_grew,
+            tx_grew: true,
+            silent_for: Duration::from_secs(silent_for),
+            keepalive: Some(COMMON_PKA),
+        }
+    }
+
+    #[test]
+    fn classifies_peers() {
+        let c = |o: Observation| classify(&o, &THRESHOLDS);
+        assert_eq!(c(observe(None, false, 0)), PeerLiveness::Unreachable);
+        assert_eq!(c(observe(Some(30), true, 0)), PeerLiveness::Connected);
+        // a quiet 25s check interval is fine while keepalives are not overdue
+        assert_eq!(c(observe(Some(30), false, 30)), PeerLiveness::Connected);
+        assert_eq!(c(observe(Some(100), false, 100)), PeerLiveness::Stale);
+        // the overdue handshake counts even though bytes still arrive
+        assert_eq!(c(observe(Some(150), true, 0)), PeerLiveness::Stale);
+        assert_eq!(c(observe(Some(200), true, 0)), PeerLiveness::Unreachable);
+    }
+
+    #[test]
+    fn quiet_links_stay_connected() {
+        let quiet = Observation {
+            handshake_age: Some(Duration::from_secs(3600)),
+            rx_grew: false,
+            tx_grew: false,
+            silent_for: Duration::from_secs(3600),
+            keepalive: None,
+        };
+        assert_eq!(classify(&quiet, &THRESHOLDS), PeerLiveness::Connected);
+        let turned_off = Observation {
+            keepalive: keepalive_interval(Some(0)),
+            ..quiet
+        };
+        assert_eq!(classify(&turned_off, &THRESHOLDS), PeerLiveness::Connected);
+        let sending = Observation {
+            tx_grew: true,
+            ..quiet
+        };
+        assert_eq!(classify(&sending, &THRESHOLDS), PeerLiveness::Unreachable);
+    }
+
+    #[test]
+    fn stale_peers_are_announced_less_often() {
+        let mut tracker = PeerTracker::new(HashMap::new());
+        let peer = Key::generate_private().get_public();
+        tracker
+            .peers
+            .insert(peer.clone(), WireplugPeerInfo::new(None, 0, 0));
+        let now = Instant::now();
+        let later = |secs| now + Duration::from_secs(secs);
+        assert!(tracker.needs_announcing(&peer, PeerLiveness::Stale, now));
+        assert!(!tracker.needs_announcing(&peer, PeerLiveness::Stale, later(25)));
+        assert!(tracker.needs_announcing(&peer, PeerLiveness::Unreachable, later(50)));
+        assert!(tracker.needs_announcing(&peer, PeerLiveness::Stale, later(120)));
+        // once connected again, the next stale check is announced right away
+        assert!(!tracker.needs_announcing(&peer, PeerLiveness::Connected, later(145)));
+        assert!(tracker.needs_announcing(&peer, PeerLiveness::Stale, later(170)));
+    }
+
+    #[test]
+    fn peers_with_a_session_are_not_probed() {
+        let now = SystemTime::now();
+        let endpoint: SocketAddr = "198.51.100.7:51820".parse().unwrap();
+        let candidates = [Candidate {
+            kind: CandidateKind::Ipv4,
+            addr: endpoint,
+            spray: vec![],
+        }];
+        let fresh = Key::generate_private().get_public();
+        let expiring = Key::generate_private().get_public();
+        let never = Key::generate_private().get_public();
+        let sessions = HashMap::from([
+            (
+                fresh.clone(),
+                (Some(now - Duration::from_secs(30)), Some(endpoint)),
+            ),
+            (
+                expiring.clone(),
+                (Some(now - Duration::from_secs(170)), Some(endpoint)),
+            ),
+            (never.clone(), (None, Some(endpoint))),
+        ]);
+        assert!(has_session(&sessions, &fresh, &candidates, now));
+        assert!(!has_session(&sessions, &expiring, &candidates, now));
+        assert!(!has_session(&sessions, &never, &candidates, now));
+        let unknown = Key::generate_private().get_public();
+        assert!(!has_session(&sessions, &unknown, &candidates, now));
+    }
+
+    #[test]
+    fn peers_are_probed_after_a_network_change() {
+        let now = SystemTime::now();
+        let peer = Key::generate_private().get_public();
+        // the session still runs over the peer's address on the old LAN
+        let old_lan: SocketAddr = "192.168.1.20:51820".parse().unwrap();
+        let sessions = HashMap::from([(
+            peer.clone(),
+            (Some(now - Duration::from_secs(30)), Some(old_lan)),
+        )]);
+        let candidates = [Candidate {
+            kind: CandidateKind::Ipv4,
+            addr: "198.51.100.7:51820".parse().unwrap(),
+            spray: vec![],
+        }];
+        assert!(!has_session(&sessions, &peer, &candidates, now));
+    }
+}
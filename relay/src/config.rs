@@ -0,0 +1,27 @@
+use serde::Deserialize;
+use std::io::{self, Error};
+
+static CONFIG_PATH: &str = "/etc/wprelay.conf";
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct Config {
+    // coordination servers list the matching public key in their RelayKeys
+    pub private_key: String,
+    // e.g. ["wp.example.org:443"]
+    pub coordinators: Vec<String>,
+    pub relay_listen_on: String,
+    // e.g. "50000-59999"
+    pub relay_ports: Option<String>,
+    // seconds
+    pub relay_idle_timeout: Option<u64>,
+    // where peers reach the relay, if not the address it connects from
+    pub public_ip: Option<String>,
+    // trust a private CA instead of the web PKI
+    pub ca_cert: Option<String>,
+}
+
+pub(crate) fn read_from_file() -> io::Result<Config> {
+    let config = std::fs::read_to_string(CONFIG_PATH)?;
+    toml::from_str(&config).map_err(|e| Error::other(format!("Config file parsing error: {e}")))
+}
@@ -0,0 +1,591 @@
+use rand::Rng;
+use shared::protocol::decode_wgkey;
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+use tokio::{net::UdpSocket, sync::RwLock, task::JoinHandle};
+
+mod session;
+
+const DEFAULT_PORTS: RangeInclusive<u16> = 50000..=59999;
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+
+pub struct RelayConfig {
+    listen_on: IpAddr,
+    ports: RangeInclusive<u16>,
+    idle_timeout: Duration,
+}
+
+impl RelayConfig {
+    // `ports` is a range like "50000-59999", `idle_timeout` in seconds
+    pub fn new(
+        listen_on: &str,
+        ports: Option<&str>,
+        idle_timeout: Option<u64>,
+    ) -> anyhow::Result<Self> {
+        let ports = match ports {
+            Some(ports) => {
+                let (first, last) = ports
+                    .split_once('-')
+                    .ok_or(anyhow::Error::msg(format!("bad RelayPorts: {ports}")))?;
+                first.trim().parse()?..=last.trim().parse()?
+            }
+            None => DEFAULT_PORTS,
+        };
+        if ports.is_empty() {
+            return Err(anyhow::Error::msg("RelayPorts is empty"));
+        }
+        Ok(Self {
+            listen_on: listen_on.parse()?,
+            ports,
+            idle_timeout: idle_timeout.map_or(DEFAULT_IDLE_TIMEOUT, Duration::from_secs),
+        })
+    }
+}
+
+pub struct ProtoRelay {
+    a_ip: IpAddr,
+    pub relay_port: u16,
+}
+
+pub struct PendingRelay {
+    pub a_ip: IpAddr,
+    pub a_oport: u16,
+    pub relay_port: u16,
+}
+
+impl PendingRelay {
+    pub fn new(a_ip: IpAddr, a_oport: u16, relay_port: u16) -> Self {
+        Self {
+            a_ip,
+            a_oport,
+            relay_port,
+        }
+    }
+}
+
+pub struct EstablishedRelay {
+    a_ip: IpAddr,
+    pub a_oport: u16,
+    b_ip: IpAddr,
+    b_oport: u16,
+    relay_ip: IpAddr,
+    pub relay_port: u16,
+}
+
+impl EstablishedRelay {
+    pub fn new(
+        a_ip: IpAddr,
+        a_oport: u16,
+        b_ip: IpAddr,
+        b_oport: u16,
+        relay_ip: IpAddr,
+        relay_port: u16,
+    ) -> Self {
+        Self {
+            a_ip,
+            a_oport,
+            b_ip,
+            b_oport,
+            relay_ip,
+            relay_port,
+        }
+    }
+
+    fn a_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.a_ip, self.a_oport)
+    }
+
+    fn b_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.b_ip, self.b_oport)
+    }
+}
+
+pub trait WriteTo {
+    fn write_to<Write: std::io::Write>(&self, writer: &mut Write) -> std::io::Result<()>;
+}
+
+pub enum RelayKind {
+    Proto(u16),
+    Pending(u16),
+    Established(u16),
+}
+
+#[derive(PartialEq, PartialOrd, Eq, Clone, Hash)]
+pub struct Key(pub [u8; 32]);
+#[derive(PartialEq, PartialOrd, Eq, Clone, Hash)]
+pub struct NormalizedKey(pub [u8; 64]);
+
+fn get_normalized(a: &str, b: &str) -> NormalizedKey {
+    let a = decode_wgkey(a).unwrap_or_default();
+    let b = decode_wgkey(b).unwrap_or_default();
+    let mut out = [0u8; 64];
+    if a <= b {
+        out[..32].copy_from_slice(a.as_slice());
+        out[32..].copy_from_slice(b.as_slice());
+    } else {
+        out[..32].copy_from_slice(b.as_slice());
+        out[32..].copy_from_slice(a.as_slice());
+    }
+    NormalizedKey(out)
+}
+
+pub struct RelayManager {
+    config: Option<RelayConfig>,
+    proto: HashMap<(String, String), ProtoRelay>,
+    pending: HashMap<(String, String), PendingRelay>,
+    established: HashMap<NormalizedKey, EstablishedRelay>,
+    // the forwarding task of every relay port in use
+    sessions: HashMap<u16, JoinHandle<()>>,
+    this: Weak<RwLock<RelayManager>>,
+}
+
+pub type SharedRelayManager = Arc<RwLock<RelayManager>>;
+
+impl RelayManager {
+    pub fn new_shared(config: Option<RelayConfig>) -> SharedRelayManager {
+        Arc::new_cyclic(|this| {
+            RwLock::new(Self {
+                config,
+                proto: HashMap::new(),
+                pending: HashMap::new(),
+                established: HashMap::new(),
+                sessions: HashMap::new(),
+                this: this.clone(),
+            })
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.is_some()
+    }
+
+    // relay ports, in use or not
+    pub fn capacity(&self) -> usize {
+        self.config.as_ref().map_or(0, |c| c.ports.len())
+    }
+
+    // relay ports in use
+    pub fn load(&self) -> usize {
+        self.sessions.len()
+    }
+
+    // None if relaying is disabled or no relay port could be set up
+    pub fn get_relay_port(
+        &mut self,
+        peer_a: &String,
+        peer_b: &String,
+        announcing_ip: IpAddr,
+    ) -> Option<RelayKind> {
+        self.config.as_ref()?;
+        let normalized_key = get_normalized(peer_a, peer_b);
+        if let Some(relay) = self.established.get(&normalized_key) {
+            if relay.a_ip == announcing_ip || relay.b_ip == announcing_ip {
+                return Some(RelayKind::Established(relay.relay_port));
+            }
+            // a peer moved, start over
+            self.remove_for_pair(peer_a, peer_b);
+        }
+        let pair = (peer_a.to_string(), peer_b.to_string());
+        if let Some(relay) = self.pending.get(&pair) {
+            if relay.a_ip == announcing_ip {
+                return Some(RelayKind::Pending(relay.relay_port));
+            }
+            // the source port has to be learned again
+            let relay_port = relay.relay_port;
+            self.pending.remove(&pair);
+            self.proto.insert(
+                pair,
+                ProtoRelay {
+                    a_ip: announcing_ip,
+                    relay_port,
+                },
+            );
+            return Some(RelayKind::Proto(relay_port));
+        }
+        if let Some(relay) = self.proto.get_mut(&pair) {
+            relay.a_ip = announcing_ip;
+            return Some(RelayKind::Proto(relay.relay_port));
+        }
+        // the non-announcing peer is already waiting so use the same port
+        let reverse = (peer_b.to_string(), peer_a.to_string());
+        let waiting_port = self
+            .pending
+            .get(&reverse)
+            .map(|r| r.relay_port)
+            .or(self.proto.get(&reverse).map(|r| r.relay_port));
+        let port = match waiting_port {
+            Some(port) => port,
+            None => match self.start_session() {
+                Ok(port) => port,
+                Err(e) => {
+                    log::error!("failed to set up relay: {e}");
+                    return None;
+                }
+            },
+        };
+        self.proto.insert(
+            pair,
+            ProtoRelay {
+                a_ip: announcing_ip,
+                relay_port: port,
+            },
+        );
+        Some(RelayKind::Proto(port))
+    }
+
+    // whether either peer of the pair was given a port
+    pub fn has_pair(&self, peer_a: &String, peer_b: &String) -> bool {
+        let pairs = [
+            (peer_a.to_string(), peer_b.to_string()),
+            (peer_b.to_string(), peer_a.to_string()),
+        ];
+        self.established
+            .contains_key(&get_normalized(peer_a, peer_b))
+            || pairs
+                .iter()
+                .any(|pair| self.proto.contains_key(pair) || self.pending.contains_key(pair))
+    }
+
+    fn start_session(&mut self) -> std::io::Result<u16> {
+        let Some(config) = &self.config else {
+            return Err(std::io::Error::other("relaying is disabled"));
+        };
+        let (port, socket) = bind_free_random_port(config, &self.sessions)?;
+        let session = session::run(socket, port, config.idle_timeout, self.this.clone());
+        self.sessions.insert(port, tokio::spawn(session));
+        Ok(port)
+    }
+
+    // Called for packets from unknown sources on a relay port. Learns the
+    // sender's source port and returns both sides once they are known.
+    pub(crate) fn learn(
+        &mut self,
+        relay_port: u16,
+        from: SocketAddr,
+    ) -> Option<(SocketAddr, SocketAddr)> {
+        if let Some(relay) = self
+            .established
+            .values_mut()
+            .find(|r| r.relay_port == relay_port)
+        {
+            // the peer's NAT picked a new source port, the session only
+            // lets a handshake initiation through for that
+            if from.ip() == relay.a_ip && from.ip() != relay.b_ip {
+                relay.a_oport = from.port();
+            } else if from.ip() == relay.b_ip && from.ip() != relay.a_ip {
+                relay.b_oport = from.port();
+            }
+            return Some((relay.a_addr(), relay.b_addr()));
+        }
+
+        let learned = self
+            .proto
+            .iter()
+            .find(|(_, r)| r.relay_port == relay_port && r.a_ip == from.ip())
+            .map(|(pair, _)| pair.clone());
+        if let Some(pair) = learned
+            && let Some(proto) = self.proto.remove(&pair)
+        {
+            log::debug!("relay port {relay_port}: {} is @{from}", pair.0);
+            self.pending.insert(
+                pair,
+                PendingRelay::new(proto.a_ip, from.port(), proto.relay_port),
+            );
+        } else if let Some(relay) = self
+            .pending
+            .values_mut()
+            .find(|r| r.relay_port == relay_port && r.a_ip == from.ip())
+        {
+            relay.a_oport = from.port();
+        }
+
+        let (a, b) = self
+            .pending
+            .iter()
+            .find(|((a, b), r)| {
+                r.relay_port == relay_port
+                    && self
+                        .pending
+                        .get(&(b.clone(), a.clone()))
+                        .is_some_and(|r| r.relay_port == relay_port)
+            })
+            .map(|(pair, _)| pair.clone())?;
+        let a_side = self.pending.remove(&(a.clone(), b.clone()))?;
+        let b_side = self.pending.remove(&(b.clone(), a.clone()))?;
+        let relay_ip = self.config.as_ref()?.listen_on;
+        let relay = EstablishedRelay::new(
+            a_side.a_ip,
+            a_side.a_oport,
+            b_side.a_ip,
+            b_side.a_oport,
+            relay_ip,
+            relay_port,
+        );
+        let peers = (relay.a_addr(), relay.b_addr());
+        log::info!(
+            "relay established on port {relay_port}: {} <=> {}",
+            peers.0,
+            peers.1
+        );
+        self.established.insert(get_normalized(&a, &b), relay);
+        Some(peers)
+    }
+
+    pub fn remove_for_pair(&mut self, peer_a: &String, peer_b: &String) {
+        let mut ports = vec![];
+        for pair in [
+            (peer_a.to_string(), peer_b.to_string()),
+            (peer_b.to_string(), peer_a.to_string()),
+        ] {
+            ports.extend(self.proto.remove(&pair).map(|r| r.relay_port));
+            ports.extend(self.pending.remove(&pair).map(|r| r.relay_port));
+        }
+        ports.extend(
+            self.established
+                .remove(&get_normalized(peer_a, peer_b))
+                .map(|r| r.relay_port),
+        );
+        for port in ports {
+            if let Some(session) = self.sessions.remove(&port) {
+                log::debug!("closing relay port {port}");
+                session.abort();
+            }
+        }
+    }
+
+    // Called by a session that timed out
+    pub(crate) fn remove_for_port(&mut self, relay_port: u16) {
+        self.proto.retain(|_, r| r.relay_port != relay_port);
+        self.pending.retain(|_, r| r.relay_port != relay_port);
+        self.established.retain(|_, r| r.relay_port != relay_port);
+        self.sessions.remove(&relay_port);
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::fmt::Result {
+        for ((a, b), r) in &self.proto {
+            let ip = r.a_ip.to_string();
+            let port = r.relay_port;
+            writeln!(writer, "{a} => {b} waiting for {ip} on port:{port} (proto)")?;
+        }
+        for ((a, b), r) in &self.pending {
+            let ip = r.a_ip.to_string();
+            let port = r.a_oport;
+            writeln!(writer, "{a} => {b} {ip}:{port} (pending)")?;
+        }
+        for r in self.established.values() {
+            writeln!(
+                writer,
+                "{}:{} <=> {}:{} on port:{} (established)",
+                r.a_ip, r.a_oport, r.b_ip, r.b_oport, r.relay_port
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/*
+/////////////////////////////////////////////////////////////////////////////////////////////
+*/
+
+impl WriteTo for EstablishedRelay {
+    fn write_to<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let rdr_to_1 = format!(
+            //"pass in proto udp from {} port {} to {} port {} rdr-to {}\n",
+            //self.a_ip, self.a_oport, self.relay_ip, self.b_oport, self.b_ip
+            "pass in proto udp from {} to {} port {} rdr-to {}\n",
+            self.a_ip, self.relay_ip, self.b_oport, self.b_ip
+        );
+        let nat_to_1 = format!(
+            "pass out proto udp from {} to {} nat-to {} static-port\n",
+            self.a_ip, self.b_ip, self.relay_ip
+        );
+        let rdr_to_2 = format!(
+            "pass in proto udp from {} port {} to {} port {} rdr-to {}\n",
+            self.b_ip, self.b_oport, self.relay_ip, self.a_oport, self.a_ip
+        );
+        let nat_to_2 = format!(
+            "pass out proto udp from {} to {} nat-to {} static-port\n",
+            self.b_ip, self.a_ip, self.relay_ip
+        );
+        writer.write_all(rdr_to_1.as_bytes())?;
+        writer.write_all(nat_to_1.as_bytes())?;
+        writer.write_all(rdr_to_2.as_bytes())?;
+        writer.write_all(nat_to_2.as_bytes())?;
+
+        Ok(())
+    }
+}
+
+// Tries the pool starting at a random port, skipping the ones in use
+fn bind_free_random_port(
+    config: &RelayConfig,
+    in_use: &HashMap<u16, JoinHandle<()>>,
+) -> std::io::Result<(u16, UdpSocket)> {
+    let first = *config.ports.start() as u32;
+    let count = *config.ports.end() as u32 - first + 1;
+    let offset = rand::rng().random_range(0..count);
+    for i in 0..count {
+        let port = (first + (offset + i) % count) as u16;
+        if in_use.contains_key(&port) {
+            continue;
+        }
+        match std::net::UdpSocket::bind((config.listen_on, port)) {
+            Ok(socket) => {
+                socket.set_nonblocking(true)?;
+                return Ok((port, UdpSocket::from_std(socket)?));
+            }
+            Err(e) if e.kind() == ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(std::io::Error::other("no free relay ports"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::timeout;
+
+    const PEER_A: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+    const PEER_B: &str = "BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBA=";
+
+    fn test_config(idle_timeout: Duration) -> Option<RelayConfig> {
+        Some(RelayConfig {
+            listen_on: "127.0.0.1".parse().unwrap(),
+            ports: 42000..=42999,
+            idle_timeout,
+        })
+    }
+
+    fn wg_packet(message_type: u8) -> Vec<u8> {
+        let mut packet = vec![0u8; 32];
+        packet[0] = message_type;
+        packet
+    }
+
+    #[tokio::test]
+    async fn relays_between_peers() {
+        let manager = RelayManager::new_shared(test_config(Duration::from_secs(5)));
+        let (a, b) = (PEER_A.to_owned(), PEER_B.to_owned());
+        let ip = "127.0.0.1".parse().unwrap();
+        let Some(RelayKind::Proto(port)) = manager.write().await.get_relay_port(&a, &b, ip) else {
+            panic!("no relay port");
+        };
+        let Some(RelayKind::Proto(port_b)) = manager.write().await.get_relay_port(&b, &a, ip)
+        else {
+            panic!("no relay port");
+        };
+        assert_eq!(port, port_b);
+
+        let relay = SocketAddr::new(ip, port);
+        let peer_a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        // not WireGuard, ignored
+        peer_a.send_to(b"hello", relay).await.unwrap();
+        peer_a.send_to(&wg_packet(1), relay).await.unwrap();
+        peer_b.send_to(&wg_packet(2), relay).await.unwrap();
+
+        let mut buf = [0u8; 64];
+        let (n, from) = timeout(Duration::from_secs(1), peer_a.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((&buf[..n], from), (&wg_packet(2)[..], relay));
+
+        peer_a.send_to(&wg_packet(4), relay).await.unwrap();
+        let (n, from) = timeout(Duration::from_secs(1), peer_b.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((&buf[..n], from), (&wg_packet(4)[..], relay));
+
+        assert!(matches!(
+            manager.write().await.get_relay_port(&a, &b, ip),
+            Some(RelayKind::Established(p)) if p == port
+        ));
+        manager.write().await.remove_for_pair(&a, &b);
+        assert!(manager.read().await.sessions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn same_ip_cannot_take_over() {
+        let manager = RelayManager::new_shared(test_config(Duration::from_secs(5)));
+        let (a, b) = (PEER_A.to_owned(), PEER_B.to_owned());
+        let (ip_a, ip_b) = ("127.0.0.2".parse().unwrap(), "127.0.0.1".parse().unwrap());
+        let Some(RelayKind::Proto(port)) = manager.write().await.get_relay_port(&a, &b, ip_a)
+        else {
+            panic!("no relay port");
+        };
+        manager.write().await.get_relay_port(&b, &a, ip_b).unwrap();
+
+        let relay = SocketAddr::new(ip_b, port);
+        let peer_a = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+        let peer_b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let spoofer = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+        peer_a.send_to(&wg_packet(1), relay).await.unwrap();
+        peer_b.send_to(&wg_packet(2), relay).await.unwrap();
+        let mut buf = [0u8; 64];
+        timeout(Duration::from_secs(1), peer_a.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+
+        // same IP as a, another port, while a is in use
+        spoofer.send_to(&wg_packet(4), relay).await.unwrap();
+        spoofer.send_to(&wg_packet(1), relay).await.unwrap();
+        assert!(
+            timeout(Duration::from_millis(200), peer_b.recv_from(&mut buf))
+                .await
+                .is_err()
+        );
+        peer_b.send_to(&wg_packet(4), relay).await.unwrap();
+        let (n, _) = timeout(Duration::from_secs(1), peer_a.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..n], &wg_packet(4)[..]);
+        assert!(
+            timeout(Duration::from_millis(200), spoofer.recv_from(&mut buf))
+                .await
+                .is_err()
+        );
+        manager.write().await.remove_for_pair(&a, &b);
+    }
+
+    #[tokio::test]
+    async fn idle_relay_is_torn_down() {
+        let manager = RelayManager::new_shared(test_config(Duration::from_millis(100)));
+        let ip = "127.0.0.1".parse().unwrap();
+        assert!(
+            manager
+                .write()
+                .await
+                .get_relay_port(&PEER_A.to_owned(), &PEER_B.to_owned(), ip)
+                .is_some()
+        );
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let manager = manager.read().await;
+        assert!(manager.sessions.is_empty());
+        assert!(manager.proto.is_empty());
+    }
+
+    #[tokio::test]
+    async fn disabled_without_config() {
+        let manager = RelayManager::new_shared(None);
+        let ip = "127.0.0.1".parse().unwrap();
+        assert!(
+            manager
+                .write()
+                .await
+                .get_relay_port(&PEER_A.to_owned(), &PEER_B.to_owned(), ip)
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,102 @@
+use clap::Parser;
+use std::sync::Arc;
+use tokio_rustls::{
+    TlsConnector,
+    rustls::{
+        self, RootCertStore,
+        pki_types::{CertificateDer, pem::PemObject},
+    },
+};
+
+use shared::{TmpLogger, protocol};
+
+pub mod config;
+pub mod register;
+
+#[derive(Parser)]
+#[command(version, name="wprelay", about="", long_about = None)]
+struct Cli {
+    #[arg(short, long, help = "do not daemonize")]
+    debug: bool,
+}
+
+static LOGGER: TmpLogger = TmpLogger;
+
+fn get_tls_connector(ca_cert: Option<&str>) -> anyhow::Result<TlsConnector> {
+    let roots = match ca_cert {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(path)? {
+                roots.add(cert?)?;
+            }
+            roots
+        }
+        None => RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        },
+    };
+    let mut tls_config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    tls_config.alpn_protocols = vec![protocol::WIREPLUG_RELAY_REGISTRATION_ALPN.to_vec()];
+    Ok(TlsConnector::from(Arc::new(tls_config)))
+}
+
+async fn start(_cli: Cli) -> anyhow::Result<()> {
+    log::set_max_level(log::LevelFilter::Trace);
+    log::set_logger(&LOGGER).map_err(|e| anyhow::Error::msg(format!("set_logger(): {e}")))?;
+    log::info!("starting wireplug relay");
+
+    let config = config::read_from_file()?;
+    let private_key =
+        protocol::decode_wgkey(&config.private_key).ok_or(anyhow::Error::msg("bad PrivateKey"))?;
+    let identity = Arc::new(register::Identity {
+        private_key,
+        ip: config.public_ip.as_deref().map(str::parse).transpose()?,
+    });
+    if config.coordinators.is_empty() {
+        return Err(anyhow::Error::msg("no Coordinators"));
+    }
+    log::info!("relay key: {}", protocol::public_wgkey(private_key));
+
+    let relay_config = wprelay::RelayConfig::new(
+        &config.relay_listen_on,
+        config.relay_ports.as_deref(),
+        config.relay_idle_timeout,
+    )?;
+    let relay_manager = wprelay::RelayManager::new_shared(Some(relay_config));
+    let connector = get_tls_connector(config.ca_cert.as_deref())?;
+
+    let mut coordinators = tokio::task::JoinSet::new();
+    for coordinator in config.coordinators {
+        let identity = Arc::clone(&identity);
+        let connector = connector.clone();
+        let rm = Arc::clone(&relay_manager);
+        coordinators
+            .spawn(async move { register::maintain(coordinator, &identity, connector, rm).await });
+    }
+    coordinators.join_next().await;
+
+    Ok(())
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("could not build tokio runtime");
+
+    #[cfg(target_os = "openbsd")]
+    if !cli.debug {
+        if let Err(e) = shared::daemonize() {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+
+    if let Err(e) = rt.block_on(start(cli)) {
+        eprintln!("fatal: {e}");
+        std::process::exit(1);
+    }
+}
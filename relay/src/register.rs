@@ -0,0 +1,145 @@
+use shared::protocol::{
+    self, WireplugRelayReply, WireplugRelayRequest, read_message, write_message,
+};
+use std::{net::IpAddr, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_rustls::{TlsConnector, rustls::pki_types::ServerName};
+use wprelay::SharedRelayManager;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(10);
+// coordination servers ping every 30 seconds
+const IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+// What a relay registers with
+pub(crate) struct Identity {
+    pub private_key: [u8; 32],
+    pub ip: Option<IpAddr>,
+}
+
+// Serves the coordination server's requests until it goes quiet or away
+async fn answer<S>(stream: &mut S, manager: &SharedRelayManager) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        let request = tokio::time::timeout(IDLE_TIMEOUT, read_message(stream))
+            .await
+            .map_err(|_| anyhow::Error::msg("no requests for too long"))??;
+        let mut manager = manager.write().await;
+        let port = match request {
+            WireplugRelayRequest::Port { peer_a, peer_b, ip } => manager
+                .get_relay_port(&peer_a, &peer_b, ip)
+                .map(|kind| match kind {
+                    wprelay::RelayKind::Proto(p)
+                    | wprelay::RelayKind::Pending(p)
+                    | wprelay::RelayKind::Established(p) => p,
+                }),
+            WireplugRelayRequest::RemovePair { peer_a, peer_b } => {
+                manager.remove_for_pair(&peer_a, &peer_b);
+                None
+            }
+            WireplugRelayRequest::Ping => None,
+        };
+        let reply = WireplugRelayReply {
+            port,
+            load: manager.load() as u32,
+        };
+        drop(manager);
+        write_message(stream, &reply).await?;
+    }
+}
+
+async fn register(
+    coordinator: &str,
+    identity: &Identity,
+    connector: &TlsConnector,
+    manager: &SharedRelayManager,
+) -> anyhow::Result<()> {
+    let (host, port) = shared::parse_host_port(coordinator, shared::WIREPLUG_WPCOD_PORT)?;
+    let tcp = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect((host.as_str(), port)))
+        .await
+        .map_err(|_| anyhow::Error::msg("timed out connecting"))??;
+    let mut stream = connector
+        .connect(ServerName::try_from(host.clone())?, tcp)
+        .await?;
+    if stream.get_ref().1.alpn_protocol() != Some(protocol::WIREPLUG_RELAY_REGISTRATION_ALPN) {
+        return Err(anyhow::Error::msg("not accepting relays"));
+    }
+
+    let (capacity, load) = {
+        let manager = manager.read().await;
+        (manager.capacity() as u32, manager.load() as u32)
+    };
+    let relay_pubkey = protocol::public_wgkey(identity.private_key);
+    let registration =
+        protocol::WireplugRelayRegistration::new(&relay_pubkey, identity.ip, capacity, load);
+    write_message(&mut stream, &registration).await?;
+    let challenge: protocol::WireplugChallenge = read_message(&mut stream).await?;
+    write_message(&mut stream, &challenge.respond(identity.private_key)).await?;
+    let registered: protocol::WireplugRelayRegistered = read_message(&mut stream).await?;
+    log::info!("registered with {coordinator} as relay {}", registered.id);
+
+    answer(&mut stream, manager).await
+}
+
+// Stays registered with `coordinator`, registering again whenever the
+// connection drops
+pub(crate) async fn maintain(
+    coordinator: String,
+    identity: &Identity,
+    connector: TlsConnector,
+    manager: SharedRelayManager,
+) {
+    loop {
+        if let Err(e) = register(&coordinator, identity, &connector, &manager).await {
+            log::warn!("{coordinator}: {e}");
+        }
+        tokio::time::sleep(RECONNECT_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    const PEER_A: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+    const PEER_B: &str = "BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBA=";
+
+    #[tokio::test]
+    async fn answers_coordination_server() {
+        let config = wprelay::RelayConfig::new("127.0.0.1", Some("43000-43009"), None).unwrap();
+        let manager = wprelay::RelayManager::new_shared(Some(config));
+        let (mut coordinator, mut relay) = duplex(1024);
+        let m = manager.clone();
+        tokio::spawn(async move { answer(&mut relay, &m).await });
+
+        let request = WireplugRelayRequest::Port {
+            peer_a: PEER_A.to_owned(),
+            peer_b: PEER_B.to_owned(),
+            ip: "127.0.0.1".parse().unwrap(),
+        };
+        write_message(&mut coordinator, &request).await.unwrap();
+        let reply: WireplugRelayReply = read_message(&mut coordinator).await.unwrap();
+        assert!(reply.port.is_some_and(|p| (43000..=43009).contains(&p)));
+        assert_eq!(reply.load, 1);
+
+        let request = WireplugRelayRequest::RemovePair {
+            peer_a: PEER_B.to_owned(),
+            peer_b: PEER_A.to_owned(),
+        };
+        write_message(&mut coordinator, &request).await.unwrap();
+        let reply: WireplugRelayReply = read_message(&mut coordinator).await.unwrap();
+        assert_eq!(
+            reply,
+            WireplugRelayReply {
+                port: None,
+                load: 0
+            }
+        );
+    }
+}
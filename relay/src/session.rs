@@ -0,0 +1,114 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Weak,
+    time::{Duration, Instant},
+};
+use tokio::{net::UdpSocket, sync::RwLock};
+
+use crate::RelayManager;
+
+// handshake initiation/response, cookie reply and transport data messages
+// all start with a type in 1..=4 followed by three reserved zero bytes
+fn is_wireguard(packet: &[u8]) -> bool {
+    packet.len() >= 32 && (1..=4).contains(&packet[0]) && packet[1..4] == [0, 0, 0]
+}
+
+const HANDSHAKE_INITIATION: u8 = 1;
+// WireGuard initiates a handshake about 15 seconds after its packets stop
+// getting answers, well after this
+const MOVE_AFTER_QUIET: Duration = Duration::from_secs(5);
+
+// Once a pair is known, a side only moves to a new source port with a
+// handshake initiation after its current one went quiet, so another host
+// behind the same NAT IP can't take the session over
+fn may_move(
+    heard: &HashMap<SocketAddr, Instant>,
+    known: [SocketAddr; 2],
+    from: SocketAddr,
+    packet: &[u8],
+) -> bool {
+    packet[0] == HANDSHAKE_INITIATION
+        && known
+            .iter()
+            .filter(|addr| addr.ip() == from.ip())
+            .all(|addr| {
+                heard
+                    .get(addr)
+                    .is_none_or(|t| t.elapsed() >= MOVE_AFTER_QUIET)
+            })
+}
+
+fn other_side(peers: Option<(SocketAddr, SocketAddr)>, from: SocketAddr) -> Option<SocketAddr> {
+    match peers {
+        Some((a, b)) if from == a => Some(b),
+        Some((a, b)) if from == b => Some(a),
+        _ => None,
+    }
+}
+
+// Forwards WireGuard packets between the two peers of a relay port until it
+// sees no traffic for `idle_timeout`
+pub(crate) async fn run(
+    socket: UdpSocket,
+    port: u16,
+    idle_timeout: Duration,
+    manager: Weak<RwLock<RelayManager>>,
+) {
+    let mut buf = vec![0u8; u16::MAX as usize];
+    let mut peers = None;
+    // when each side was last heard from the address it is known by
+    let mut heard = HashMap::new();
+    loop {
+        let (n, from) = match tokio::time::timeout(idle_timeout, socket.recv_from(&mut buf)).await {
+            Ok(Ok(res)) => res,
+            Ok(Err(e)) => {
+                log::warn!("relay port {port}: {e}");
+                continue;
+            }
+            Err(_) => {
+                log::debug!("relay port {port} is idle, closing");
+                break;
+            }
+        };
+        let to = match other_side(peers, from) {
+            Some(to) => {
+                heard.insert(from, Instant::now());
+                to
+            }
+            None => {
+                if !is_wireguard(&buf[..n]) {
+                    continue;
+                }
+                if let Some((a, b)) = peers
+                    && !may_move(&heard, [a, b], from, &buf[..n])
+                {
+                    continue;
+                }
+                let Some(manager) = manager.upgrade() else {
+                    return;
+                };
+                let learned = manager.write().await.learn(port, from);
+                if learned != peers {
+                    // both sides were just heard from, one of them to be learned
+                    let now = Instant::now();
+                    heard = learned
+                        .iter()
+                        .flat_map(|&(a, b)| [(a, now), (b, now)])
+                        .collect();
+                    peers = learned;
+                }
+                match other_side(peers, from) {
+                    Some(to) => to,
+                    None => continue,
+                }
+            }
+        };
+        if let Err(e) = socket.send_to(&buf[..n], to).await {
+            log::warn!("relay port {port}: failed to forward to {to}: {e}");
+        }
+    }
+    if let Some(manager) = manager.upgrade() {
+        manager.write().await.remove_for_port(port);
+    }
+}
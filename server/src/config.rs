@@ -1,4 +1,4 @@
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 use std::io::{self, Error};
 
 static CONFIG_PATH: &str = "/etc/wpcod.conf";
@@ -6,10 +6,35 @@ static CONFIG_PATH: &str = "/etc/wpcod.conf";
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "PascalCase")]
 pub(crate) struct Config {
-    pub wp_listen_on: String,
+    // one address or a list, e.g. ["0.0.0.0", "::"]
+    #[serde(deserialize_with = "deserialize_listen_on")]
+    pub wp_listen_on: Vec<String>,
     pub stun_listen_on: Vec<String>,
     pub cert_path: String,
     pub key_path: String,
+    pub relay_listen_on: Option<String>,
+    // e.g. "50000-59999"
+    pub relay_ports: Option<String>,
+    // seconds
+    pub relay_idle_timeout: Option<u64>,
+    // relay for peers without UDP, over TLS on the announcement port
+    pub tcp_relay: Option<bool>,
+    // public keys of the wprelay instances that may register
+    pub relay_keys: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ListenOnRepr {
+    One(String),
+    Many(Vec<String>),
+}
+
+fn deserialize_listen_on<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<String>, D::Error> {
+    Ok(match ListenOnRepr::deserialize(d)? {
+        ListenOnRepr::One(addr) => vec![addr],
+        ListenOnRepr::Many(addrs) => addrs,
+    })
 }
 
 pub(crate) fn read_from_file() -> io::Result<Config> {
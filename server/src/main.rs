@@ -1,4 +1,5 @@
 use clap::Parser;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
@@ -39,6 +40,63 @@ struct Cli {
 
 static LOGGER: TmpLogger = TmpLogger;
 
+fn get_listen_addr(addr: &str, port: u16) -> anyhow::Result<SocketAddr> {
+    let ip: IpAddr = addr
+        .parse()
+        .map_err(|e| anyhow::Error::msg(format!("bad listen address {addr:?}: {e}")))?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+// IPv6 sockets also take IPv4 on Linux unless made IPv6-only, and would then
+// clash with a separately listed IPv4 address
+pub(crate) fn bind_socket(
+    addr: SocketAddr,
+    ty: socket2::Type,
+    only_v6: bool,
+) -> std::io::Result<socket2::Socket> {
+    let socket = socket2::Socket::new(socket2::Domain::for_address(addr), ty, None)?;
+    if addr.is_ipv6() && only_v6 {
+        socket.set_only_v6(true)?;
+    }
+    if ty == socket2::Type::STREAM {
+        socket.set_reuse_address(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+fn bind_listener(addr: SocketAddr, only_v6: bool) -> std::io::Result<TcpListener> {
+    let socket = bind_socket(addr, socket2::Type::STREAM, only_v6)?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+fn has_ipv4(addrs: &[String]) -> bool {
+    addrs
+        .iter()
+        .any(|addr| addr.parse::<IpAddr>().is_ok_and(|ip| ip.is_ipv4()))
+}
+
+// Two specific addresses of the same family are served together, so RFC 5780
+// clients can be answered from the other address
+fn get_stun_groups(addrs: &[String]) -> anyhow::Result<Vec<Vec<IpAddr>>> {
+    let mut groups: Vec<Vec<IpAddr>> = vec![];
+    for addr in addrs {
+        let ip = get_listen_addr(addr, shared::WIREPLUG_STUN_PORT)?.ip();
+        match groups.iter_mut().find(|g| {
+            g.len() == 1
+                && g[0].is_ipv4() == ip.is_ipv4()
+                && !g[0].is_unspecified()
+                && !ip.is_unspecified()
+        }) {
+            Some(group) => group.push(ip),
+            None => groups.push(vec![ip]),
+        }
+    }
+    Ok(groups)
+}
+
 async fn start(cli: Cli) -> anyhow::Result<()> {
     #[cfg(target_os = "openbsd")]
     lockdown::step1()?;
@@ -54,7 +112,11 @@ async fn start(cli: Cli) -> anyhow::Result<()> {
     let key = PrivateKeyDer::from_pem_file(&key_path)?;
 
     let storage: SharedStorage = Arc::new(RwLock::new(Storage::new()));
-    let relay_manager = Arc::new(RwLock::new(relay::RelayManager::new()));
+    let relay_config = relay::local_config(&config)?;
+    if relay_config.is_none() {
+        log::info!("relaying is disabled");
+    }
+    let relay_manager = relay::RelayManager::new_shared(relay_config);
     let server_stats = Arc::new(RwLock::new(server::ServerStats::new()));
 
     if cli.monitor {
@@ -78,28 +140,54 @@ async fn start(cli: Cli) -> anyhow::Result<()> {
         }
     });
 
-    for stun_addr in config.stun_listen_on {
-        log::info!("spawning STUN service @{stun_addr:?}");
+    let only_v6 = has_ipv4(&config.stun_listen_on);
+    for stun_ips in get_stun_groups(&config.stun_listen_on)? {
+        log::info!("spawning STUN service @{stun_ips:?}");
         tokio::spawn(async move {
-            let bind_to = format!("{stun_addr}:{}", shared::WIREPLUG_STUN_PORT);
-            stun::start_serving(bind_to).await;
+            stun::start_serving(stun_ips, only_v6).await;
         });
     }
 
-    let tls_config = rustls::ServerConfig::builder()
+    let mut tls_config = rustls::ServerConfig::builder()
         .with_no_client_auth()
         .with_single_cert(cert, key)?;
+    if config.tcp_relay.unwrap_or(false) {
+        log::info!("relaying over TCP");
+        tls_config
+            .alpn_protocols
+            .push(shared::protocol::WIREPLUG_TCP_RELAY_ALPN.to_vec());
+        relay_manager.write().await.enable_tcp();
+    }
+    if let Some(relay_keys) = config.relay_keys.filter(|keys| !keys.is_empty()) {
+        log::info!("accepting {} relays", relay_keys.len());
+        tls_config
+            .alpn_protocols
+            .push(shared::protocol::WIREPLUG_RELAY_REGISTRATION_ALPN.to_vec());
+        relay_manager.write().await.accept_relays(relay_keys);
+    }
     let acceptor = TlsAcceptor::from(Arc::new(tls_config));
-    let wp_listen_addr = format!("{}:{}", config.wp_listen_on, shared::WIREPLUG_WPCOD_PORT);
-    let listener = TcpListener::bind(&wp_listen_addr).await?;
+    let mut listeners = vec![];
+    let only_v6 = has_ipv4(&config.wp_listen_on);
+    for addr in &config.wp_listen_on {
+        let wp_listen_addr = get_listen_addr(addr, shared::WIREPLUG_WPCOD_PORT)?;
+        listeners.push((wp_listen_addr, bind_listener(wp_listen_addr, only_v6)?));
+    }
 
     // let async tasks schedule before lockdown
     sleep(Duration::from_secs(1)).await;
     #[cfg(target_os = "openbsd")]
     lockdown::step2(cli.monitor)?;
 
-    log::info!("serving peer discovery @{wp_listen_addr:?}");
-    server::serve(listener, acceptor, &storage, relay_manager, server_stats).await;
+    let mut servers = tokio::task::JoinSet::new();
+    for (wp_listen_addr, listener) in listeners {
+        log::info!("serving peer discovery @{wp_listen_addr:?}");
+        let acceptor = acceptor.clone();
+        let s = Arc::clone(&storage);
+        let rm = Arc::clone(&relay_manager);
+        let ss = Arc::clone(&server_stats);
+        servers.spawn(async move { server::serve(listener, acceptor, &s, rm, ss).await });
+    }
+    servers.join_next().await;
 
     Ok(())
 }
@@ -6,42 +6,58 @@ use std::{
     time::{Duration, SystemTime},
 };
 
-use shared::protocol::{WireplugAnnouncement, WireplugEndpoint};
+use shared::protocol::{
+    BirthdayRole, NatBehaviour, NatClassification, PortRange, WireplugAnnouncement,
+    WireplugEndpoint, WireplugRendezvous,
+};
 use tokio::sync::RwLock;
 
-use crate::relay::{RelayKind, SharedRelayManager};
+use crate::relay::{self, SharedRelayManager};
 
 const RECORD_TIMEOUT_SEC: u64 = 60 * 60;
-const RELAY_ENABLED: bool = false;
+// a peer that announced this recently is still looking for its peers
+const RENDEZVOUS_WINDOW: Duration = Duration::from_secs(60);
+// how often a peer is assumed to announce until two of its announcements were seen
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(25);
+// retries after a failed announcement come quicker than the peer's cadence
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5);
+// time for the other peer's announcement to arrive
+const RENDEZVOUS_MARGIN: Duration = Duration::from_secs(3);
 
 #[derive(Clone)]
 struct Record {
-    // XXX ipv4 is currently not optional
-    // pub wan_ipv4: Option<Ipv4Addr>,
-    pub wan_ipv4: Ipv4Addr,
+    pub wan_ipv4: Option<Ipv4Addr>,
     pub wan_ipv6: Option<Ipv6Addr>,
     pub lan_addrs: Vec<ipnet::IpNet>,
     pub wg_port: u16,
     pub timestamp: SystemTime,
+    // time between the peer's last two announcements
+    pub interval: Option<Duration>,
     pub needs_relay: bool,
+    pub nat: Option<NatClassification>,
+    pub birthday: bool,
+    pub predicted_ports: Option<PortRange>,
+    pub tcp_relay: bool,
 }
 
 impl Record {
     fn new(
-        wan_ipv4: Ipv4Addr,
-        wan_ipv6: Option<Ipv6Addr>,
-        lan_addrs: Vec<ipnet::IpNet>,
-        wg_port: u16,
+        announcement: &WireplugAnnouncement,
+        (wan_ipv4, wan_ipv6): (Option<Ipv4Addr>, Option<Ipv6Addr>),
         timestamp: SystemTime,
-        needs_relay: bool,
     ) -> Self {
         Self {
             wan_ipv4,
             wan_ipv6,
-            lan_addrs,
-            wg_port,
+            lan_addrs: announcement.lan_addrs.to_owned(),
+            wg_port: announcement.wg_port,
             timestamp,
-            needs_relay,
+            interval: None,
+            needs_relay: announcement.needs_relay,
+            nat: announcement.nat,
+            birthday: announcement.birthday,
+            predicted_ports: announcement.predicted_ports,
+            tcp_relay: announcement.tcp_relay,
         }
     }
 }
@@ -49,12 +65,15 @@ impl Record {
 type PeeringRecords = HashMap<(String, String), Record>;
 pub(crate) struct Storage {
     peering_records: PeeringRecords,
+    // sorted pair of peers -> when both send a handshake
+    rendezvous: HashMap<(String, String), SystemTime>,
 }
 
 impl Storage {
     pub fn new() -> Self {
         Self {
             peering_records: HashMap::new(),
+            rendezvous: HashMap::new(),
         }
     }
     pub fn write_to<W: Write>(&self, writer: &mut W) -> anyhow::Result<()> {
@@ -72,6 +91,9 @@ impl Storage {
                 "\t{peer_a} @{:?}/{:?} (LAN: {:?} -> {peer_b}) | {sec} sec ago",
                 ipv4, ipv6, lan
             )?;
+            if let Some(nat) = &p.1.nat {
+                writeln!(writer, "\t\tNAT: {nat}")?;
+            }
         }
         Ok(())
     }
@@ -79,6 +101,33 @@ impl Storage {
 
 pub(crate) type SharedStorage = Arc<RwLock<Storage>>;
 
+// The address the announcement arrived from, plus whatever the peer reported
+// for the other family
+fn get_wan_addrs(
+    announcement: &WireplugAnnouncement,
+    announcing_peer_addr: SocketAddr,
+) -> (Option<Ipv4Addr>, Option<Ipv6Addr>) {
+    match announcing_peer_addr.ip().to_canonical() {
+        IpAddr::V4(ipv4) => (Some(ipv4), announcement.ipv6),
+        IpAddr::V6(ipv6) => (announcement.ipv4, Some(ipv6)),
+    }
+}
+
+// Behind the same IPv4 NAT, or on the same IPv6 /64
+fn same_network((ipv4, ipv6): (Option<Ipv4Addr>, Option<Ipv6Addr>), record: &Record) -> bool {
+    if let (Some(a), Some(b)) = (ipv4, record.wan_ipv4)
+        && a == b
+    {
+        return true;
+    }
+    if let (Some(a), Some(b)) = (ipv6, record.wan_ipv6)
+        && a.segments()[..4] == b.segments()[..4]
+    {
+        return true;
+    }
+    false
+}
+
 pub(crate) async fn get_peer_endpoints(
     announcement: &WireplugAnnouncement,
     announcing_peer_addr: SocketAddr,
@@ -86,67 +135,73 @@ pub(crate) async fn get_peer_endpoints(
     relay_manager: SharedRelayManager,
 ) -> HashMap<String, WireplugEndpoint> {
     let mut res_peers = HashMap::new();
-    let storage_reader = storage.read().await;
-    let mut relay_manager = relay_manager.write().await;
+    let announcing_wan_addrs = get_wan_addrs(announcement, announcing_peer_addr);
 
     for peer in &announcement.peer_pubkeys {
-        let peer_endpoint = match storage_reader
+        // copied, so storage isn't kept locked while a relay answers
+        let record = storage
+            .read()
+            .await
             .peering_records
             .get(&(peer.to_owned(), announcement.initiator_pubkey.to_owned()))
-        {
+            .cloned();
+        let tcp_enabled = relay_manager.read().await.tcp_enabled();
+        let peer_endpoint = match record {
             Some(record) => {
-                if announcing_peer_addr.ip() == record.wan_ipv4 {
+                if same_network(announcing_wan_addrs, &record) {
+                    relay_manager
+                        .write()
+                        .await
+                        .remove_for_pair(&announcement.initiator_pubkey, peer)
+                        .await;
                     WireplugEndpoint::LocalNetwork {
                         ipv6: record.wan_ipv6,
-                        lan_addrs: record.lan_addrs.clone(),
+                        lan_addrs: record.lan_addrs,
                         wg_port: record.wg_port,
                     }
-                } else if RELAY_ENABLED && (announcement.needs_relay || record.needs_relay) {
-                    let relay_port = match relay_manager.get_relay_port(
+                } else if (announcement.tcp_relay || record.tcp_relay) && tcp_enabled {
+                    relay_manager
+                        .write()
+                        .await
+                        .remove_for_pair(&announcement.initiator_pubkey, peer)
+                        .await;
+                    WireplugEndpoint::TcpRelay
+                } else if (announcement.needs_relay || record.needs_relay)
+                    && let Some((id, ip, port)) = relay::get_relay(
+                        &relay_manager,
                         &announcement.initiator_pubkey,
                         peer,
                         announcing_peer_addr.ip(),
-                    ) {
-                        RelayKind::Proto(p) => {
-                            log::trace!("Proto Relay port:{p}");
-                            p
-                        }
-                        RelayKind::Pending(p) => {
-                            log::trace!("Pending Relay port:{p}");
-                            p
-                        }
-                        RelayKind::Established(p) => {
-                            log::trace!("Established Relay port:{p}");
-                            p
-                        }
-                    };
-                    WireplugEndpoint::Relay {
-                        id: 1,
-                        port: relay_port,
-                    }
+                    )
+                    .await
+                {
+                    WireplugEndpoint::Relay { id, ip, port }
                 } else {
+                    relay_manager
+                        .write()
+                        .await
+                        .remove_for_pair(&announcement.initiator_pubkey, peer)
+                        .await;
                     WireplugEndpoint::RemoteNetwork {
-                        ipv4: Some(record.wan_ipv4),
+                        ipv4: record.wan_ipv4,
                         ipv6: record.wan_ipv6,
                         wg_port: record.wg_port,
+                        predicted_ports: record.predicted_ports,
                     }
                 }
             }
+            None if announcement.tcp_relay && tcp_enabled => WireplugEndpoint::TcpRelay,
             None => {
-                if RELAY_ENABLED && announcement.needs_relay {
-                    let relay_port = match relay_manager.get_relay_port(
+                if announcement.needs_relay
+                    && let Some((id, ip, port)) = relay::get_relay(
+                        &relay_manager,
                         &announcement.initiator_pubkey,
                         peer,
                         announcing_peer_addr.ip(),
-                    ) {
-                        RelayKind::Proto(p) => p,
-                        RelayKind::Pending(p) => p,
-                        _ => todo!(), // error
-                    };
-                    WireplugEndpoint::Relay {
-                        id: 1,
-                        port: relay_port,
-                    }
+                    )
+                    .await
+                {
+                    WireplugEndpoint::Relay { id, ip, port }
                 } else {
                     WireplugEndpoint::Unknown
                 }
@@ -157,37 +212,137 @@ pub(crate) async fn get_peer_endpoints(
     res_peers
 }
 
+pub(crate) fn pair_key(a: &str, b: &str) -> (String, String) {
+    match a < b {
+        true => (a.to_owned(), b.to_owned()),
+        false => (b.to_owned(), a.to_owned()),
+    }
+}
+
+// With endpoint-independent filtering on both sides, the first packets get through anyway
+fn needs_rendezvous(a: Option<NatClassification>, b: Option<NatClassification>) -> bool {
+    let open = |nat: Option<NatClassification>| {
+        nat.is_some_and(|n| n.filtering == Some(NatBehaviour::EndpointIndependent))
+    };
+    !(open(a) && open(b))
+}
+
+// Stateful IPv6 firewalls drop packets from peers they haven't seen us send to
+fn both_ipv6(announcing_ipv6: Option<Ipv6Addr>, record: &Record) -> bool {
+    announcing_ipv6.is_some() && record.wan_ipv6.is_some()
+}
+
+fn is_easy(needs_relay: bool, nat: Option<NatClassification>) -> bool {
+    !needs_relay && nat.is_some_and(|n| n.mapping == Some(NatBehaviour::EndpointIndependent))
+}
+
+// The announcing peer's part in birthday-paradox traversal, when it and its
+// peer both opted in and exactly one of them is behind a hard NAT
+fn get_birthday_role(announcement: &WireplugAnnouncement, record: &Record) -> Option<BirthdayRole> {
+    if !announcement.birthday || !record.birthday {
+        return None;
+    }
+    let peer_ipv4 = record.wan_ipv4?;
+    if announcement.needs_relay && is_easy(record.needs_relay, record.nat) {
+        return Some(BirthdayRole::Hard {
+            peer: SocketAddr::new(IpAddr::V4(peer_ipv4), record.wg_port),
+        });
+    }
+    if record.needs_relay && is_easy(announcement.needs_relay, announcement.nat) {
+        return Some(BirthdayRole::Easy {
+            peer: IpAddr::V4(peer_ipv4),
+        });
+    }
+    None
+}
+
+// When both peers of a pair are announcing, each is told to send a handshake
+// at the same moment: just after the other's next announcement, as expected
+// from its last two, so that both NATs, or IPv6 firewalls, have a mapping open
+// when the packets cross.
+pub(crate) async fn arrange_rendezvous(
+    announcement: &WireplugAnnouncement,
+    announcing_peer_addr: SocketAddr,
+    peer_endpoints: &HashMap<String, WireplugEndpoint>,
+    storage: &SharedStorage,
+) -> HashMap<String, WireplugRendezvous> {
+    let now = SystemTime::now();
+    let (_, announcing_ipv6) = get_wan_addrs(announcement, announcing_peer_addr);
+    let mut res = HashMap::new();
+    let mut storage_writer = storage.write().await;
+    for (peer, endpoint) in peer_endpoints {
+        if !matches!(endpoint, WireplugEndpoint::RemoteNetwork { .. }) {
+            continue;
+        }
+        let Some(record) = storage_writer
+            .peering_records
+            .get(&(peer.to_owned(), announcement.initiator_pubkey.to_owned()))
+            .cloned()
+        else {
+            continue;
+        };
+        let announcing = now
+            .duration_since(record.timestamp)
+            .is_ok_and(|d| d < RENDEZVOUS_WINDOW);
+        let birthday = get_birthday_role(announcement, &record);
+        let needed = needs_rendezvous(announcement.nat, record.nat)
+            || both_ipv6(announcing_ipv6, &record)
+            || birthday.is_some();
+        if !announcing || !needed {
+            continue;
+        }
+        let interval = record.interval.unwrap_or(ANNOUNCE_INTERVAL);
+        let mut next = record.timestamp + interval + RENDEZVOUS_MARGIN;
+        while next <= now {
+            next += interval;
+        }
+        let at = storage_writer
+            .rendezvous
+            .entry(pair_key(peer, &announcement.initiator_pubkey))
+            .and_modify(|at| {
+                if *at <= now {
+                    *at = next
+                }
+            })
+            .or_insert(next);
+        if let Ok(delay) = at.duration_since(now) {
+            let rendezvous = WireplugRendezvous {
+                delay_ms: delay.as_millis() as u32,
+                birthday,
+            };
+            res.insert(peer.to_owned(), rendezvous);
+        }
+    }
+    res
+}
+
 pub(crate) async fn process_announcement(
     announcement: &WireplugAnnouncement,
     announcing_peer_addr: SocketAddr,
     storage: &SharedStorage,
 ) -> std::io::Result<()> {
-    let announing_peer_ipv4 = match announcing_peer_addr.ip() {
-        IpAddr::V4(ipv4_addr) => ipv4_addr,
-        IpAddr::V6(_) => {
-            return Err(std::io::Error::other("bad ip"));
-        }
-    };
+    let wan_addrs = get_wan_addrs(announcement, announcing_peer_addr);
+    let now = SystemTime::now();
     let mut storage_writer = storage.write().await;
     for peer in &announcement.peer_pubkeys {
-        storage_writer.peering_records.insert(
-            (announcement.initiator_pubkey.to_owned(), peer.to_owned()),
-            Record::new(
-                announing_peer_ipv4,
-                announcement.ipv6,
-                announcement.lan_addrs.to_owned(),
-                announcement.wg_port,
-                SystemTime::now(),
-                announcement.needs_relay,
-            ),
-        );
+        let key = (announcement.initiator_pubkey.to_owned(), peer.to_owned());
+        let mut record = Record::new(announcement, wan_addrs, now);
+        record.interval = storage_writer
+            .peering_records
+            .get(&key)
+            .and_then(|previous| now.duration_since(previous.timestamp).ok())
+            .filter(|interval| *interval < RENDEZVOUS_WINDOW)
+            .map(|interval| interval.max(MIN_ANNOUNCE_INTERVAL));
+        storage_writer.peering_records.insert(key, record);
     }
     Ok(())
 }
 
 pub(crate) async fn remove_old_records(storage: &SharedStorage) -> std::io::Result<()> {
     let now = SystemTime::now();
-    storage.write().await.peering_records.retain(|_, record| {
+    let mut storage_writer = storage.write().await;
+    storage_writer.rendezvous.retain(|_, at| *at > now);
+    storage_writer.peering_records.retain(|_, record| {
         if let Ok(record_duration) = now.duration_since(record.timestamp)
             && record_duration < Duration::from_secs(RECORD_TIMEOUT_SEC)
         {
@@ -197,3 +352,233 @@ pub(crate) async fn remove_old_records(storage: &SharedStorage) -> std::io::Resu
     });
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::RelayManager;
+
+    const PEER_A: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+    const PEER_B: &str = "BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBB=";
+
+    fn announcement(from: &str, to: &str, ipv4: Option<Ipv4Addr>) -> WireplugAnnouncement {
+        WireplugAnnouncement::new(
+            &from.to_owned(),
+            vec![to.to_owned()],
+            ipv4,
+            None,
+            51820,
+            vec![],
+            false,
+        )
+    }
+
+    #[tokio::test]
+    async fn ipv6_only_and_dual_stack_peers() {
+        let storage: SharedStorage = Arc::new(RwLock::new(Storage::new()));
+        let relay_manager = RelayManager::new_shared(None);
+        // dual-stack A announces over IPv6 and reports its IPv4, behind a sequential NAT
+        let a_addr: SocketAddr = "[2001:db8:a::1]:443".parse().unwrap();
+        let predicted_ports = PortRange {
+            first: 40001,
+            step: 1,
+            count: 4,
+        };
+        let a = announcement(PEER_A, PEER_B, Some(Ipv4Addr::new(192, 0, 2, 1)))
+            .with_predicted_ports(Some(predicted_ports));
+        process_announcement(&a, a_addr, &storage).await.unwrap();
+        // IPv6-only B
+        let b_addr: SocketAddr = "[2001:db8:b::1]:443".parse().unwrap();
+        let b = announcement(PEER_B, PEER_A, None);
+        process_announcement(&b, b_addr, &storage).await.unwrap();
+
+        let endpoints = get_peer_endpoints(&b, b_addr, &storage, relay_manager.clone()).await;
+        assert_eq!(
+            endpoints.get(PEER_A),
+            Some(&WireplugEndpoint::RemoteNetwork {
+                ipv4: Some(Ipv4Addr::new(192, 0, 2, 1)),
+                ipv6: Some("2001:db8:a::1".parse().unwrap()),
+                wg_port: 51820,
+                predicted_ports: Some(predicted_ports),
+            })
+        );
+        let endpoints = get_peer_endpoints(&a, a_addr, &storage, relay_manager.clone()).await;
+        assert_eq!(
+            endpoints.get(PEER_B),
+            Some(&WireplugEndpoint::RemoteNetwork {
+                ipv4: None,
+                ipv6: Some("2001:db8:b::1".parse().unwrap()),
+                wg_port: 51820,
+                predicted_ports: None,
+            })
+        );
+
+        // a third address on B's /64 is on B's network
+        let c_addr: SocketAddr = "[2001:db8:b::2]:443".parse().unwrap();
+        let endpoints = get_peer_endpoints(&a, c_addr, &storage, relay_manager).await;
+        assert!(matches!(
+            endpoints.get(PEER_B),
+            Some(WireplugEndpoint::LocalNetwork { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn peers_without_udp_are_relayed_over_tcp() {
+        let storage: SharedStorage = Arc::new(RwLock::new(Storage::new()));
+        let relay_manager = RelayManager::new_shared(None);
+        let a_addr: SocketAddr = "192.0.2.1:443".parse().unwrap();
+        let a = announcement(PEER_A, PEER_B, None).with_tcp_relay(true);
+        let b_addr: SocketAddr = "198.51.100.1:443".parse().unwrap();
+        let b = announcement(PEER_B, PEER_A, None);
+        process_announcement(&a, a_addr, &storage).await.unwrap();
+
+        // unless the server relays over TCP
+        let endpoints = get_peer_endpoints(&b, b_addr, &storage, relay_manager.clone()).await;
+        assert!(matches!(
+            endpoints.get(PEER_A),
+            Some(WireplugEndpoint::RemoteNetwork { .. })
+        ));
+        relay_manager.write().await.enable_tcp();
+        let endpoints = get_peer_endpoints(&b, b_addr, &storage, relay_manager.clone()).await;
+        assert_eq!(endpoints.get(PEER_A), Some(&WireplugEndpoint::TcpRelay));
+        let endpoints = get_peer_endpoints(&a, a_addr, &storage, relay_manager).await;
+        assert_eq!(endpoints.get(PEER_B), Some(&WireplugEndpoint::TcpRelay));
+    }
+
+    #[tokio::test]
+    async fn announcing_peers_meet_at_the_same_moment() {
+        let storage: SharedStorage = Arc::new(RwLock::new(Storage::new()));
+        let relay_manager = RelayManager::new_shared(None);
+        let a_addr: SocketAddr = "192.0.2.1:443".parse().unwrap();
+        let a = announcement(PEER_A, PEER_B, None);
+        let b_addr: SocketAddr = "198.51.100.1:443".parse().unwrap();
+        let b = announcement(PEER_B, PEER_A, None);
+
+        // B isn't announcing yet
+        let endpoints = get_peer_endpoints(&a, a_addr, &storage, relay_manager.clone()).await;
+        assert!(
+            arrange_rendezvous(&a, a_addr, &endpoints, &storage)
+                .await
+                .is_empty()
+        );
+        process_announcement(&a, a_addr, &storage).await.unwrap();
+
+        let endpoints = get_peer_endpoints(&b, b_addr, &storage, relay_manager.clone()).await;
+        let b_rendezvous = arrange_rendezvous(&b, b_addr, &endpoints, &storage).await[PEER_A];
+        process_announcement(&b, b_addr, &storage).await.unwrap();
+        assert!(
+            b_rendezvous.delay_ms as u128 <= (ANNOUNCE_INTERVAL + RENDEZVOUS_MARGIN).as_millis()
+        );
+        assert_eq!(b_rendezvous.birthday, None);
+
+        let endpoints = get_peer_endpoints(&a, a_addr, &storage, relay_manager).await;
+        let a_rendezvous = arrange_rendezvous(&a, a_addr, &endpoints, &storage).await[PEER_B];
+        assert!(b_rendezvous.delay_ms.abs_diff(a_rendezvous.delay_ms) < 1000);
+    }
+
+    #[tokio::test]
+    async fn rendezvous_follows_the_peers_announce_interval() {
+        let storage: SharedStorage = Arc::new(RwLock::new(Storage::new()));
+        let relay_manager = RelayManager::new_shared(None);
+        let a_addr: SocketAddr = "192.0.2.1:443".parse().unwrap();
+        let a = announcement(PEER_A, PEER_B, None);
+        let b_addr: SocketAddr = "198.51.100.1:443".parse().unwrap();
+        let b = announcement(PEER_B, PEER_A, None);
+
+        // A announces twice, 10s apart
+        process_announcement(&a, a_addr, &storage).await.unwrap();
+        let key = (PEER_A.to_owned(), PEER_B.to_owned());
+        storage
+            .write()
+            .await
+            .peering_records
+            .get_mut(&key)
+            .unwrap()
+            .timestamp -= Duration::from_secs(10);
+        process_announcement(&a, a_addr, &storage).await.unwrap();
+        let interval = storage.read().await.peering_records[&key].interval.unwrap();
+        assert!(interval.abs_diff(Duration::from_secs(10)) < Duration::from_secs(1));
+
+        // B is told to punch just after A's next announcement, not 25s later
+        let endpoints = get_peer_endpoints(&b, b_addr, &storage, relay_manager).await;
+        let b_rendezvous = arrange_rendezvous(&b, b_addr, &endpoints, &storage).await[PEER_A];
+        let expected = (Duration::from_secs(10) + RENDEZVOUS_MARGIN).as_millis();
+        assert!(b_rendezvous.delay_ms as u128 <= expected);
+        assert!(b_rendezvous.delay_ms as u128 > expected - 1000);
+    }
+
+    #[tokio::test]
+    async fn birthday_roles_for_hard_and_easy_peers() {
+        let storage: SharedStorage = Arc::new(RwLock::new(Storage::new()));
+        let relay_manager = RelayManager::new_shared(None);
+        let easy_nat = NatClassification {
+            mapping: Some(NatBehaviour::EndpointIndependent),
+            filtering: Some(NatBehaviour::AddressAndPortDependent),
+            hairpinning: false,
+            port_preservation: false,
+            cgnat: false,
+            allocation_step: None,
+        };
+        let a_addr: SocketAddr = "192.0.2.1:443".parse().unwrap();
+        let a = announcement(PEER_A, PEER_B, None)
+            .with_nat(Some(easy_nat))
+            .with_birthday(true);
+        let b_addr: SocketAddr = "198.51.100.1:443".parse().unwrap();
+        let mut b = announcement(PEER_B, PEER_A, None).with_birthday(true);
+        b.needs_relay = true;
+
+        process_announcement(&a, a_addr, &storage).await.unwrap();
+        let endpoints = get_peer_endpoints(&b, b_addr, &storage, relay_manager.clone()).await;
+        let rendezvous = arrange_rendezvous(&b, b_addr, &endpoints, &storage).await;
+        assert_eq!(
+            rendezvous[PEER_A].birthday,
+            Some(BirthdayRole::Hard {
+                peer: "192.0.2.1:51820".parse().unwrap()
+            })
+        );
+        process_announcement(&b, b_addr, &storage).await.unwrap();
+
+        let endpoints = get_peer_endpoints(&a, a_addr, &storage, relay_manager).await;
+        let rendezvous = arrange_rendezvous(&a, a_addr, &endpoints, &storage).await;
+        assert_eq!(
+            rendezvous[PEER_B].birthday,
+            Some(BirthdayRole::Easy {
+                peer: "198.51.100.1".parse().unwrap()
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn ipv6_peers_meet_to_open_their_firewalls() {
+        let storage: SharedStorage = Arc::new(RwLock::new(Storage::new()));
+        let relay_manager = RelayManager::new_shared(None);
+        let open_nat = NatClassification {
+            mapping: Some(NatBehaviour::EndpointIndependent),
+            filtering: Some(NatBehaviour::EndpointIndependent),
+            hairpinning: false,
+            port_preservation: true,
+            cgnat: false,
+            allocation_step: None,
+        };
+        // nothing stands in the way over IPv4
+        let a_addr: SocketAddr = "192.0.2.1:443".parse().unwrap();
+        let a = announcement(PEER_A, PEER_B, None).with_nat(Some(open_nat));
+        let b_addr: SocketAddr = "198.51.100.1:443".parse().unwrap();
+        let b = announcement(PEER_B, PEER_A, None).with_nat(Some(open_nat));
+        process_announcement(&a, a_addr, &storage).await.unwrap();
+        let endpoints = get_peer_endpoints(&b, b_addr, &storage, relay_manager.clone()).await;
+        assert!(
+            arrange_rendezvous(&b, b_addr, &endpoints, &storage)
+                .await
+                .is_empty()
+        );
+
+        // but both have IPv6 behind a firewall
+        let a_addr: SocketAddr = "[2001:db8:a::1]:443".parse().unwrap();
+        let b_addr: SocketAddr = "[2001:db8:b::1]:443".parse().unwrap();
+        process_announcement(&a, a_addr, &storage).await.unwrap();
+        let endpoints = get_peer_endpoints(&b, b_addr, &storage, relay_manager).await;
+        let rendezvous = arrange_rendezvous(&b, b_addr, &endpoints, &storage).await;
+        assert!(rendezvous.contains_key(PEER_A));
+    }
+}
@@ -1,236 +1,254 @@
-use futures::future::join_all;
-use std::{collections::HashMap, fmt::Write, net::IpAddr, sync::Arc};
+use shared::protocol::WireplugRelayRequest;
+use std::{fmt::Write, net::IpAddr, sync::Arc};
 use tokio::sync::RwLock;
-mod port_mapping;
 
-pub struct ProtoRelay {
-    a_ip: IpAddr,
-    pub relay_port: u16,
-}
-
-pub struct PendingRelay {
-    pub a_ip: IpAddr,
-    pub a_oport: u16,
-    pub relay_port: u16,
-}
-
-impl PendingRelay {
-    pub fn new(a_ip: IpAddr, a_oport: u16, relay_port: u16) -> Self {
-        Self {
-            a_ip,
-            a_oport,
-            relay_port,
-        }
-    }
-}
-
-pub struct EstablishedRelay {
-    a_ip: IpAddr,
-    pub a_oport: u16,
-    b_ip: IpAddr,
-    b_oport: u16,
-    relay_ip: IpAddr,
-    pub relay_port: u16,
-}
-
-impl EstablishedRelay {
-    pub fn new(
-        a_ip: IpAddr,
-        a_oport: u16,
-        b_ip: IpAddr,
-        b_oport: u16,
-        relay_ip: IpAddr,
-        relay_port: u16,
-    ) -> Self {
-        Self {
-            a_ip,
-            a_oport,
-            b_ip,
-            b_oport,
-            relay_ip,
-            relay_port,
-        }
-    }
-}
-
-pub trait WriteTo {
-    fn write_to<Write: std::io::Write>(&self, writer: &mut Write) -> std::io::Result<()>;
-}
-
-pub enum RelayKind {
-    Proto(u16),
-    Pending(u16),
-    Established(u16),
-}
-
-#[derive(PartialEq, PartialOrd, Eq, Clone, Hash)]
-pub struct Key(pub [u8; 32]);
-#[derive(PartialEq, PartialOrd, Eq, Clone, Hash)]
-pub struct NormalizedKey(pub [u8; 64]);
-
-// XXX
-fn tmp_string_to_bytes(_s: &String) -> [u8; 32] {
-    [0u8; 32]
-}
-
-// XXX
-//fn get_normalized(a: Key, b: Key) -> NormalizedKey {
-fn get_normalized(a: &String, b: &String) -> NormalizedKey {
-    let a = tmp_string_to_bytes(a);
-    let b = tmp_string_to_bytes(b);
-    let mut out = [0u8; 64];
-    if a <= b {
-        out[..32].copy_from_slice(a.as_slice());
-        out[32..].copy_from_slice(b.as_slice());
-    } else {
-        out[..32].copy_from_slice(b.as_slice());
-        out[32..].copy_from_slice(a.as_slice());
-    }
-    NormalizedKey(out)
+pub use wprelay::{RelayConfig, RelayKind};
+
+use crate::config::Config;
+
+pub(crate) mod registry;
+pub(crate) mod tcp;
+
+// endpoints name wpcod's own relay by this id, registered relays by theirs
+pub const LOCAL_RELAY_ID: usize = 0;
+
+// relaying on wpcod itself is enabled by setting RelayListenOn
+pub(crate) fn local_config(config: &Config) -> anyhow::Result<Option<RelayConfig>> {
+    config
+        .relay_listen_on
+        .as_deref()
+        .map(|listen_on| {
+            RelayConfig::new(
+                listen_on,
+                config.relay_ports.as_deref(),
+                config.relay_idle_timeout,
+            )
+        })
+        .transpose()
 }
 
 pub struct RelayManager {
-    proto: HashMap<(String, String), ProtoRelay>,
-    pending: HashMap<(String, String), PendingRelay>,
-    established: HashMap<NormalizedKey, EstablishedRelay>,
+    // relay ports of wpcod itself
+    local: wprelay::SharedRelayManager,
+    // wprelay instances that registered
+    remote: registry::Registry,
+    // None unless relaying over TCP is enabled
+    tcp: Option<tcp::TcpRelays>,
 }
 
 pub type SharedRelayManager = Arc<RwLock<RelayManager>>;
 
-impl Default for RelayManager {
-    fn default() -> Self {
-        Self::new()
-    }
-}
-
-impl RelayManager {
-    pub fn new() -> Self {
-        Self {
-            proto: HashMap::new(),
-            pending: HashMap::new(),
-            established: HashMap::new(),
+// A pair stays on the relay it was given. New pairs go to the registered
+// relay with the most room, or to wpcod's own if there is none. Returns the
+// relay's id, its address unless it is wpcod's own, and the port. The manager
+// is not kept locked while a registered relay answers.
+pub(crate) async fn get_relay(
+    manager: &SharedRelayManager,
+    peer_a: &String,
+    peer_b: &String,
+    announcing_ip: IpAddr,
+) -> Option<(usize, Option<IpAddr>, u16)> {
+    let (remote, local) = {
+        let mut manager = manager.write().await;
+        let remote = manager.pick(peer_a, peer_b).await;
+        (remote, Arc::clone(&manager.local))
+    };
+    if let Some(relay) = remote {
+        let request = WireplugRelayRequest::Port {
+            peer_a: peer_a.to_owned(),
+            peer_b: peer_b.to_owned(),
+            ip: announcing_ip,
+        };
+        match relay.request(request).await.and_then(|reply| reply.port) {
+            Some(port) => {
+                log::trace!("relay {} port:{port}", relay.id);
+                return Some((relay.id, Some(relay.ip), port));
+            }
+            None => {
+                log::warn!("relay {} has no port for {peer_a} => {peer_b}", relay.id);
+                manager.write().await.remote.unassign(peer_a, peer_b);
+            }
         }
     }
-
-    pub fn get_relay_port(
-        &mut self,
-        peer_a: &String,
-        peer_b: &String,
-        announcing_ip: IpAddr,
-    ) -> RelayKind {
-        let normalized_key = get_normalized(peer_a, peer_b);
-        if let Some(relay) = self.established.get(&normalized_key) {
-            return RelayKind::Established(relay.relay_port);
+    let port = match local
+        .write()
+        .await
+        .get_relay_port(peer_a, peer_b, announcing_ip)?
+    {
+        RelayKind::Proto(p) => {
+            log::trace!("Proto Relay port:{p}");
+            p
         }
-        if let Some(relay) = self.pending.get(&(peer_a.to_string(), peer_b.to_string())) {
-            return RelayKind::Pending(relay.relay_port);
+        RelayKind::Pending(p) => {
+            log::trace!("Pending Relay port:{p}");
+            p
         }
-        if let Some(relay) = self.proto.get(&(peer_a.to_string(), peer_b.to_string())) {
-            return RelayKind::Proto(relay.relay_port);
+        RelayKind::Established(p) => {
+            log::trace!("Established Relay port:{p}");
+            p
         }
-        // the non-announcing peer is already pending so use the same port
-        let port = if let Some(relay) = self.pending.get(&(peer_b.to_string(), peer_a.to_string()))
-        {
-            relay.relay_port
-        } else {
-            get_free_random_port() // XXX
-        };
-        self.proto.insert(
-            (peer_a.to_string(), peer_b.to_string()),
-            ProtoRelay {
-                a_ip: announcing_ip,
-                relay_port: port,
-            },
-        );
-        RelayKind::Proto(port)
+    };
+    Some((LOCAL_RELAY_ID, None, port))
+}
+
+impl RelayManager {
+    pub fn new_shared(config: Option<RelayConfig>) -> SharedRelayManager {
+        Arc::new(RwLock::new(Self {
+            local: wprelay::RelayManager::new_shared(config),
+            remote: registry::Registry::default(),
+            tcp: None,
+        }))
     }
 
-    // XXX
-    pub(crate) async fn _wait_for_protos(&mut self) -> std::io::Result<()> {
-        let work = self.proto.drain().map(|((a, b), proto)| async move {
-            port_mapping::detect_source_port(&proto.a_ip, proto.relay_port)
-                .await
-                .ok()
-                .map(|observed_source_port| {
-                    (
-                        (a, b),
-                        PendingRelay::new(proto.a_ip, observed_source_port, proto.relay_port),
-                    )
-                })
-        });
+    pub fn enable_tcp(&mut self) {
+        self.tcp.get_or_insert_default();
+    }
 
-        join_all(work).await.iter().for_each(|thing| {
-            if let Some(((_a, _b), _pending)) = thing {
-                // XXX
-                // if we got a port:
-                //  if we have a matching pending, establish (pf) and promote to established
-                //  else promote to pending
-            };
-        });
+    pub fn tcp_enabled(&self) -> bool {
+        self.tcp.is_some()
+    }
 
-        Ok(())
+    // relays holding the private key of one of `pubkeys` may register
+    pub fn accept_relays(&mut self, pubkeys: Vec<String>) {
+        self.remote.allow(pubkeys);
     }
 
-    pub fn remove_for_pair(&mut self, _peer_a: &String, _peer_b: &String) {
-        // remove peers' protos and pending
-        // destroy (pf) and remove established
+    pub fn accepts_relay(&self, pubkey: &str) -> bool {
+        self.remote.allows(pubkey)
     }
 
-    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::fmt::Result {
-        for ((a, b), r) in &self.proto {
-            let ip = r.a_ip.to_string();
-            let port = r.relay_port;
-            writeln!(writer, "{a} => {b} waiting for {ip} on port:{port} (proto)")?;
+    // The registered relay serving the pair, or one to give it to, unless the
+    // pair is already on wpcod's own
+    async fn pick(&mut self, peer_a: &String, peer_b: &String) -> Option<registry::RemoteRelay> {
+        if self.local.read().await.has_pair(peer_a, peer_b) {
+            return None;
         }
-        for ((a, b), r) in &self.pending {
-            let ip = r.a_ip.to_string();
-            let port = r.a_oport;
-            writeln!(writer, "{a} => {b} {ip}:{port} (pending)")?;
+        self.remote.pick(peer_a, peer_b)
+    }
+
+    pub async fn remove_for_pair(&mut self, peer_a: &String, peer_b: &String) {
+        if let Some(relay) = self.remote.unassign(peer_a, peer_b) {
+            relay.notify(WireplugRelayRequest::RemovePair {
+                peer_a: peer_a.to_owned(),
+                peer_b: peer_b.to_owned(),
+            });
         }
-        for r in self.established.values() {
-            writeln!(
-                writer,
-                "{}:{} <=> {}:{} (established)",
-                r.a_ip, r.a_oport, r.b_ip, r.b_oport
-            )?;
+        self.local.write().await.remove_for_pair(peer_a, peer_b);
+    }
+
+    pub async fn write_to<W: Write>(&self, writer: &mut W) -> std::fmt::Result {
+        self.local.read().await.write_to(writer)?;
+        self.remote.write_to(writer)?;
+        if let Some(tcp) = &self.tcp {
+            tcp.write_to(writer)?;
         }
         Ok(())
     }
 }
 
-/*
-/////////////////////////////////////////////////////////////////////////////////////////////
-*/
-
-impl WriteTo for EstablishedRelay {
-    fn write_to<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
-        let rdr_to_1 = format!(
-            //"pass in proto udp from {} port {} to {} port {} rdr-to {}\n",
-            //self.a_ip, self.a_oport, self.relay_ip, self.b_oport, self.b_ip
-            "pass in proto udp from {} to {} port {} rdr-to {}\n",
-            self.a_ip, self.relay_ip, self.b_oport, self.b_ip
-        );
-        let nat_to_1 = format!(
-            "pass out proto udp from {} to {} nat-to {} static-port\n",
-            self.a_ip, self.b_ip, self.relay_ip
-        );
-        let rdr_to_2 = format!(
-            "pass in proto udp from {} port {} to {} port {} rdr-to {}\n",
-            self.b_ip, self.b_oport, self.relay_ip, self.a_oport, self.a_ip
-        );
-        let nat_to_2 = format!(
-            "pass out proto udp from {} to {} nat-to {} static-port\n",
-            self.b_ip, self.a_ip, self.relay_ip
-        );
-        writer.write_all(rdr_to_1.as_bytes())?;
-        writer.write_all(nat_to_1.as_bytes())?;
-        writer.write_all(rdr_to_2.as_bytes())?;
-        writer.write_all(nat_to_2.as_bytes())?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
 
-        Ok(())
+    const PEER_A: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+    const PEER_B: &str = "BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBA=";
+
+    fn wg_packet(message_type: u8) -> Vec<u8> {
+        let mut packet = vec![0u8; 32];
+        packet[0] = message_type;
+        packet
     }
-}
 
-fn get_free_random_port() -> u16 {
-    todo!()
+    #[tokio::test]
+    async fn relays_over_tcp() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+
+        let manager = RelayManager::new_shared(None);
+        manager.write().await.enable_tcp();
+        let (mut a, a_server) = duplex(1024);
+        let (mut b, b_server) = duplex(1024);
+        let (a_key, b_key) = (PEER_A.to_owned(), PEER_B.to_owned());
+        tokio::spawn(tcp::relay(
+            manager.clone(),
+            a_key.clone(),
+            b_key.clone(),
+            Box::new(a_server),
+        ));
+        tokio::spawn(tcp::relay(
+            manager.clone(),
+            b_key,
+            a_key,
+            Box::new(b_server),
+        ));
+
+        a.write_all(&[32, 0]).await.unwrap();
+        a.write_all(&wg_packet(1)).await.unwrap();
+        let mut buf = [0u8; 34];
+        timeout(Duration::from_secs(1), b.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(buf[2..], wg_packet(1));
+        b.write_all(b"back").await.unwrap();
+        let mut buf = [0u8; 4];
+        timeout(Duration::from_secs(1), a.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"back");
+    }
+
+    #[tokio::test]
+    async fn pairs_go_to_registered_relays() {
+        use crate::server::read_message;
+        use shared::protocol::{
+            WireplugRelayRegistered, WireplugRelayRegistration, WireplugRelayReply, write_message,
+        };
+        use tokio::io::duplex;
+
+        const RELAY: &str = "CCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCA=";
+        let manager = RelayManager::new_shared(None);
+        manager.write().await.accept_relays(vec![RELAY.to_owned()]);
+        assert!(manager.read().await.accepts_relay(RELAY));
+        assert!(!manager.read().await.accepts_relay(PEER_A));
+
+        let (mut relay, coordinator) = duplex(1024);
+        let relay_ip: IpAddr = "192.0.2.7".parse().unwrap();
+        let registration = WireplugRelayRegistration::new(RELAY, Some(relay_ip), 100, 0);
+        tokio::spawn(registry::serve(
+            coordinator,
+            registration,
+            relay_ip,
+            manager.clone(),
+        ));
+        let registered: WireplugRelayRegistered = read_message(&mut relay).await.unwrap().unwrap();
+
+        // the relay hands out ports as wpcod asks for them
+        tokio::spawn(async move {
+            while let Ok(Some(request)) = read_message(&mut relay).await {
+                let port = match request {
+                    WireplugRelayRequest::Port { .. } => Some(4242),
+                    _ => None,
+                };
+                let reply = WireplugRelayReply { port, load: 1 };
+                write_message(&mut relay, &reply).await.unwrap();
+            }
+        });
+        let (a, b) = (PEER_A.to_owned(), PEER_B.to_owned());
+        let ip = "198.51.100.1".parse().unwrap();
+        let expected = Some((registered.id, Some(relay_ip), 4242));
+        let relay_a = timeout(Duration::from_secs(1), get_relay(&manager, &a, &b, ip))
+            .await
+            .unwrap();
+        assert_eq!(relay_a, expected);
+        let relay_b = get_relay(&manager, &b, &a, ip).await;
+        assert_eq!(relay_b, expected);
+
+        // without any relay left, wpcod has none of its own either
+        manager.write().await.remove_for_pair(&a, &b).await;
+        manager.write().await.remote.remove(registered.id);
+        assert_eq!(get_relay(&manager, &a, &b, ip).await, None);
+    }
 }
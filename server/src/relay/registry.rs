@@ -0,0 +1,206 @@
+use shared::protocol::{
+    WireplugRelayRegistered, WireplugRelayRegistration, WireplugRelayReply, WireplugRelayRequest,
+    write_message,
+};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    net::IpAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{mpsc, oneshot},
+};
+
+use super::SharedRelayManager;
+use crate::{peering::pair_key, server::read_message};
+
+// a relay without requests is pinged this often
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Request = (
+    WireplugRelayRequest,
+    Option<oneshot::Sender<WireplugRelayReply>>,
+);
+
+#[derive(Clone)]
+pub(crate) struct RemoteRelay {
+    pub id: usize,
+    pub ip: IpAddr,
+    pubkey: String,
+    capacity: u32,
+    // as of its last reply
+    load: Arc<AtomicU32>,
+    requests: mpsc::Sender<Request>,
+}
+
+impl RemoteRelay {
+    // None if the relay did not answer in time
+    pub async fn request(&self, request: WireplugRelayRequest) -> Option<WireplugRelayReply> {
+        let (tx, rx) = oneshot::channel();
+        self.requests.send((request, Some(tx))).await.ok()?;
+        tokio::time::timeout(REPLY_TIMEOUT, rx).await.ok()?.ok()
+    }
+
+    // without waiting for the reply
+    pub fn notify(&self, request: WireplugRelayRequest) {
+        if self.requests.try_send((request, None)).is_err() {
+            log::warn!("relay {} is not keeping up", self.id);
+        }
+    }
+
+    fn load(&self) -> u32 {
+        self.load.load(Ordering::Relaxed)
+    }
+
+    // in thousandths of its capacity
+    fn usage(&self) -> u64 {
+        self.load() as u64 * 1000 / self.capacity as u64
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Registry {
+    // public keys of the relays that may register
+    allowed: Vec<String>,
+    relays: HashMap<usize, RemoteRelay>,
+    last_id: usize,
+    // pair -> id of the relay serving it
+    assigned: HashMap<(String, String), usize>,
+}
+
+impl Registry {
+    pub fn allow(&mut self, pubkeys: Vec<String>) {
+        self.allowed = pubkeys;
+    }
+
+    pub fn allows(&self, pubkey: &str) -> bool {
+        self.allowed.iter().any(|k| k == pubkey)
+    }
+
+    fn add(&mut self, mut relay: RemoteRelay) -> usize {
+        // a relay that reconnects replaces its old registration
+        let old = self
+            .relays
+            .values()
+            .find(|r| r.pubkey == relay.pubkey)
+            .map(|r| r.id);
+        if let Some(old) = old {
+            self.remove(old);
+        }
+        self.last_id += 1;
+        relay.id = self.last_id;
+        self.relays.insert(relay.id, relay);
+        self.last_id
+    }
+
+    pub fn remove(&mut self, id: usize) {
+        self.relays.remove(&id);
+        self.assigned.retain(|_, r| *r != id);
+    }
+
+    // The relay already serving the pair, or the least used one with room
+    pub fn pick(&mut self, peer_a: &str, peer_b: &str) -> Option<RemoteRelay> {
+        let pair = pair_key(peer_a, peer_b);
+        if let Some(relay) = self.assigned.get(&pair).and_then(|id| self.relays.get(id)) {
+            return Some(relay.clone());
+        }
+        let relay = self
+            .relays
+            .values()
+            .filter(|r| r.load() < r.capacity)
+            .min_by_key(|r| r.usage())?
+            .clone();
+        self.assigned.insert(pair, relay.id);
+        Some(relay)
+    }
+
+    pub fn unassign(&mut self, peer_a: &str, peer_b: &str) -> Option<RemoteRelay> {
+        let id = self.assigned.remove(&pair_key(peer_a, peer_b))?;
+        self.relays.get(&id).cloned()
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::fmt::Result {
+        for r in self.relays.values() {
+            writeln!(
+                writer,
+                "relay {} @{} {}/{} ports in use",
+                r.id,
+                r.ip,
+                r.load(),
+                r.capacity
+            )?;
+        }
+        for ((a, b), id) in &self.assigned {
+            writeln!(writer, "{a} <=> {b} on relay {id}")?;
+        }
+        Ok(())
+    }
+}
+
+// Passes requests to the relay and its replies back, pinging it when idle
+async fn exchange<S>(
+    stream: &mut S,
+    requests: &mut mpsc::Receiver<Request>,
+    load: &AtomicU32,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        let (request, reply_tx) = match tokio::time::timeout(PING_INTERVAL, requests.recv()).await {
+            Ok(Some(request)) => request,
+            // registered again over another connection
+            Ok(None) => return Ok(()),
+            Err(_) => (WireplugRelayRequest::Ping, None),
+        };
+        write_message(stream, &request).await?;
+        let reply: WireplugRelayReply = tokio::time::timeout(REPLY_TIMEOUT, read_message(stream))
+            .await
+            .map_err(|_| anyhow::Error::msg("relay did not reply"))??
+            .ok_or(anyhow::Error::msg("bad reply from relay"))?;
+        load.store(reply.load, Ordering::Relaxed);
+        if let Some(reply_tx) = reply_tx {
+            let _ = reply_tx.send(reply);
+        }
+    }
+}
+
+// Registers a relay that proved its key, and serves it until it disconnects
+pub(crate) async fn serve<S>(
+    mut stream: S,
+    registration: WireplugRelayRegistration,
+    ip: IpAddr,
+    manager: SharedRelayManager,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (tx, mut rx) = mpsc::channel(16);
+    let load = Arc::new(AtomicU32::new(registration.load));
+    let id = manager.write().await.remote.add(RemoteRelay {
+        id: 0,
+        ip,
+        pubkey: registration.relay_pubkey,
+        capacity: registration.capacity,
+        load: Arc::clone(&load),
+        requests: tx,
+    });
+    log::info!(
+        "relay {id} registered @{ip} with {} ports",
+        registration.capacity
+    );
+    let res = match write_message(&mut stream, &WireplugRelayRegistered { id }).await {
+        Ok(()) => exchange(&mut stream, &mut rx, &load).await,
+        Err(e) => Err(e.into()),
+    };
+    manager.write().await.remote.remove(id);
+    log::info!("relay {id} is gone");
+    res
+}
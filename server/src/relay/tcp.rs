@@ -0,0 +1,83 @@
+use std::{collections::HashMap, fmt::Write, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::oneshot,
+};
+
+use super::SharedRelayManager;
+
+// how long a connection waits for the peer's
+const PEER_WAIT: Duration = Duration::from_secs(60);
+
+pub(crate) trait RelayStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> RelayStream for S {}
+
+pub(crate) type BoxedStream = Box<dyn RelayStream>;
+
+// Connections whose peer hasn't connected yet
+#[derive(Default)]
+pub(crate) struct TcpRelays {
+    // (from, to) -> hands the peer's stream to the waiting connection
+    waiting: HashMap<(String, String), oneshot::Sender<BoxedStream>>,
+}
+
+impl TcpRelays {
+    // Hands `stream` over to the peer's connection if it is waiting. Otherwise
+    // `stream` waits itself, and is returned with where the peer's will arrive.
+    fn join(
+        &mut self,
+        from: &str,
+        to: &str,
+        stream: BoxedStream,
+    ) -> Option<(BoxedStream, oneshot::Receiver<BoxedStream>)> {
+        // connections that gave up waiting
+        self.waiting.retain(|_, tx| !tx.is_closed());
+        let mut stream = stream;
+        if let Some(tx) = self.waiting.remove(&(to.to_owned(), from.to_owned())) {
+            match tx.send(stream) {
+                Ok(()) => return None,
+                Err(s) => stream = s,
+            }
+        }
+        // replaces an older connection of the same peer
+        let (tx, rx) = oneshot::channel();
+        self.waiting.insert((from.to_owned(), to.to_owned()), tx);
+        Some((stream, rx))
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::fmt::Result {
+        for (a, b) in self.waiting.keys() {
+            writeln!(writer, "{a} => {b} waiting over TCP")?;
+        }
+        Ok(())
+    }
+}
+
+// Pairs an authenticated connection from `from` with the one from `to`, and
+// copies between them until either side closes
+pub(crate) async fn relay(
+    manager: SharedRelayManager,
+    from: String,
+    to: String,
+    stream: BoxedStream,
+) {
+    let joined = match manager.write().await.tcp.as_mut() {
+        Some(tcp) => tcp.join(&from, &to, stream),
+        None => return,
+    };
+    let Some((mut stream, peer)) = joined else {
+        return;
+    };
+    let mut peer_stream = match tokio::time::timeout(PEER_WAIT, peer).await {
+        Ok(Ok(peer_stream)) => peer_stream,
+        _ => {
+            log::debug!("{from} => {to}: the peer did not connect over TCP");
+            return;
+        }
+    };
+    log::info!("relaying over TCP: {from} <=> {to}");
+    match tokio::io::copy_bidirectional(&mut stream, &mut peer_stream).await {
+        Ok((a, b)) => log::debug!("TCP relay {from} <=> {to} closed after {a}/{b} bytes"),
+        Err(e) => log::debug!("TCP relay {from} <=> {to}: {e}"),
+    }
+}
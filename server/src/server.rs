@@ -3,19 +3,20 @@ use std::{fmt::Write, net::SocketAddr, sync::Arc};
 use shared::protocol::{self, WireplugResponse};
 use tokio::net::TcpListener;
 use tokio::{
-    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
     sync::RwLock,
 };
 use tokio_rustls::TlsAcceptor;
 
 use crate::{
     peering::{self, SharedStorage},
-    relay::SharedRelayManager,
+    relay::{self, SharedRelayManager},
 };
 
 pub(crate) struct ServerStats {
     tls_errros: usize,
     relays_needed: usize,
+    failed_proofs: usize,
 }
 
 pub(crate) type SharedServerStats = Arc<RwLock<ServerStats>>;
@@ -25,6 +26,7 @@ impl ServerStats {
         Self {
             tls_errros: 0,
             relays_needed: 0,
+            failed_proofs: 0,
         }
     }
     fn inc_tls_errors(&mut self) {
@@ -33,70 +35,187 @@ impl ServerStats {
     fn inc_relays_needed(&mut self) {
         self.relays_needed += 1;
     }
+    fn inc_failed_proofs(&mut self) {
+        self.failed_proofs += 1;
+    }
     pub fn write_to<W: Write>(&self, writer: &mut W) -> std::fmt::Result {
         writeln!(writer, "tls errors: {}", self.tls_errros)?;
         writeln!(writer, "relays needed: {}", self.relays_needed)?;
+        writeln!(writer, "failed key proofs: {}", self.failed_proofs)?;
         Ok(())
     }
 }
 
-async fn handle_connection<S>(
+// Like protocol::read_message, but a client speaking another protocol gets our
+// version back and the connection is closed, which returns None
+pub(crate) async fn read_message<S, T>(stream: &mut S) -> anyhow::Result<Option<T>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    T: serde::de::DeserializeOwned,
+{
+    let e = match protocol::read_message(stream).await {
+        Ok(message) => return Ok(Some(message)),
+        Err(e) => e,
+    };
+    match protocol::HeaderError::of(&e) {
+        Some(protocol::HeaderError::BadMagic) => {
+            stream.shutdown().await?;
+            Ok(None)
+        }
+        Some(protocol::HeaderError::OtherVersion) => {
+            stream.write_all(&protocol::WIREPLUG_PROTOCOL_MAGIC).await?;
+            stream
+                .write_all(&protocol::WIREPLUG_PROTOCOL_VERSION)
+                .await?;
+
+            stream.shutdown().await?;
+            Ok(None)
+        }
+        _ => Err(e.into()),
+    }
+}
+
+// Challenges the client to prove it holds the private key of `initiator_pubkey`
+async fn prove_ownership<S>(
+    stream: &mut S,
+    initiator_pubkey: &str,
+    peer_addr: SocketAddr,
+    server_stats: &SharedServerStats,
+) -> anyhow::Result<bool>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (challenge, challenge_secret) = protocol::WireplugChallenge::generate();
+    protocol::write_message(stream, &challenge).await?;
+    let Some(challenge_response) =
+        read_message::<_, protocol::WireplugChallengeResponse>(stream).await?
+    else {
+        return Ok(false);
+    };
+    if !challenge_secret.verify(&challenge, initiator_pubkey, &challenge_response) {
+        log::warn!("{peer_addr:?} failed to prove ownership of {initiator_pubkey}");
+        server_stats.write().await.inc_failed_proofs();
+        stream.shutdown().await?;
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+async fn handle_tcp_relay<S>(
     mut stream: S,
-    announcing_peer_addr: SocketAddr,
-    storage: peering::SharedStorage,
+    peer_addr: SocketAddr,
     relay_manager: SharedRelayManager,
     server_stats: SharedServerStats,
 ) -> anyhow::Result<()>
 where
-    S: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
-    let mut header = [0u8; 4];
-    stream.read_exact(&mut header).await?;
-    if header[..3] != protocol::WIREPLUG_PROTOCOL_MAGIC {
+    let Some(request) = read_message::<_, protocol::WireplugTcpRelayRequest>(&mut stream).await?
+    else {
+        return Ok(());
+    };
+    if !request.valid() {
         stream.shutdown().await?;
         return Ok(());
     }
-    if header[3..] != protocol::WIREPLUG_PROTOCOL_VERSION {
-        stream.write_all(&protocol::WIREPLUG_PROTOCOL_MAGIC).await?;
-        stream
-            .write_all(&protocol::WIREPLUG_PROTOCOL_VERSION)
-            .await?;
+    if !prove_ownership(
+        &mut stream,
+        &request.initiator_pubkey,
+        peer_addr,
+        &server_stats,
+    )
+    .await?
+    {
+        return Ok(());
+    }
+    relay::tcp::relay(
+        relay_manager,
+        request.initiator_pubkey,
+        request.peer_pubkey,
+        Box::new(stream),
+    )
+    .await;
+    Ok(())
+}
 
+async fn handle_relay_registration<S>(
+    mut stream: S,
+    peer_addr: SocketAddr,
+    relay_manager: SharedRelayManager,
+    server_stats: SharedServerStats,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let Some(registration) =
+        read_message::<_, protocol::WireplugRelayRegistration>(&mut stream).await?
+    else {
+        return Ok(());
+    };
+    if !registration.valid()
+        || !relay_manager
+            .read()
+            .await
+            .accepts_relay(&registration.relay_pubkey)
+    {
+        log::warn!("{peer_addr:?} is not a known relay");
         stream.shutdown().await?;
         return Ok(());
     }
-    let encoded_length = usize::try_from(stream.read_u32_le().await?)?;
-    if encoded_length > shared::MAX_MESSAGE_SIZE {
-        return Err(anyhow::anyhow!(
-            "Message size {} exceeds maximum allowed size of {}",
-            encoded_length,
-            shared::MAX_MESSAGE_SIZE
-        ));
+    if !prove_ownership(
+        &mut stream,
+        &registration.relay_pubkey,
+        peer_addr,
+        &server_stats,
+    )
+    .await?
+    {
+        return Ok(());
     }
+    let ip = registration.ip.unwrap_or(peer_addr.ip());
+    relay::registry::serve(stream, registration, ip, relay_manager).await
+}
 
-    let mut buffer = [0u8; shared::MAX_MESSAGE_SIZE];
-    stream.read_exact(&mut buffer[0..encoded_length]).await?;
-    let announcement: protocol::WireplugAnnouncement = postcard::from_bytes(&buffer)?;
+async fn handle_connection<S>(
+    mut stream: S,
+    announcing_peer_addr: SocketAddr,
+    storage: peering::SharedStorage,
+    relay_manager: SharedRelayManager,
+    server_stats: SharedServerStats,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let Some(announcement) = read_message::<_, protocol::WireplugAnnouncement>(&mut stream).await?
+    else {
+        return Ok(());
+    };
 
     if !announcement.valid() {
         stream.shutdown().await?;
         return Ok(());
     }
+    if !prove_ownership(
+        &mut stream,
+        &announcement.initiator_pubkey,
+        announcing_peer_addr,
+        &server_stats,
+    )
+    .await?
+    {
+        return Ok(());
+    }
 
     let res_peers =
         peering::get_peer_endpoints(&announcement, announcing_peer_addr, &storage, relay_manager)
             .await;
 
-    let response = WireplugResponse::from_peer_endpoints(res_peers);
-    let encoded_message = postcard::to_allocvec(&response)?;
-    let encoded_size_bytes: [u8; 4] = u32::try_from(encoded_message.len())?.to_le_bytes();
-
-    stream.write_all(&protocol::WIREPLUG_PROTOCOL_MAGIC).await?;
-    stream
-        .write_all(&protocol::WIREPLUG_PROTOCOL_VERSION)
-        .await?;
-    stream.write_all(&encoded_size_bytes).await?;
-    stream.write_all(&encoded_message).await?;
+    let rendezvous =
+        peering::arrange_rendezvous(&announcement, announcing_peer_addr, &res_peers, &storage)
+            .await;
+    let response =
+        WireplugResponse::from_peer_endpoints(res_peers, announcing_peer_addr, rendezvous);
+    protocol::write_message(&mut stream, &response).await?;
 
     stream.shutdown().await?;
 
@@ -123,6 +242,8 @@ pub(crate) async fn serve(
                 continue;
             }
         };
+        // IPv4 clients of a dual-stack socket show up as ::ffff:a.b.c.d
+        let peer_addr = SocketAddr::new(peer_addr.ip().to_canonical(), peer_addr.port());
         let acceptor = acceptor.clone();
         let s = Arc::clone(storage);
         let rm = Arc::clone(&relay_manager);
@@ -138,8 +259,21 @@ pub(crate) async fn serve(
                 }
             };
 
-            log::info!("handling request over TLS from {peer_addr:?}");
-            if let Err(e) = handle_connection(stream, peer_addr, s, rm, ss).await {
+            let res = match stream.get_ref().1.alpn_protocol() {
+                Some(protocol::WIREPLUG_TCP_RELAY_ALPN) => {
+                    log::info!("relaying over TLS for {peer_addr:?}");
+                    handle_tcp_relay(stream, peer_addr, rm, ss).await
+                }
+                Some(protocol::WIREPLUG_RELAY_REGISTRATION_ALPN) => {
+                    log::info!("relay registering from {peer_addr:?}");
+                    handle_relay_registration(stream, peer_addr, rm, ss).await
+                }
+                _ => {
+                    log::info!("handling request over TLS from {peer_addr:?}");
+                    handle_connection(stream, peer_addr, s, rm, ss).await
+                }
+            };
+            if let Err(e) = res {
                 log::error!("{e}");
             }
         });
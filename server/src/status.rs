@@ -20,7 +20,7 @@ pub(crate) async fn start_writer(
         writeln!(writer, "\n\nPeers:\n-----")?;
         storage.read().await.write_to(&mut writer)?;
         writeln!(writer, "\n\nRelays:\n------")?;
-        relay_manager.read().await.write_to(&mut writer)?;
+        relay_manager.read().await.write_to(&mut writer).await?;
         writeln!(writer, "\n\nStats:\n------")?;
         server_stats.read().await.write_to(&mut writer)?;
         match UnixStream::connect(MON_SOCK).await {
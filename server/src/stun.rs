@@ -1,62 +1,137 @@
-use std::sync::Arc;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
 
-use shared::protocol::{self};
-use tokio::net::UdpSocket;
+use shared::{
+    protocol::{self},
+    stun,
+};
+use tokio::{net::UdpSocket, task::JoinSet};
 
-pub async fn start_serving(bind_to: String) {
-    let socket = match UdpSocket::bind(bind_to).await {
-        Ok(s) => s,
-        Err(e) => {
-            log::error!("{e}");
-            return;
-        }
+// One socket per address and port, indexed [address][port]. RFC 5780 requests
+// are answered from the socket their CHANGE-REQUEST asks for; a second address
+// only exists when two addresses of the same family are configured.
+type Sockets = Arc<Vec<[Arc<UdpSocket>; 2]>>;
+
+fn bind(ip: IpAddr, only_v6: bool) -> std::io::Result<[Arc<UdpSocket>; 2]> {
+    let bind_port = |port| {
+        let socket = crate::bind_socket(SocketAddr::new(ip, port), socket2::Type::DGRAM, only_v6)?;
+        UdpSocket::from_std(socket.into())
     };
-    let socket = Arc::new(socket);
+    let primary = bind_port(shared::WIREPLUG_STUN_PORT)?;
+    let alternate = bind_port(shared::WIREPLUG_STUN_ALT_PORT)?;
+    Ok([Arc::new(primary), Arc::new(alternate)])
+}
+
+pub async fn start_serving(ips: Vec<IpAddr>, only_v6: bool) {
+    let mut sockets = vec![];
+    for ip in ips {
+        match bind(ip, only_v6) {
+            Ok(pair) => sockets.push(pair),
+            Err(e) => {
+                log::error!("{e}");
+                return;
+            }
+        }
+    }
+    let sockets: Sockets = Arc::new(sockets);
+    let mut tasks = JoinSet::new();
+    for address in 0..sockets.len() {
+        for port in 0..2 {
+            tasks.spawn(serve(Arc::clone(&sockets), address, port));
+        }
+    }
+    while tasks.join_next().await.is_some() {}
+}
+
+async fn serve(sockets: Sockets, address: usize, port: usize) {
+    let socket = Arc::clone(&sockets[address][port]);
     let mut buf = [0u8; 1024];
     loop {
-        let addr = match socket.recv_from(&mut buf).await {
-            Ok((_, addr)) => addr,
+        let (n, addr) = match socket.recv_from(&mut buf).await {
+            Ok(res) => res,
             Err(e) => {
                 log::error!("{e}");
                 continue;
             }
         };
         log::debug!("udp test from addr: {:?}", &addr);
-        let observed_port = addr.port();
-        let socket = Arc::clone(&socket);
+        let sockets = Arc::clone(&sockets);
         tokio::spawn(async move {
-            if buf[..3] != protocol::WIREPLUG_PROTOCOL_MAGIC
-                || buf[3..=3] != protocol::WIREPLUG_PROTOCOL_VERSION
-            {
-                log::warn!("bad STUN client");
+            if let Some(transaction_id) = stun::parse_binding_request(&buf[..n]) {
+                let change = stun::parse_change_request(&buf[..n]);
+                answer_binding_request(&sockets, (address, port), transaction_id, change, addr)
+                    .await;
                 return;
             }
-            let udp_test_request: protocol::WireplugStunRequest =
-                match postcard::from_bytes(&buf[4..]) {
-                    Ok(r) => r,
-                    Err(e) => {
-                        log::error!("{e}");
-                        return;
-                    }
-                };
-
-            log::trace!("stated port: {}", udp_test_request.port);
-            log::trace!("observed port: {observed_port}");
-            let udp_test_response = match observed_port == udp_test_request.port {
-                true => protocol::WireplugStunResponse::new(None),
-                false => protocol::WireplugStunResponse::new(Some(observed_port)),
-            };
-
-            let data = match postcard::to_allocvec(&udp_test_response) {
-                Ok(data) => data,
-                Err(e) => {
-                    log::error!("{e}");
-                    return;
-                }
-            };
-            let _ = socket.send_to(&data, addr).await.map_err(|e| {
-                log::error!("{e}");
-            });
+            answer_wireplug_request(&sockets[address][port], &buf, addr).await;
         });
     }
 }
+
+async fn answer_binding_request(
+    sockets: &Sockets,
+    (address, port): (usize, usize),
+    transaction_id: stun::TransactionId,
+    change: stun::ChangeRequest,
+    addr: SocketAddr,
+) {
+    let from_address = match change.ip {
+        true => 1 - address,
+        false => address,
+    };
+    let from_port = match change.port {
+        true => 1 - port,
+        false => port,
+    };
+    let Some(from) = sockets.get(from_address) else {
+        log::debug!("{addr} asked for another address, but there is none");
+        return;
+    };
+    // the opposite address and port, as RFC 5780 expects
+    let other = sockets
+        .get(1 - address)
+        .and_then(|pair| pair[1 - port].local_addr().ok());
+    log::trace!("RFC 5389 Binding request, observed address: {addr}, change: {change:?}");
+    let data = stun::encode_binding_response(&transaction_id, addr, other);
+    let _ = from[from_port].send_to(&data, addr).await.map_err(|e| {
+        log::error!("{e}");
+    });
+}
+
+async fn answer_wireplug_request(socket: &UdpSocket, buf: &[u8], addr: SocketAddr) {
+    if buf[..3] != protocol::WIREPLUG_PROTOCOL_MAGIC
+        || buf[3..=3] != protocol::WIREPLUG_PROTOCOL_VERSION
+    {
+        log::warn!("bad STUN client");
+        return;
+    }
+    let udp_test_request: protocol::WireplugStunRequest = match postcard::from_bytes(&buf[4..]) {
+        Ok(r) => r,
+        Err(e) => {
+            log::error!("{e}");
+            return;
+        }
+    };
+
+    let observed_port = addr.port();
+    log::trace!("stated port: {}", udp_test_request.port);
+    log::trace!("observed port: {observed_port}");
+    let observed_addr = SocketAddr::new(addr.ip().to_canonical(), observed_port);
+    let udp_test_response = match observed_port == udp_test_request.port {
+        true => protocol::WireplugStunResponse::new(None, observed_addr),
+        false => protocol::WireplugStunResponse::new(Some(observed_port), observed_addr),
+    };
+
+    let data = match postcard::to_allocvec(&udp_test_response) {
+        Ok(data) => data,
+        Err(e) => {
+            log::error!("{e}");
+            return;
+        }
+    };
+    let _ = socket.send_to(&data, addr).await.map_err(|e| {
+        log::error!("{e}");
+    });
+}
@@ -0,0 +1,149 @@
+// Local control protocol between wireplugd and wireplugctl: a u32 LE length
+// followed by a postcard encoded ControlRequest or ControlResponse.
+
+use std::{
+    io::{Read, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use ipnet::IpNet;
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::protocol::NatClassification;
+
+const MAX_CONTROL_MESSAGE_SIZE: usize = 1024 * 1024;
+
+pub fn socket_path(ifname: &str) -> String {
+    format!("/var/run/wireplugd.{ifname}.sock")
+}
+
+#[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+pub enum ControlRequest {
+    Status,
+    // base64 public key
+    Peer(String),
+    Announce,
+    DetectNat,
+    Pause,
+    Resume,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+pub enum ControlResponse {
+    Status(DaemonStatus),
+    Peer(PeerStatus),
+    Done,
+    Error(String),
+}
+
+#[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+pub struct DaemonStatus {
+    pub interface: String,
+    // endpoints are left alone while paused
+    pub paused: bool,
+    pub listen_port: Option<u16>,
+    pub announced_port: u16,
+    pub nat: Option<String>,
+    pub network: Option<NetworkStatus>,
+    pub peers: Vec<PeerStatus>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+pub struct NetworkStatus {
+    pub wan_ipv4: Option<Ipv4Addr>,
+    pub wan_ipv6: Option<Ipv6Addr>,
+    pub lan_addrs: Vec<IpNet>,
+    pub default_gateway: Option<Ipv4Addr>,
+    pub gateway_mac: Option<String>,
+    pub nat: Option<NatClassification>,
+    pub needs_relay: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum PeerLiveness {
+    Connected,
+    // handshake or keepalives are late
+    Stale,
+    // handshake is overdue, looking for a new endpoint
+    Unreachable,
+}
+
+impl std::fmt::Display for PeerLiveness {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerLiveness::Connected => write!(f, "connected"),
+            PeerLiveness::Stale => write!(f, "stale"),
+            PeerLiveness::Unreachable => write!(f, "unreachable"),
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+pub struct PeerStatus {
+    pub public_key: String,
+    pub endpoint: Option<SocketAddr>,
+    pub allowed_ips: Vec<IpNet>,
+    pub last_handshake_secs: Option<u64>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub persistent_keepalive: Option<u16>,
+    // None until the first liveness check
+    pub liveness: Option<PeerLiveness>,
+    // waiting for a new endpoint
+    pub inactive: bool,
+}
+
+pub fn write_message<W: Write, T: Serialize>(stream: &mut W, message: &T) -> std::io::Result<()> {
+    let encoded_message = postcard::to_allocvec(message)
+        .map_err(|e| std::io::Error::other(format!("encoding error: {e}")))?;
+    let encoded_message_size = u32::try_from(encoded_message.len())
+        .map_err(|_| std::io::Error::other("message too large"))?;
+    stream.write_all(&encoded_message_size.to_le_bytes())?;
+    stream.write_all(&encoded_message)?;
+    stream.flush()
+}
+
+pub fn read_message<R: Read, T: DeserializeOwned>(stream: &mut R) -> std::io::Result<T> {
+    let mut length_bytes = [0u8; 4];
+    stream.read_exact(&mut length_bytes)?;
+    let encoded_length = u32::from_le_bytes(length_bytes) as usize;
+    if encoded_length > MAX_CONTROL_MESSAGE_SIZE {
+        return Err(std::io::Error::other(format!(
+            "message size {encoded_length} exceeds {MAX_CONTROL_MESSAGE_SIZE}"
+        )));
+    }
+    let mut encoded_message = vec![0u8; encoded_length];
+    stream.read_exact(&mut encoded_message)?;
+    postcard::from_bytes(&encoded_message)
+        .map_err(|e| std::io::Error::other(format!("encoding error: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_roundtrip() {
+        let response = ControlResponse::Peer(PeerStatus {
+            public_key: "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=".to_owned(),
+            endpoint: Some("203.0.113.5:51820".parse().unwrap()),
+            allowed_ips: vec!["10.0.0.2/32".parse().unwrap()],
+            last_handshake_secs: Some(12),
+            rx_bytes: 1024,
+            tx_bytes: 2048,
+            persistent_keepalive: Some(25),
+            liveness: Some(PeerLiveness::Stale),
+            inactive: false,
+        });
+        let mut buf = vec![];
+        write_message(&mut buf, &ControlRequest::Status).unwrap();
+        write_message(&mut buf, &response).unwrap();
+
+        let mut reader = buf.as_slice();
+        let request: ControlRequest = read_message(&mut reader).unwrap();
+        assert_eq!(request, ControlRequest::Status);
+        let decoded: ControlResponse = read_message(&mut reader).unwrap();
+        assert_eq!(decoded, response);
+        assert!(read_message::<_, ControlRequest>(&mut reader).is_err());
+    }
+}
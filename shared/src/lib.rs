@@ -4,11 +4,15 @@ use chrono::Utc;
 use colored::Colorize;
 use log::{Level, Log, Metadata, Record};
 
+pub mod control;
 pub mod protocol;
+pub mod stun;
 
 pub const WIREPLUG_WPCOD_PORT: u16 = 443;
 pub const WIREPLUG_WPCOD_DEV_PORT: u16 = 4430;
 pub const WIREPLUG_STUN_PORT: u16 = 4455;
+// answers RFC 5780 change-port requests
+pub const WIREPLUG_STUN_ALT_PORT: u16 = 4456;
 pub const WIREPLUG_ORG_STUN1: &str = "stun1.wireplug.org";
 pub const WIREPLUG_ORG_STUN2: &str = "stun2.wireplug.org";
 pub const WIREPLUG_ORG_WP: &str = "a.wireplug.org";
@@ -16,6 +20,26 @@ pub const WIREPLUG_ORG_RELAY: &str = "relay.wireplug.org";
 
 pub const MAX_MESSAGE_SIZE: usize = 4096;
 
+// accepts "host", "host:port", "[v6addr]" and "[v6addr]:port"
+pub fn parse_host_port(s: &str, default_port: u16) -> io::Result<(String, u16)> {
+    let bad = || io::Error::other(format!("bad server address: {s}"));
+    if let Some(rest) = s.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']').ok_or_else(bad)?;
+        return match rest.strip_prefix(':') {
+            Some(port) => Ok((host.to_owned(), port.parse().map_err(|_| bad())?)),
+            None if rest.is_empty() => Ok((host.to_owned(), default_port)),
+            None => Err(bad()),
+        };
+    }
+    match s.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') => {
+            Ok((host.to_owned(), port.parse().map_err(|_| bad())?))
+        }
+        _ if s.is_empty() => Err(bad()),
+        _ => Ok((s.to_owned(), default_port)),
+    }
+}
+
 pub struct TmpLogger;
 
 impl Log for TmpLogger {
@@ -57,3 +81,27 @@ pub fn daemonize() -> std::io::Result<()> {
         _ => Err(io::Error::other("daemon(3) failed")),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::parse_host_port;
+
+    #[test]
+    fn host_port_parsing() {
+        let p = |s| parse_host_port(s, 443).ok();
+        assert_eq!(
+            p("wp.example.org"),
+            Some(("wp.example.org".to_owned(), 443))
+        );
+        assert_eq!(
+            p("wp.example.org:4430"),
+            Some(("wp.example.org".to_owned(), 4430))
+        );
+        assert_eq!(p("192.0.2.1:80"), Some(("192.0.2.1".to_owned(), 80)));
+        assert_eq!(p("2001:db8::1"), Some(("2001:db8::1".to_owned(), 443)));
+        assert_eq!(p("[2001:db8::1]:80"), Some(("2001:db8::1".to_owned(), 80)));
+        assert_eq!(p("[2001:db8::1]"), Some(("2001:db8::1".to_owned(), 443)));
+        assert_eq!(p("wp.example.org:http"), None);
+        assert_eq!(p(""), None);
+    }
+}
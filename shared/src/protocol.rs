@@ -1,11 +1,119 @@
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use hmac::{Hmac, Mac};
 use ipnet::IpNet;
+use sha2::Sha256;
 use std::{
     collections::HashMap,
-    net::{Ipv4Addr, Ipv6Addr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
 };
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
 
 pub const WIREPLUG_PROTOCOL_MAGIC: [u8; 3] = [0xFD, 0xAC, 0xAF];
-pub const WIREPLUG_PROTOCOL_VERSION: [u8; 1] = [0x1];
+pub const WIREPLUG_PROTOCOL_VERSION: [u8; 1] = [0xA];
+// TLS ALPN of connections that carry relayed WireGuard datagrams
+pub const WIREPLUG_TCP_RELAY_ALPN: &[u8] = b"wireplug-relay";
+// TLS ALPN of relays registering with a coordination server
+pub const WIREPLUG_RELAY_REGISTRATION_ALPN: &[u8] = b"wireplug-relay-registration";
+
+const WIREPLUG_PROOF_LABEL: &[u8] = b"wireplug announcement proof v1";
+
+// magic and version, followed by the length of the encoded message
+pub const WIREPLUG_HEADER_SIZE: usize = 4;
+
+#[derive(Debug, PartialEq)]
+pub enum HeaderError {
+    BadMagic,
+    OtherVersion,
+    TooLarge(usize),
+}
+
+impl std::fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HeaderError::BadMagic => write!(f, "bad message"),
+            HeaderError::OtherVersion => write!(f, "the other side runs another protocol version"),
+            HeaderError::TooLarge(size) => write!(
+                f,
+                "Message size {size} exceeds maximum allowed size of {}",
+                crate::MAX_MESSAGE_SIZE
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HeaderError {}
+
+impl HeaderError {
+    // The header problem read_message failed with, if that's what it was
+    pub fn of(e: &std::io::Error) -> Option<&HeaderError> {
+        e.get_ref()?.downcast_ref()
+    }
+}
+
+// `message` with its header, as it goes on the wire
+pub fn encode_message<T: serde::Serialize>(message: &T) -> std::io::Result<Vec<u8>> {
+    let encoded_message = postcard::to_allocvec(message)
+        .map_err(|e| std::io::Error::other(format!("encoding error: {e}")))?;
+    let encoded_message_size = u32::try_from(encoded_message.len())
+        .map_err(|_| std::io::Error::other("message too large"))?;
+    let mut frame = Vec::with_capacity(WIREPLUG_HEADER_SIZE + 4 + encoded_message.len());
+    frame.extend_from_slice(&WIREPLUG_PROTOCOL_MAGIC);
+    frame.extend_from_slice(&WIREPLUG_PROTOCOL_VERSION);
+    frame.extend_from_slice(&encoded_message_size.to_le_bytes());
+    frame.extend_from_slice(&encoded_message);
+    Ok(frame)
+}
+
+pub fn decode_header(header: &[u8; WIREPLUG_HEADER_SIZE]) -> Result<(), HeaderError> {
+    if header[..3] != WIREPLUG_PROTOCOL_MAGIC {
+        return Err(HeaderError::BadMagic);
+    }
+    if header[3..] != WIREPLUG_PROTOCOL_VERSION {
+        return Err(HeaderError::OtherVersion);
+    }
+    Ok(())
+}
+
+// The length of the encoded message that follows the header
+pub fn decode_length(length: u32) -> Result<usize, HeaderError> {
+    let encoded_length = length as usize;
+    if encoded_length > crate::MAX_MESSAGE_SIZE {
+        return Err(HeaderError::TooLarge(encoded_length));
+    }
+    Ok(encoded_length)
+}
+
+pub fn decode_message<T: serde::de::DeserializeOwned>(encoded: &[u8]) -> std::io::Result<T> {
+    postcard::from_bytes(encoded).map_err(|e| std::io::Error::other(format!("encoding error: {e}")))
+}
+
+#[cfg(feature = "tokio")]
+pub async fn write_message<S, T>(stream: &mut S, message: &T) -> std::io::Result<()>
+where
+    S: tokio::io::AsyncWrite + Unpin,
+    T: serde::Serialize,
+{
+    use tokio::io::AsyncWriteExt;
+    stream.write_all(&encode_message(message)?).await
+}
+
+// A bad header fails with a HeaderError inside the io::Error, see HeaderError::of
+#[cfg(feature = "tokio")]
+pub async fn read_message<S, T>(stream: &mut S) -> std::io::Result<T>
+where
+    S: tokio::io::AsyncRead + Unpin,
+    T: serde::de::DeserializeOwned,
+{
+    use tokio::io::AsyncReadExt;
+    let mut header = [0u8; WIREPLUG_HEADER_SIZE];
+    stream.read_exact(&mut header).await?;
+    decode_header(&header).map_err(std::io::Error::other)?;
+    let encoded_length =
+        decode_length(stream.read_u32_le().await?).map_err(std::io::Error::other)?;
+    let mut buffer = vec![0u8; encoded_length];
+    stream.read_exact(&mut buffer).await?;
+    decode_message(&buffer)
+}
 
 fn is_valid_wgkey(s: &str) -> bool {
     if s.len() != 44 {
@@ -19,20 +127,123 @@ fn is_valid_wgkey(s: &str) -> bool {
     true
 }
 
+pub fn decode_wgkey(s: &str) -> Option<[u8; 32]> {
+    BASE64.decode(s).ok()?.try_into().ok()
+}
+
+pub fn public_wgkey(private_key: [u8; 32]) -> String {
+    BASE64.encode(PublicKey::from(&StaticSecret::from(private_key)).as_bytes())
+}
+
+fn compute_proof(
+    shared_secret: &[u8; 32],
+    server_pubkey: &[u8; 32],
+    initiator_pubkey: &[u8; 32],
+) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(shared_secret).expect("HMAC accepts keys of any size");
+    mac.update(WIREPLUG_PROOF_LABEL);
+    mac.update(server_pubkey);
+    mac.update(initiator_pubkey);
+    mac
+}
+
+// RFC 5780 mapping and filtering behaviour
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum NatBehaviour {
+    EndpointIndependent,
+    AddressDependent,
+    AddressAndPortDependent,
+}
+
+impl std::fmt::Display for NatBehaviour {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NatBehaviour::EndpointIndependent => write!(f, "endpoint-independent"),
+            NatBehaviour::AddressDependent => write!(f, "address-dependent"),
+            NatBehaviour::AddressAndPortDependent => write!(f, "address and port-dependent"),
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct NatClassification {
+    // None when the STUN servers could not tell
+    pub mapping: Option<NatBehaviour>,
+    pub filtering: Option<NatBehaviour>,
+    pub hairpinning: bool,
+    pub port_preservation: bool,
+    // behind a carrier-grade NAT (100.64.0.0/10)
+    pub cgnat: bool,
+    // new mappings get ports this far apart, one after another
+    pub allocation_step: Option<u16>,
+}
+
+impl std::fmt::Display for NatClassification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let behaviour = |b: Option<NatBehaviour>| b.map_or("unknown".to_owned(), |b| b.to_string());
+        write!(
+            f,
+            "mapping: {}, filtering: {}",
+            behaviour(self.mapping),
+            behaviour(self.filtering)
+        )?;
+        if self.hairpinning {
+            write!(f, ", hairpinning")?;
+        }
+        if self.port_preservation {
+            write!(f, ", port preservation")?;
+        }
+        if self.cgnat {
+            write!(f, ", carrier-grade NAT")?;
+        }
+        if let Some(step) = self.allocation_step {
+            write!(f, ", sequential ports (step {step})")?;
+        }
+        Ok(())
+    }
+}
+
+// The ports a sequentially allocating NAT is expected to hand out next
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct PortRange {
+    pub first: u16,
+    pub step: u16,
+    pub count: u16,
+}
+
+impl PortRange {
+    pub fn ports(&self) -> impl Iterator<Item = u16> {
+        let PortRange { first, step, count } = *self;
+        (0..count).map_while(move |i| first.checked_add(i.checked_mul(step)?))
+    }
+}
+
 #[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
 pub struct WireplugAnnouncement {
     pub initiator_pubkey: String,
     pub peer_pubkeys: Vec<String>,
+    // the server fills in the address family the announcement arrived over
+    pub ipv4: Option<Ipv4Addr>,
     pub ipv6: Option<Ipv6Addr>,
     pub wg_port: u16,
     pub lan_addrs: Vec<IpNet>,
     pub needs_relay: bool,
+    // lets the server pick a traversal strategy
+    pub nat: Option<NatClassification>,
+    // willing to take part in birthday-paradox traversal
+    pub birthday: bool,
+    // where our next mappings will likely be, behind a sequential NAT
+    pub predicted_ports: Option<PortRange>,
+    // UDP is blocked, peers have to be reached over TCP
+    pub tcp_relay: bool,
 }
 
 impl WireplugAnnouncement {
     pub fn new(
         initiator_pubkey: &String,
         peer_pubkeys: Vec<String>,
+        ipv4: Option<Ipv4Addr>,
         ipv6: Option<Ipv6Addr>,
         wg_port: u16,
         lan_addrs: Vec<IpNet>,
@@ -41,19 +252,105 @@ impl WireplugAnnouncement {
         WireplugAnnouncement {
             initiator_pubkey: initiator_pubkey.to_owned(),
             peer_pubkeys,
+            ipv4,
             ipv6,
             wg_port,
             lan_addrs,
             needs_relay: need_relay,
+            nat: None,
+            birthday: false,
+            predicted_ports: None,
+            tcp_relay: false,
         }
     }
+    pub fn with_nat(mut self, nat: Option<NatClassification>) -> Self {
+        self.nat = nat;
+        self
+    }
+    pub fn with_birthday(mut self, birthday: bool) -> Self {
+        self.birthday = birthday;
+        self
+    }
+    pub fn with_predicted_ports(mut self, predicted_ports: Option<PortRange>) -> Self {
+        self.predicted_ports = predicted_ports;
+        self
+    }
+    pub fn with_tcp_relay(mut self, tcp_relay: bool) -> Self {
+        self.tcp_relay = tcp_relay;
+        self
+    }
     pub fn valid(&self) -> bool {
         is_valid_wgkey(&self.initiator_pubkey)
             && self.peer_pubkeys.iter().all(|p| is_valid_wgkey(p))
-            && self.wg_port >= 1024
+            // without peers, the client only asks for its observed address
+            && (self.peer_pubkeys.is_empty() || self.wg_port >= 1024)
+    }
+}
+
+// The server answers every announcement with a fresh ephemeral X25519 public key.
+// The client proves it owns `initiator_pubkey` by MACing the transcript with the
+// shared secret derived from its WireGuard private key and the ephemeral key.
+#[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+pub struct WireplugChallenge {
+    pub server_pubkey: [u8; 32],
+}
+
+pub struct WireplugChallengeSecret(EphemeralSecret);
+
+impl WireplugChallenge {
+    pub fn generate() -> (Self, WireplugChallengeSecret) {
+        let secret = EphemeralSecret::random();
+        let challenge = WireplugChallenge {
+            server_pubkey: PublicKey::from(&secret).to_bytes(),
+        };
+        (challenge, WireplugChallengeSecret(secret))
+    }
+
+    pub fn respond(&self, private_key: [u8; 32]) -> WireplugChallengeResponse {
+        let secret = StaticSecret::from(private_key);
+        let initiator_pubkey = PublicKey::from(&secret).to_bytes();
+        let shared_secret = secret.diffie_hellman(&PublicKey::from(self.server_pubkey));
+        let proof = compute_proof(
+            shared_secret.as_bytes(),
+            &self.server_pubkey,
+            &initiator_pubkey,
+        )
+        .finalize()
+        .into_bytes()
+        .into();
+        WireplugChallengeResponse { proof }
+    }
+}
+
+impl WireplugChallengeSecret {
+    pub fn verify(
+        self,
+        challenge: &WireplugChallenge,
+        initiator_pubkey: &str,
+        response: &WireplugChallengeResponse,
+    ) -> bool {
+        let Some(initiator_pubkey) = decode_wgkey(initiator_pubkey) else {
+            return false;
+        };
+        let shared_secret = self.0.diffie_hellman(&PublicKey::from(initiator_pubkey));
+        if !shared_secret.was_contributory() {
+            return false;
+        }
+        compute_proof(
+            shared_secret.as_bytes(),
+            &challenge.server_pubkey,
+            &initiator_pubkey,
+        )
+        .verify_slice(&response.proof)
+        .is_ok()
     }
 }
 
+#[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+pub struct WireplugChallengeResponse {
+    pub proof: [u8; 32],
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Clone, PartialEq, Debug)]
 pub enum WireplugEndpoint {
     Unknown,
@@ -66,21 +363,55 @@ pub enum WireplugEndpoint {
         ipv4: Option<Ipv4Addr>,
         ipv6: Option<Ipv6Addr>,
         wg_port: u16,
+        // also worth probing on ipv4
+        predicted_ports: Option<PortRange>,
     },
     Relay {
         id: usize,
+        // None for the coordination server's own relay, at the client's RelayServer
+        ip: Option<IpAddr>,
         port: u16,
     },
+    // over TLS to the coordination server, see WireplugTcpRelayRequest
+    TcpRelay,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
 pub struct WireplugResponse {
     pub peer_endpoints: HashMap<String, WireplugEndpoint>,
+    // the announcement's source address as seen by the server
+    pub observed_addr: SocketAddr,
+    pub rendezvous: HashMap<String, WireplugRendezvous>,
+}
+
+// Birthday-paradox traversal between a destination-dependent NAT and an
+// endpoint-independent one
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum BirthdayRole {
+    // open many ports towards the peer's endpoint and wait for its probes
+    Hard { peer: SocketAddr },
+    // probe many ports of the peer's address from the listen port
+    Easy { peer: IpAddr },
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct WireplugRendezvous {
+    // milliseconds until both sides send a handshake at once
+    pub delay_ms: u32,
+    pub birthday: Option<BirthdayRole>,
 }
 
 impl WireplugResponse {
-    pub fn from_peer_endpoints(peer_endpoints: HashMap<String, WireplugEndpoint>) -> Self {
-        WireplugResponse { peer_endpoints }
+    pub fn from_peer_endpoints(
+        peer_endpoints: HashMap<String, WireplugEndpoint>,
+        observed_addr: SocketAddr,
+        rendezvous: HashMap<String, WireplugRendezvous>,
+    ) -> Self {
+        WireplugResponse {
+            peer_endpoints,
+            observed_addr,
+            rendezvous,
+        }
     }
     pub fn valid(&self) -> bool {
         // XXX
@@ -88,6 +419,90 @@ impl WireplugResponse {
     }
 }
 
+// Sent over a WIREPLUG_TCP_RELAY_ALPN connection and answered with a
+// WireplugChallenge like an announcement. Once both peers of a pair are
+// connected, the server copies everything one sends to the other: WireGuard
+// datagrams, each prefixed with its length as a little-endian u16.
+#[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+pub struct WireplugTcpRelayRequest {
+    pub initiator_pubkey: String,
+    pub peer_pubkey: String,
+}
+
+impl WireplugTcpRelayRequest {
+    pub fn new(initiator_pubkey: &str, peer_pubkey: &str) -> Self {
+        WireplugTcpRelayRequest {
+            initiator_pubkey: initiator_pubkey.to_owned(),
+            peer_pubkey: peer_pubkey.to_owned(),
+        }
+    }
+    pub fn valid(&self) -> bool {
+        is_valid_wgkey(&self.initiator_pubkey)
+            && is_valid_wgkey(&self.peer_pubkey)
+            && self.initiator_pubkey != self.peer_pubkey
+    }
+}
+
+// Sent by a relay over a WIREPLUG_RELAY_REGISTRATION_ALPN connection and
+// answered with a WireplugChallenge for `relay_pubkey`, then with
+// WireplugRelayRegistered. From then on the coordination server sends
+// WireplugRelayRequests and the relay answers each with a WireplugRelayReply.
+#[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+pub struct WireplugRelayRegistration {
+    pub relay_pubkey: String,
+    // None to use the address the relay connects from
+    pub ip: Option<IpAddr>,
+    // relay ports, in use or not
+    pub capacity: u32,
+    // relay ports in use
+    pub load: u32,
+}
+
+impl WireplugRelayRegistration {
+    pub fn new(relay_pubkey: &str, ip: Option<IpAddr>, capacity: u32, load: u32) -> Self {
+        WireplugRelayRegistration {
+            relay_pubkey: relay_pubkey.to_owned(),
+            ip,
+            capacity,
+            load,
+        }
+    }
+    pub fn valid(&self) -> bool {
+        is_valid_wgkey(&self.relay_pubkey)
+            && self.capacity > 0
+            && self.ip.is_none_or(|ip| !ip.is_unspecified())
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+pub struct WireplugRelayRegistered {
+    // what endpoints name this relay by
+    pub id: usize,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+pub enum WireplugRelayRequest {
+    // a port relaying `peer_a`, announcing from `ip`, to `peer_b`
+    Port {
+        peer_a: String,
+        peer_b: String,
+        ip: IpAddr,
+    },
+    RemovePair {
+        peer_a: String,
+        peer_b: String,
+    },
+    // keeps the connection open and the load current
+    Ping,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+pub struct WireplugRelayReply {
+    // only for WireplugRelayRequest::Port, None if no port could be set up
+    pub port: Option<u16>,
+    pub load: u32,
+}
+
 #[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
 pub struct WireplugStunRequest {
     pub port: u16,
@@ -108,14 +523,122 @@ pub enum WireplugStunResult {
 #[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
 pub struct WireplugStunResponse {
     pub result: WireplugStunResult,
+    pub observed_addr: SocketAddr,
 }
 
 impl WireplugStunResponse {
-    pub fn new(port: Option<u16>) -> Self {
+    pub fn new(port: Option<u16>, observed_addr: SocketAddr) -> Self {
         let res = match port {
             Some(p) => WireplugStunResult::DifferentPort(p),
             None => WireplugStunResult::SamePort,
         };
-        WireplugStunResponse { result: res }
+        WireplugStunResponse {
+            result: res,
+            observed_addr,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    fn keypair(seed: u8) -> ([u8; 32], String) {
+        let private_key = [seed; 32];
+        let public_key = PublicKey::from(&StaticSecret::from(private_key));
+        (private_key, BASE64.encode(public_key.as_bytes()))
+    }
+
+    #[test]
+    fn frames_messages() {
+        let frame = encode_message(&WireplugRelayRegistered { id: 3 }).unwrap();
+        let header: [u8; WIREPLUG_HEADER_SIZE] = frame[..4].try_into().unwrap();
+        assert_eq!(decode_header(&header), Ok(()));
+        let length = decode_length(u32::from_le_bytes(frame[4..8].try_into().unwrap())).unwrap();
+        assert_eq!(length, frame.len() - 8);
+        let message: WireplugRelayRegistered = decode_message(&frame[8..]).unwrap();
+        assert_eq!(message.id, 3);
+
+        let mut other_version = header;
+        other_version[3] ^= 1;
+        assert_eq!(
+            decode_header(&other_version),
+            Err(HeaderError::OtherVersion)
+        );
+        assert_eq!(
+            decode_header(&[0; WIREPLUG_HEADER_SIZE]),
+            Err(HeaderError::BadMagic)
+        );
+        assert!(matches!(
+            decode_length(u32::MAX),
+            Err(HeaderError::TooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn challenge_accepts_key_owner() {
+        let (private_key, public_key) = keypair(7);
+        let (challenge, secret) = WireplugChallenge::generate();
+        let response = challenge.respond(private_key);
+        assert!(secret.verify(&challenge, &public_key, &response));
+    }
+
+    #[test]
+    fn relay_proves_its_key() {
+        let (private_key, public_key) = keypair(9);
+        assert_eq!(public_wgkey(private_key), public_key);
+        let registration = WireplugRelayRegistration::new(&public_key, None, 1000, 0);
+        assert!(registration.valid());
+        let (challenge, secret) = WireplugChallenge::generate();
+        let response = challenge.respond(private_key);
+        assert!(secret.verify(&challenge, &registration.relay_pubkey, &response));
+        assert!(!WireplugRelayRegistration::new(&public_key, None, 0, 0).valid());
+    }
+
+    #[test]
+    fn challenge_rejects_other_key() {
+        let (_, public_key) = keypair(7);
+        let (impostor_private_key, _) = keypair(8);
+        let (challenge, secret) = WireplugChallenge::generate();
+        let response = challenge.respond(impostor_private_key);
+        assert!(!secret.verify(&challenge, &public_key, &response));
+    }
+
+    #[test]
+    fn challenge_rejects_replayed_response() {
+        let (private_key, public_key) = keypair(7);
+        let (old_challenge, _) = WireplugChallenge::generate();
+        let replayed = old_challenge.respond(private_key);
+        let (challenge, secret) = WireplugChallenge::generate();
+        assert!(!secret.verify(&challenge, &public_key, &replayed));
+    }
+
+    #[test]
+    fn observed_address_query_needs_no_port() {
+        let (_, public_key) = keypair(7);
+        let (_, peer) = keypair(8);
+        let query = WireplugAnnouncement::new(&public_key, vec![], None, None, 0, vec![], false);
+        assert!(query.valid());
+        let announcement =
+            WireplugAnnouncement::new(&public_key, vec![peer], None, None, 0, vec![], false);
+        assert!(!announcement.valid());
+    }
+
+    #[test]
+    fn predicted_ports_stop_at_the_last_port() {
+        let range = PortRange {
+            first: 40000,
+            step: 2,
+            count: 3,
+        };
+        assert_eq!(range.ports().collect::<Vec<_>>(), vec![40000, 40002, 40004]);
+        let range = PortRange {
+            first: 65534,
+            step: 1,
+            count: 4,
+        };
+        assert_eq!(range.ports().collect::<Vec<_>>(), vec![65534, 65535]);
     }
 }
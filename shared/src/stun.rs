@@ -0,0 +1,269 @@
+// Minimal RFC 5389 STUN: Binding requests and (XOR-)MAPPED-ADDRESS responses,
+// plus CHANGE-REQUEST and OTHER-ADDRESS for RFC 5780 NAT behaviour discovery
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+pub const MAGIC_COOKIE: u32 = 0x2112_A442;
+pub const HEADER_SIZE: usize = 20;
+
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS_RESPONSE: u16 = 0x0101;
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_CHANGE_REQUEST: u16 = 0x0003;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const ATTR_OTHER_ADDRESS: u16 = 0x802C;
+const CHANGE_IP: u8 = 0x04;
+const CHANGE_PORT: u8 = 0x02;
+const FAMILY_IPV4: u8 = 0x01;
+const FAMILY_IPV6: u8 = 0x02;
+
+pub type TransactionId = [u8; 12];
+
+// Asks the server to answer from its other address and/or port
+#[derive(Default, Clone, Copy, PartialEq, Debug)]
+pub struct ChangeRequest {
+    pub ip: bool,
+    pub port: bool,
+}
+
+pub struct BindingResponse {
+    pub mapped: SocketAddr,
+    // the server's alternate address and port, if it supports RFC 5780
+    pub other: Option<SocketAddr>,
+}
+
+fn header(message_type: u16, length: u16, transaction_id: &TransactionId) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_SIZE + length as usize);
+    buf.extend(message_type.to_be_bytes());
+    buf.extend(length.to_be_bytes());
+    buf.extend(MAGIC_COOKIE.to_be_bytes());
+    buf.extend(transaction_id);
+    buf
+}
+
+// Returns the message type, the attributes and the transaction ID of a STUN message
+fn parse(buf: &[u8]) -> Option<(u16, &[u8], TransactionId)> {
+    if buf.len() < HEADER_SIZE || buf[0] & 0xC0 != 0 || buf[4..8] != MAGIC_COOKIE.to_be_bytes() {
+        return None;
+    }
+    let message_type = u16::from_be_bytes([buf[0], buf[1]]);
+    let length = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+    let attributes = buf.get(HEADER_SIZE..HEADER_SIZE + length)?;
+    let transaction_id = buf[8..HEADER_SIZE].try_into().ok()?;
+    Some((message_type, attributes, transaction_id))
+}
+
+// Iterates over the (type, value) pairs of the attributes
+fn attributes(mut attributes: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        let attr_type = u16::from_be_bytes([*attributes.first()?, *attributes.get(1)?]);
+        let len = u16::from_be_bytes([*attributes.get(2)?, *attributes.get(3)?]) as usize;
+        let value = attributes.get(4..4 + len)?;
+        // attributes are padded to 4 bytes
+        let padded = (4 + len).next_multiple_of(4);
+        attributes = attributes.get(padded..).unwrap_or_default();
+        Some((attr_type, value))
+    })
+}
+
+// XOR mask for the address: the cookie, followed by the transaction ID for IPv6
+fn xor_mask(transaction_id: &TransactionId) -> [u8; 16] {
+    let mut mask = [0u8; 16];
+    mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    mask[4..].copy_from_slice(transaction_id);
+    mask
+}
+
+fn decode_address(value: &[u8], mask: Option<&[u8; 16]>) -> Option<SocketAddr> {
+    let family = *value.get(1)?;
+    let mut port = u16::from_be_bytes([*value.get(2)?, *value.get(3)?]);
+    let mut octets = value.get(4..)?.to_vec();
+    if let Some(mask) = mask {
+        port ^= (MAGIC_COOKIE >> 16) as u16;
+        octets.iter_mut().zip(mask).for_each(|(b, m)| *b ^= m);
+    }
+    let ip = match (family, octets.len()) {
+        (FAMILY_IPV4, 4) => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(octets).ok()?)),
+        (FAMILY_IPV6, 16) => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(octets).ok()?)),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+fn encode_address(buf: &mut Vec<u8>, attr_type: u16, addr: SocketAddr, mask: Option<&[u8; 16]>) {
+    let (family, mut octets) = match addr.ip().to_canonical() {
+        IpAddr::V4(ip) => (FAMILY_IPV4, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (FAMILY_IPV6, ip.octets().to_vec()),
+    };
+    let mut port = addr.port();
+    if let Some(mask) = mask {
+        port ^= (MAGIC_COOKIE >> 16) as u16;
+        octets.iter_mut().zip(mask).for_each(|(b, m)| *b ^= m);
+    }
+    buf.extend(attr_type.to_be_bytes());
+    buf.extend((4 + octets.len() as u16).to_be_bytes());
+    buf.extend([0, family]);
+    buf.extend(port.to_be_bytes());
+    buf.extend(octets);
+}
+
+// The message length is only known once the attributes are written
+fn finish(mut buf: Vec<u8>) -> Vec<u8> {
+    let length = (buf.len() - HEADER_SIZE) as u16;
+    buf[2..4].copy_from_slice(&length.to_be_bytes());
+    buf
+}
+
+pub fn encode_binding_request(transaction_id: &TransactionId) -> Vec<u8> {
+    encode_change_request(transaction_id, ChangeRequest::default())
+}
+
+pub fn encode_change_request(transaction_id: &TransactionId, change: ChangeRequest) -> Vec<u8> {
+    let mut buf = header(BINDING_REQUEST, 0, transaction_id);
+    if change != ChangeRequest::default() {
+        let flags = (change.ip as u8 * CHANGE_IP) | (change.port as u8 * CHANGE_PORT);
+        buf.extend(ATTR_CHANGE_REQUEST.to_be_bytes());
+        buf.extend(4u16.to_be_bytes());
+        buf.extend([0, 0, 0, flags]);
+    }
+    finish(buf)
+}
+
+// Returns the transaction ID if `buf` holds a Binding request
+pub fn parse_binding_request(buf: &[u8]) -> Option<TransactionId> {
+    match parse(buf)? {
+        (BINDING_REQUEST, _, transaction_id) => Some(transaction_id),
+        _ => None,
+    }
+}
+
+// The CHANGE-REQUEST of a Binding request, if any
+pub fn parse_change_request(buf: &[u8]) -> ChangeRequest {
+    let Some((BINDING_REQUEST, attrs, _)) = parse(buf) else {
+        return ChangeRequest::default();
+    };
+    attributes(attrs)
+        .find(|(attr_type, _)| *attr_type == ATTR_CHANGE_REQUEST)
+        .and_then(|(_, value)| value.get(3))
+        .map(|flags| ChangeRequest {
+            ip: flags & CHANGE_IP != 0,
+            port: flags & CHANGE_PORT != 0,
+        })
+        .unwrap_or_default()
+}
+
+pub fn encode_binding_response(
+    transaction_id: &TransactionId,
+    observed: SocketAddr,
+    other: Option<SocketAddr>,
+) -> Vec<u8> {
+    let mask = xor_mask(transaction_id);
+    let mut buf = header(BINDING_SUCCESS_RESPONSE, 0, transaction_id);
+    encode_address(&mut buf, ATTR_XOR_MAPPED_ADDRESS, observed, Some(&mask));
+    if let Some(other) = other {
+        encode_address(&mut buf, ATTR_OTHER_ADDRESS, other, None);
+    }
+    finish(buf)
+}
+
+// Returns the reflexive address from a Binding success response to `transaction_id`.
+// MAPPED-ADDRESS is only used when XOR-MAPPED-ADDRESS is missing (RFC 3489 servers).
+pub fn parse_binding_response(
+    buf: &[u8],
+    transaction_id: &TransactionId,
+) -> Option<BindingResponse> {
+    let (BINDING_SUCCESS_RESPONSE, attrs, id) = parse(buf)? else {
+        return None;
+    };
+    if id != *transaction_id {
+        return None;
+    }
+    let mask = xor_mask(transaction_id);
+    let (mut xor_mapped, mut mapped, mut other) = (None, None, None);
+    for (attr_type, value) in attributes(attrs) {
+        match attr_type {
+            ATTR_XOR_MAPPED_ADDRESS => xor_mapped = decode_address(value, Some(&mask)),
+            ATTR_MAPPED_ADDRESS => mapped = decode_address(value, None),
+            ATTR_OTHER_ADDRESS => other = decode_address(value, None),
+            _ => (),
+        }
+    }
+    Some(BindingResponse {
+        mapped: xor_mapped.or(mapped)?,
+        other,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binding_roundtrip() {
+        let id = [7u8; 12];
+        let request = encode_binding_request(&id);
+        assert_eq!(parse_binding_request(&request), Some(id));
+
+        for observed in ["203.0.113.5:51820", "[2001:db8::1]:51820"] {
+            let observed: SocketAddr = observed.parse().unwrap();
+            let response = encode_binding_response(&id, observed, None);
+            let parsed = parse_binding_response(&response, &id).unwrap();
+            assert_eq!(parsed.mapped, observed);
+            assert_eq!(parsed.other, None);
+            assert!(parse_binding_response(&response, &[8u8; 12]).is_none());
+            assert_eq!(parse_binding_request(&response), None);
+        }
+    }
+
+    #[test]
+    fn change_request_and_other_address() {
+        let id = [3u8; 12];
+        let change = ChangeRequest {
+            ip: true,
+            port: true,
+        };
+        let request = encode_change_request(&id, change);
+        assert_eq!(parse_binding_request(&request), Some(id));
+        assert_eq!(parse_change_request(&request), change);
+        let request = encode_binding_request(&id);
+        assert_eq!(parse_change_request(&request), ChangeRequest::default());
+
+        let observed: SocketAddr = "203.0.113.5:51820".parse().unwrap();
+        let other: SocketAddr = "198.51.100.2:4456".parse().unwrap();
+        let response = encode_binding_response(&id, observed, Some(other));
+        let parsed = parse_binding_response(&response, &id).unwrap();
+        assert_eq!(parsed.mapped, observed);
+        assert_eq!(parsed.other, Some(other));
+    }
+
+    #[test]
+    fn ipv4_mapped_is_reported_as_ipv4() {
+        let id = [1u8; 12];
+        let observed: SocketAddr = "[::ffff:203.0.113.5]:4455".parse().unwrap();
+        let response = encode_binding_response(&id, observed, None);
+        assert_eq!(
+            parse_binding_response(&response, &id).map(|r| r.mapped),
+            Some("203.0.113.5:4455".parse().unwrap())
+        );
+    }
+
+    // RFC 5769, 2.2 Sample IPv4 Response
+    #[test]
+    fn rfc5769_ipv4_response() {
+        let response = [
+            0x01, 0x01, 0x00, 0x3c, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34,
+            0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74,
+            0x20, 0x76, 0x65, 0x63, 0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x08, 0x00, 0x01,
+            0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43, 0x00, 0x08, 0x00, 0x14, 0x2b, 0x91, 0xf5, 0x99,
+            0xfd, 0x9e, 0x90, 0xc3, 0x8c, 0x74, 0x89, 0xf9, 0x2a, 0xf9, 0xba, 0x53, 0xf0, 0x6b,
+            0xe7, 0xd7, 0x80, 0x28, 0x00, 0x04, 0xc0, 0x7d, 0x4c, 0x96,
+        ];
+        let id = [
+            0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae,
+        ];
+        assert_eq!(
+            parse_binding_response(&response, &id).map(|r| r.mapped),
+            Some("192.0.2.1:32853".parse().unwrap())
+        );
+    }
+}